@@ -0,0 +1,47 @@
+// Runs a rom for 600 frames without showing anything and prints
+// the hash of the last frame. Usage: headless <rom>
+use rustboy::cartridge::Cartridge;
+use rustboy::emulator::{BootMode, Emulator};
+use std::fs;
+use std::io;
+
+const FRAMES: u32 = 600;
+
+// Final frame hash and clock cycles run
+fn run_headless(rom: Vec<u8>, frames: u32) -> (u64, u64) {
+    let mut emu = Emulator::headless(Cartridge::new(rom), BootMode::SkipDmg);
+    for _ in 0..frames {
+        emu.run_frame();
+    }
+    (emu.cpu.interconnect.ppu.frame_hash(), emu.cycles())
+}
+
+fn main() -> io::Result<()> {
+    let path = match std::env::args().nth(1) {
+        Some(path) => path,
+        None => {
+            eprintln!("Usage: headless <rom>");
+            std::process::exit(2);
+        }
+    };
+    let (hash, cycles) = run_headless(fs::read(path)?, FRAMES);
+    println!("Frame hash: {:016x}", hash);
+    println!("Cycles: {}", cycles);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_run_headless() {
+        let mut rom = vec![0; 0x8000];
+        // JR -2 forever
+        rom[0x100..0x102].copy_from_slice(&[0x18, 0xFE]);
+        let (hash, cycles) = run_headless(rom.clone(), 10);
+        assert!(cycles >= 9 * 70224 && cycles <= 10 * 70224);
+        // Same rom, same result
+        assert_eq!(run_headless(rom, 10), (hash, cycles));
+    }
+}
@@ -1,4 +1,12 @@
+use crate::emulator::EmuError;
 use crate::memory_map::*;
+use crate::save_state::{StateReader, StateWriter};
+use std::io;
+use std::path::{Path, PathBuf};
+
+const CGB_FLAG_ADDRESS: usize = 0x0143;
+const CARTRIDGE_TYPE_ADDRESS: usize = 0x0147;
+const RAM_SIZE_ADDRESS: usize = 0x0149;
 
 #[allow(non_camel_case_types)]
 #[derive(PartialEq, Clone, Copy)]
@@ -7,6 +15,16 @@ enum MemoryModel {
     ROM4M_RAM32K,
 }
 
+// Which banks are mapped in and how the MBC is set up
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct BankingState {
+    pub rom_bank: u8,
+    pub ram_bank: u8,
+    // Memory model 1, 4Mb rom and 32Kb ram
+    pub ram_banking_mode: bool,
+    pub ram_enabled: bool,
+}
+
 pub struct Cartridge {
     rom: Vec<u8>,
     ram_bank: Vec<u8>,
@@ -15,10 +33,13 @@ pub struct Cartridge {
     ram_bank_nr: u8,
     memory_model: MemoryModel,
     ram_bank_write_enable: bool,
+    // Without ram, writes to it are dropped and reads are open bus
+    has_ram: bool,
 }
 
 impl Cartridge {
     pub fn new(rom: Vec<u8>) -> Self {
+        let has_ram = header_has_ram(&rom);
         Cartridge {
             rom: rom,
             // TODO: generate ram bank from rom information instead
@@ -27,22 +48,153 @@ impl Cartridge {
             ram_bank_nr: 0,
             memory_model: MemoryModel::ROM16M_RAM8K,
             ram_bank_write_enable: false,
+            has_ram,
         }
     }
-    pub fn read_mem(&self, address: u16) -> Option<u8> {
-        match address {
-            ROM_BANK0_START..ROM_BANK0_END => {
-                Some(self.rom[address as usize - ROM_BANK0_START as usize])
+    // 0x80 means the game supports CGB functions, 0xC0 that it is CGB only
+    pub fn supports_cgb(&self) -> bool {
+        self.rom
+            .get(CGB_FLAG_ADDRESS)
+            .map_or(false, |flag| flag & 0x80 > 0)
+    }
+
+    pub fn type_name(&self) -> &'static str {
+        cartridge_type_name(self.cartridge_type())
+    }
+
+    // Ram is kept when the power is off, so it should be saved to a file
+    pub fn has_battery(&self) -> bool {
+        match self.cartridge_type() {
+            0x03 | 0x06 | 0x09 | 0x0D | 0x0F | 0x10 | 0x13 | 0x1B | 0x1E | 0xFF => true,
+            _ => false,
+        }
+    }
+
+    pub fn cartridge_type(&self) -> u8 {
+        self.rom.get(CARTRIDGE_TYPE_ADDRESS).cloned().unwrap_or(0)
+    }
+
+    // Only MBC1 banking is emulated, plain roms work with it too
+    pub fn is_supported(&self) -> bool {
+        match self.cartridge_type() {
+            0x00..=0x03 | 0x08 | 0x09 => true,
+            _ => false,
+        }
+    }
+
+    // Applies an IPS patch to the rom.
+    // Records are 3 byte offset, 2 byte length and the data.
+    // Length of 0 means a RLE record: 2 byte run length and the byte to repeat
+    pub fn apply_ips(&mut self, patch: &[u8]) -> io::Result<()> {
+        if patch.len() < 5 || &patch[0..5] != b"PATCH" {
+            return Err(ips_error("missing PATCH header"));
+        }
+        let mut pos = 5;
+        loop {
+            let record = patch
+                .get(pos..pos + 3)
+                .ok_or_else(|| ips_error("missing EOF marker"))?;
+            if record == b"EOF" {
+                return Ok(());
             }
-            SWITCH_ROM_BANK_START..SWITCH_ROM_BANK_END => {
-                let mut bank_nr = self.rom_bank_nr;
-                if bank_nr == 0 {
-                    bank_nr = 1;
-                }
-                let start_address = bank_nr as usize * SWITCH_ROM_BANK_LENGTH as usize;
-                Some(self.rom[start_address + (address - SWITCH_ROM_BANK_START) as usize])
+            let offset =
+                (record[0] as usize) << 16 | (record[1] as usize) << 8 | record[2] as usize;
+            let size = read_ips_u16(patch, pos + 3)?;
+            pos += 5;
+
+            if size == 0 {
+                let run_length = read_ips_u16(patch, pos)?;
+                let value = *patch
+                    .get(pos + 2)
+                    .ok_or_else(|| ips_error("truncated RLE record"))?;
+                pos += 3;
+                self.patch_rom(offset, &vec![value; run_length]);
+            } else {
+                let data = patch
+                    .get(pos..pos + size)
+                    .ok_or_else(|| ips_error("truncated record"))?;
+                pos += size;
+                self.patch_rom(offset, data);
             }
+        }
+    }
+
+    fn patch_rom(&mut self, offset: usize, data: &[u8]) {
+        // Patches are allowed to grow the rom
+        if self.rom.len() < offset + data.len() {
+            self.rom.resize(offset + data.len(), 0);
+        }
+        self.rom[offset..offset + data.len()].copy_from_slice(data);
+    }
+
+    // All ram banks
+    pub fn ram_snapshot(&self) -> Vec<u8> {
+        self.ram_bank.clone()
+    }
+
+    // Ram has to come from a snapshot of the same kind of cartridge
+    pub fn restore_ram(&mut self, ram: &[u8]) -> io::Result<()> {
+        if ram.len() != self.ram_bank.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "Ram is {} bytes, the cartridge has {}",
+                    ram.len(),
+                    self.ram_bank.len()
+                ),
+            ));
+        }
+        self.ram_bank.copy_from_slice(ram);
+        Ok(())
+    }
+
+    pub fn banking_snapshot(&self) -> BankingState {
+        BankingState {
+            rom_bank: self.rom_bank_nr,
+            ram_bank: self.ram_bank_nr,
+            ram_banking_mode: self.memory_model == MemoryModel::ROM4M_RAM32K,
+            ram_enabled: self.ram_bank_write_enable,
+        }
+    }
+
+    pub fn restore_banking(&mut self, banking: BankingState) {
+        self.rom_bank_nr = banking.rom_bank;
+        self.ram_bank_nr = banking.ram_bank;
+        self.memory_model = if banking.ram_banking_mode {
+            MemoryModel::ROM4M_RAM32K
+        } else {
+            MemoryModel::ROM16M_RAM8K
+        };
+        self.ram_bank_write_enable = banking.ram_enabled;
+    }
+
+    // Rom is not part of the state, it comes from the loaded cartridge
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.bytes(&self.ram_bank);
+        let banking = self.banking_snapshot();
+        w.u8(banking.rom_bank);
+        w.u8(banking.ram_bank);
+        w.bool(banking.ram_banking_mode);
+        w.bool(banking.ram_enabled);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), EmuError> {
+        r.bytes(&mut self.ram_bank)?;
+        let banking = BankingState {
+            rom_bank: r.u8()?,
+            ram_bank: r.u8()?,
+            ram_banking_mode: r.bool()?,
+            ram_enabled: r.bool()?,
+        };
+        self.restore_banking(banking);
+        Ok(())
+    }
 
+    pub fn read_mem(&self, address: u16) -> Option<u8> {
+        match address {
+            ROM_BANK0_START..SWITCH_ROM_BANK_END => Some(self.rom[self.rom_offset(address)]),
+
+            SWITCH_RAM_BANK_START..SWITCH_RAM_BANK_END if !self.has_ram => Some(0xFF),
             SWITCH_RAM_BANK_START..SWITCH_RAM_BANK_END => {
                 let start_address = self.ram_bank_nr as usize * SWITCH_RAM_BANK_LENGTH as usize;
                 Some(self.ram_bank[start_address + (address - SWITCH_RAM_BANK_START) as usize])
@@ -51,6 +203,25 @@ impl Cartridge {
         }
     }
 
+    // Changes the rom byte currently mapped at address, for debugging
+    pub fn poke_rom(&mut self, address: u16, value: u8) {
+        let offset = self.rom_offset(address);
+        self.rom[offset] = value;
+    }
+
+    // Rom offset of an address in the rom banks
+    fn rom_offset(&self, address: u16) -> usize {
+        if address < SWITCH_ROM_BANK_START {
+            return (address - ROM_BANK0_START) as usize;
+        }
+        let mut bank_nr = self.rom_bank_nr;
+        if bank_nr == 0 {
+            bank_nr = 1;
+        }
+        let start_address = bank_nr as usize * SWITCH_ROM_BANK_LENGTH as usize;
+        start_address + (address - SWITCH_ROM_BANK_START) as usize
+    }
+
     // Returns true if the write was handled. False otherwise
     pub fn write_mem(&mut self, address: u16, value: u8) -> bool {
         match address {
@@ -77,6 +248,7 @@ impl Cartridge {
                 // TODO: handle 16/8 mode somehow
             }
 
+            SWITCH_RAM_BANK_START..SWITCH_RAM_BANK_END if !self.has_ram => {}
             SWITCH_RAM_BANK_START..SWITCH_RAM_BANK_END => {
                 self.ram_bank[self.ram_bank_nr as usize * SWITCH_RAM_BANK_LENGTH as usize
                     + (address - SWITCH_RAM_BANK_START) as usize] = value;
@@ -86,3 +258,169 @@ impl Cartridge {
         true
     }
 }
+
+// Battery saves go next to the rom, "game.gb" saves to "game.sav"
+pub fn save_path<P: AsRef<Path>>(rom_path: P) -> PathBuf {
+    rom_path.as_ref().with_extension("sav")
+}
+
+// Name of the cartridge type in the header at 0x0147
+pub fn cartridge_type_name(byte: u8) -> &'static str {
+    match byte {
+        0x00 => "ROM ONLY",
+        0x01 => "ROM+MBC1",
+        0x02 => "ROM+MBC1+RAM",
+        0x03 => "ROM+MBC1+RAM+BATT",
+        0x05 => "ROM+MBC2",
+        0x06 => "ROM+MBC2+BATTERY",
+        0x08 => "ROM+RAM",
+        0x09 => "ROM+RAM+BATTERY",
+        0x0B => "ROM+MMM01",
+        0x0C => "ROM+MMM01+SRAM",
+        0x0D => "ROM+MMM01+SRAM+BATT",
+        0x0F => "ROM+MBC3+TIMER+BATT",
+        0x10 => "ROM+MBC3+TIMER+RAM+BATT",
+        0x11 => "ROM+MBC3",
+        0x12 => "ROM+MBC3+RAM",
+        0x13 => "ROM+MBC3+RAM+BATT",
+        0x19 => "ROM+MBC5",
+        0x1A => "ROM+MBC5+RAM",
+        0x1B => "ROM+MBC5+RAM+BATT",
+        0x1C => "ROM+MBC5+RUMBLE",
+        0x1D => "ROM+MBC5+RUMBLE+SRAM",
+        0x1E => "ROM+MBC5+RUMBLE+SRAM+BATT",
+        0x1F => "Pocket Camera",
+        0xFD => "Bandai TAMA5",
+        0xFE => "Hudson HuC-3",
+        0xFF => "Hudson HuC-1",
+        _ => "Unknown",
+    }
+}
+
+// MBC2 has its ram built in, otherwise the header's ram size tells
+fn header_has_ram(rom: &[u8]) -> bool {
+    match rom.get(CARTRIDGE_TYPE_ADDRESS) {
+        Some(0x05) | Some(0x06) => true,
+        _ => rom.get(RAM_SIZE_ADDRESS).map_or(false, |&size| size != 0),
+    }
+}
+
+fn read_ips_u16(patch: &[u8], pos: usize) -> io::Result<usize> {
+    let bytes = patch
+        .get(pos..pos + 2)
+        .ok_or_else(|| ips_error("truncated record"))?;
+    Ok((bytes[0] as usize) << 8 | bytes[1] as usize)
+}
+
+fn ips_error(msg: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Invalid IPS patch: {}", msg),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_apply_ips() {
+        let mut cartridge = Cartridge::new(vec![0; 0x8000]);
+        let mut patch = b"PATCH".to_vec();
+        // Normal record: 1 byte at 0x0150
+        patch.extend_from_slice(&[0x00, 0x01, 0x50, 0x00, 0x01, 0xAB]);
+        // RLE record: 3 bytes of 0x77 at 0x4000
+        patch.extend_from_slice(&[0x00, 0x40, 0x00, 0x00, 0x00, 0x00, 0x03, 0x77]);
+        patch.extend_from_slice(b"EOF");
+
+        cartridge.apply_ips(&patch).unwrap();
+        assert_eq!(cartridge.read_mem(0x014F), Some(0x00));
+        assert_eq!(cartridge.read_mem(0x0150), Some(0xAB));
+        assert_eq!(cartridge.read_mem(0x4002), Some(0x77));
+        assert_eq!(cartridge.read_mem(0x4003), Some(0x00));
+    }
+
+    #[test]
+    fn test_apply_ips_invalid_markers() {
+        let mut cartridge = Cartridge::new(vec![0; 0x8000]);
+        assert!(cartridge.apply_ips(b"PACTH").is_err());
+        assert!(cartridge
+            .apply_ips(b"PATCH\x00\x01\x50\x00\x01\xAB")
+            .is_err());
+    }
+
+    #[test]
+    fn test_banking_snapshot() {
+        // Each rom bank starts with its number
+        let mut rom = vec![0; 0x4000 * 8];
+        for bank in 0..8 {
+            rom[bank * 0x4000] = bank as u8;
+        }
+        // MBC1 with 32Kb ram
+        rom[CARTRIDGE_TYPE_ADDRESS] = 0x03;
+        rom[RAM_SIZE_ADDRESS] = 0x03;
+        let mut cartridge = Cartridge::new(rom.clone());
+        cartridge.write_mem(0x0000, 0x0A);
+        cartridge.write_mem(0x2000, 5);
+        cartridge.write_mem(0x6000, 1);
+        cartridge.write_mem(0x4000, 2);
+        cartridge.write_mem(0xA000, 0x42);
+
+        let banking = cartridge.banking_snapshot();
+        assert_eq!(
+            banking,
+            BankingState {
+                rom_bank: 5,
+                ram_bank: 2,
+                ram_banking_mode: true,
+                ram_enabled: true,
+            }
+        );
+
+        let mut restored = Cartridge::new(rom);
+        restored.restore_banking(banking);
+        restored.restore_ram(&cartridge.ram_snapshot()).unwrap();
+        assert_eq!(restored.banking_snapshot(), banking);
+        // A snapshot of a different size is rejected, leaving the ram alone
+        let err = restored.restore_ram(&[0; 16]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(restored.read_mem(0x4000), Some(5));
+        assert_eq!(restored.read_mem(0xA000), Some(0x42));
+    }
+
+    #[test]
+    fn test_no_ram_cartridge() {
+        let mut cartridge = Cartridge::new(vec![0; 0x8000]);
+        cartridge.write_mem(0x0000, 0x0A);
+        assert!(cartridge.write_mem(0xA000, 0x42));
+        assert_eq!(cartridge.read_mem(0xA000), Some(0xFF));
+        assert_eq!(cartridge.read_mem(0xBFFF), Some(0xFF));
+    }
+
+    #[test]
+    fn test_cartridge_type_name() {
+        assert_eq!(cartridge_type_name(0x00), "ROM ONLY");
+        assert_eq!(cartridge_type_name(0x03), "ROM+MBC1+RAM+BATT");
+        assert_eq!(cartridge_type_name(0x13), "ROM+MBC3+RAM+BATT");
+        assert_eq!(cartridge_type_name(0x1B), "ROM+MBC5+RAM+BATT");
+        assert_eq!(cartridge_type_name(0x04), "Unknown");
+
+        let mut rom = vec![0; 0x8000];
+        rom[CARTRIDGE_TYPE_ADDRESS] = 0x19;
+        assert_eq!(Cartridge::new(rom).type_name(), "ROM+MBC5");
+    }
+
+    #[test]
+    fn test_battery_and_save_path() {
+        let mut rom = vec![0; 0x8000];
+        assert!(!Cartridge::new(rom.clone()).has_battery());
+        rom[CARTRIDGE_TYPE_ADDRESS] = 0x1B;
+        assert!(Cartridge::new(rom).has_battery());
+
+        assert_eq!(save_path("rom.gb"), PathBuf::from("rom.sav"));
+        assert_eq!(
+            save_path("roms/Tetris-USA.gb"),
+            PathBuf::from("roms/Tetris-USA.sav")
+        );
+    }
+}
@@ -1,10 +1,18 @@
 use super::console::CpuText;
+use super::emulator::EmuError;
 use super::instruction;
 use super::instruction::{CB_Instruction, Instruction};
 use super::interconnect::*;
 use super::ppu::Color;
+use super::save_state::{StateReader, StateWriter};
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::io::prelude::*;
 use std::sync::mpsc;
 
+// Clock cycles from servicing an interrupt to the handler's first instruction
+const INTERRUPT_DISPATCH_CYCLES: i32 = 20;
+
 pub struct Cpu {
     reg_a: u8,
     reg_b: u8,
@@ -26,12 +34,37 @@ pub struct Cpu {
 
     pub interconnect: Interconnect,
     cycles: i32,
+    // Advance the devices before each memory access,
+    // so they see the state in the middle of an instruction
+    accurate_timing: bool,
+    // Multiplier for the machine cycles given to the devices, 1.0 normally.
+    // Only for finding out if a bug is timing sensitive
+    clock_scale: f64,
+    // Device updates owed but not yet run
+    device_ticks: f64,
     halt: bool,
+    // HALT with IME off and an interrupt already pending doesn't halt,
+    // but the next opcode fetch fails to increment PC
+    halt_bug: bool,
     stop: bool,
 
     // Debug variables
     print_instructions: bool,
     console_tx: Option<mpsc::Sender<CpuText>>,
+    break_on_illegal: bool,
+    paused: bool,
+    illegal_opcode_pc: Option<u16>,
+    // Executions and cycles per opcode, only counted when profiling
+    profiling: bool,
+    opcode_profile: HashMap<u8, (u64, u64)>,
+    cb_opcode_profile: HashMap<u8, (u64, u64)>,
+    // Last trace_len instructions and interrupts, for crash reports
+    trace: VecDeque<String>,
+    trace_len: usize,
+    // Clock cycles an interrupt has been pending (IF & IE) without being serviced
+    interrupt_pending_cycles: Option<u32>,
+    // Pending to the handler's first instruction, for the last serviced interrupt
+    last_interrupt_latency: Option<u32>,
 
     test_counter: i64,
 }
@@ -48,23 +81,75 @@ impl Cpu {
             reg_h: 0,
             reg_l: 0,
             reg_sp: 0,
-            reg_pc: 0xFE,
+            // Boot rom entry point. Starting at 0x00FE only skipped the boot
+            // with the DMG boot rom, whose last instruction there unmaps it.
+            // Other boot roms have to run from the start
+            reg_pc: 0x0000,
 
             flag_ime: false,
             flag_disabling_interrupts: false,
             flag_enabling_interrupts: false,
             halt: false,
+            halt_bug: false,
             stop: false,
             interconnect,
             cycles: 0,
+            accurate_timing: false,
+            clock_scale: 1.0,
+            device_ticks: 0.0,
 
             print_instructions: false,
             console_tx: None,
+            break_on_illegal: false,
+            paused: false,
+            illegal_opcode_pc: None,
+            profiling: false,
+            opcode_profile: HashMap::new(),
+            cb_opcode_profile: HashMap::new(),
+            trace: VecDeque::new(),
+            trace_len: 0,
+            interrupt_pending_cycles: None,
+            last_interrupt_latency: None,
             test_counter: 0,
         }
     }
 
+    // Sets the registers to the state the boot rom leaves them in
+    // and starts executing from the cartridge entry point.
+    // Games check A to see if they are running on a CGB
+    pub fn post_boot_init(&mut self) {
+        let cgb = self.interconnect.cgb_mode();
+        self.skip_boot(cgb);
+    }
+
+    // Same as post_boot_init, but picking which boot rom's state to use
+    pub fn skip_boot(&mut self, cgb: bool) {
+        if cgb {
+            self.set_af(0x1180);
+            self.set_bc(0x0000);
+            self.set_de(0xFF56);
+            self.set_hl(0x000D);
+        } else {
+            self.set_af(0x01B0);
+            self.set_bc(0x0013);
+            self.set_de(0x00D8);
+            self.set_hl(0x014D);
+        }
+        self.reg_sp = 0xFFFE;
+        self.reg_pc = 0x0100;
+        self.interconnect.post_boot_init();
+    }
+
     pub fn step(&mut self) {
+        // Stopped by the debugger, wait until resumed
+        if self.paused {
+            return;
+        }
+        self.interrupt_pending_cycles = if self.interconnect.check_interrupt() {
+            Some(self.interrupt_pending_cycles.map_or(0, |c| c + 4))
+        } else {
+            None
+        };
         // If cycles to burn, just return
         if self.cycles > 0 {
             self.cycles -= 4;
@@ -98,8 +183,39 @@ impl Cpu {
         self.do_next_instrution();
     }
 
+    // Runs machine cycles, advancing the devices along with the cpu,
+    // until at least budget clock cycles have passed.
+    // Returns the clock cycles actually run
+    pub fn run_cycles(&mut self, budget: u32) -> u32 {
+        let mut ran = 0;
+        while ran < budget && !self.paused {
+            self.step();
+            self.update_devices();
+            ran += 4;
+        }
+        ran
+    }
+
+    // Advances the devices by one cpu machine cycle times the clock scale
+    pub fn update_devices(&mut self) {
+        if self.interconnect.take_tick_ahead() {
+            return;
+        }
+        for _ in 0..self.scaled_ticks() {
+            self.interconnect.tick();
+        }
+    }
+
+    // Device ticks for one cpu machine cycle with the clock scale
+    fn scaled_ticks(&mut self) -> u32 {
+        self.device_ticks += self.clock_scale;
+        let ticks = self.device_ticks.floor();
+        self.device_ticks -= ticks;
+        ticks as u32
+    }
+
     fn handle_interrupts(&mut self) {
-        let interrupt = match self.interconnect.get_interrupt() {
+        let interrupt = match self.interconnect.service_interrupt() {
             Some(i) => i,
             None => return,
         };
@@ -107,12 +223,22 @@ impl Cpu {
         if let Some(ref tx) = self.console_tx {
             tx.send(CpuText::Interrupt(format!("{:?}", interrupt)));
         }
+        if self.trace_len > 0 {
+            self.record_trace(format!("Interrupt {:?}", interrupt));
+        }
 
         // Disable interrupts
         self.flag_ime = false;
-
-        // Jump to interrupt address
+        self.last_interrupt_latency = self
+            .interrupt_pending_cycles
+            .take()
+            .map(|c| c + INTERRUPT_DISPATCH_CYCLES as u32);
+
+        // Dispatch is 5 machine cycles: 2 idle, pushing pc and setting it.
+        // Billed here so the latency above is time the cpu really spent
+        self.add_cycles(8);
         self.push_stack_u16(self.reg_pc);
+        self.add_cycles(4);
         self.reg_pc = match interrupt {
             Interrupt::VBLANK => 0x0040,
             Interrupt::LCDStatus => 0x0048,
@@ -122,7 +248,11 @@ impl Cpu {
         };
     }
 
-    fn send_instr_text(&self, str: String) {
+    fn send_instr_text(&mut self, str: String) {
+        self.record_trace(str.clone());
+        if !self.print_instructions {
+            return;
+        }
         println!("got: {}", str);
         return;
         if let Some(ref tx) = self.console_tx {
@@ -131,30 +261,40 @@ impl Cpu {
     }
 
     fn do_next_instrution(&mut self) {
+        let start_cycles = self.cycles;
         let opcode = self.read_byte();
+        if self.halt_bug {
+            // The byte after HALT gets read twice
+            self.halt_bug = false;
+            self.reg_pc = self.reg_pc.wrapping_sub(1);
+        }
         let instr = match instruction::parse(opcode) {
             Some(o) => o,
             None => {
-                self.send_instr_text(format!(
-                    "0x{:04x}  Undefined opcode: 0x{:02x}",
-                    self.reg_pc - 1,
-                    opcode
-                ));
+                let pc = self.reg_pc.wrapping_sub(1);
+                let text = format!("0x{:04x}  Undefined opcode: 0x{:02x}", pc, opcode);
+                if !self.print_instructions {
+                    println!("{}", text);
+                }
+                self.send_instr_text(text);
+                if self.break_on_illegal {
+                    self.break_at_illegal(pc);
+                }
                 return;
             }
         };
 
-        // instruction string is only used if self.print_instructions is true
+        // instruction string is only used when tracing
         // But need to declare it still here, to use it later in the same function
         let mut instruction_string = String::with_capacity(20);
-        if self.print_instructions {
-            instruction_string.push_str(&format!("0x{:04x} ", self.reg_pc - 1));
+        if self.tracing() {
+            instruction_string.push_str(&format!("0x{:04x} ", self.reg_pc.wrapping_sub(1)));
         }
-        self.add_cycles(4);
+        let mut cb_opcode = None;
 
         match instr {
             Instruction::LD_r1_r2(r1, r2) => {
-                if self.print_instructions {
+                if self.tracing() {
                     instruction_string.push_str(&format!("LD {}, {}", reg_char(r1), reg_char(r2)));
                 }
                 let value = self.read_reg_r(r2);
@@ -162,7 +302,7 @@ impl Cpu {
             }
             Instruction::LD_r1_n(r1) => {
                 let value = self.read_byte();
-                if self.print_instructions {
+                if self.tracing() {
                     instruction_string.push_str(&format!("LD {}, ${:02x}", reg_char(r1), value));
                 }
                 self.write_reg_r(r1, value);
@@ -170,20 +310,20 @@ impl Cpu {
             Instruction::LD_A_nnptr => {
                 self.reg_a = match opcode {
                     0x0A => {
-                        if self.print_instructions {
+                        if self.tracing() {
                             instruction_string.push_str(&format!("LD A, (BC)"));
                         };
                         self.read_mem(self.bc())
                     }
                     0x1A => {
-                        if self.print_instructions {
+                        if self.tracing() {
                             instruction_string.push_str(&format!("LD A, (DE)"));
                         };
                         self.read_mem(self.de())
                     }
                     0xFA => {
                         let address = u8s_as_u16(self.read_nn());
-                        if self.print_instructions {
+                        if self.tracing() {
                             instruction_string.push_str(&format!("LD A, $({:04x})", address));
                         };
                         self.read_mem(address)
@@ -194,20 +334,20 @@ impl Cpu {
             Instruction::LD_nnptr_A => {
                 match opcode {
                     0x02 => {
-                        if self.print_instructions {
+                        if self.tracing() {
                             instruction_string.push_str(&format!("LD (BC), A"));
                         };
                         self.write_mem(self.bc(), self.reg_a);
                     }
                     0x12 => {
-                        if self.print_instructions {
+                        if self.tracing() {
                             instruction_string.push_str(&format!("LD (DE), A"));
                         };
                         self.write_mem(self.de(), self.reg_a);
                     }
                     0xEA => {
                         let address = u8s_as_u16(self.read_nn());
-                        if self.print_instructions {
+                        if self.tracing() {
                             instruction_string.push_str(&format!("LD 0x({:04x}), A", address));
                         };
                         self.write_mem(address, self.reg_a);
@@ -217,20 +357,20 @@ impl Cpu {
             }
             Instruction::LD_A_Cptr => {
                 let address = 0xFF00 + self.reg_c as u16;
-                if self.print_instructions {
+                if self.tracing() {
                     instruction_string.push_str(&format!("LD A, ($FF00+C)"));
                 }
                 self.reg_a = self.read_mem(address);
             }
             Instruction::LD_Cptr_A => {
                 let address = 0xFF00 + self.reg_c as u16;
-                if self.print_instructions {
+                if self.tracing() {
                     instruction_string.push_str(&format!("LD (C), A"));
                 }
                 self.write_mem(address, self.reg_a);
             }
             Instruction::LDD_A_HLptr => {
-                if self.print_instructions {
+                if self.tracing() {
                     instruction_string.push_str(&format!("LD A, (HL-)"));
                 }
                 let address = self.hl();
@@ -238,7 +378,7 @@ impl Cpu {
                 self.set_hl(address - 1);
             }
             Instruction::LDD_HLptr_A => {
-                if self.print_instructions {
+                if self.tracing() {
                     instruction_string.push_str(&format!("LD (HL-), A"));
                 }
                 let address = self.hl();
@@ -246,7 +386,7 @@ impl Cpu {
                 self.set_hl(address - 1);
             }
             Instruction::LDI_A_HLptr => {
-                if self.print_instructions {
+                if self.tracing() {
                     instruction_string.push_str(&format!("LD A, (HL+)"));
                 }
                 let address = self.hl();
@@ -254,7 +394,7 @@ impl Cpu {
                 self.set_hl(address + 1);
             }
             Instruction::LDI_HLptr_A => {
-                if self.print_instructions {
+                if self.tracing() {
                     instruction_string.push_str(&format!("LD (HL-), A"));
                 }
                 let address = self.hl();
@@ -264,14 +404,14 @@ impl Cpu {
 
             Instruction::LDH_nptr_A => {
                 let byte = 0xFF00 + self.read_byte() as u16;
-                if self.print_instructions {
+                if self.tracing() {
                     instruction_string.push_str(&format!("LDH $({:02x}), A", byte));
                 }
                 self.write_mem(byte, self.reg_a);
             }
             Instruction::LDH_A_nptr => {
                 let byte = 0xFF00 + self.read_byte() as u16;
-                if self.print_instructions {
+                if self.tracing() {
                     instruction_string.push_str(&format!("LDH A, $({:02x})", byte));
                 }
                 self.reg_a = self.read_mem(byte);
@@ -281,25 +421,25 @@ impl Cpu {
                 let value = u8s_as_u16(self.read_nn());
                 match opcode {
                     0x01 => {
-                        if self.print_instructions {
+                        if self.tracing() {
                             instruction_string.push_str(&format!("LD BC, ${:04x}", value));
                         }
                         self.set_bc(value);
                     }
                     0x11 => {
-                        if self.print_instructions {
+                        if self.tracing() {
                             instruction_string.push_str(&format!("LD DE, ${:04x}", value));
                         }
                         self.set_de(value);
                     }
                     0x21 => {
-                        if self.print_instructions {
+                        if self.tracing() {
                             instruction_string.push_str(&format!("LD HL, ${:04x}", value));
                         }
                         self.set_hl(value);
                     }
                     0x31 => {
-                        if self.print_instructions {
+                        if self.tracing() {
                             instruction_string.push_str(&format!("LD SP, ${:04x}", value));
                         }
                         self.reg_sp = value;
@@ -308,7 +448,7 @@ impl Cpu {
                 }
             }
             Instruction::LD_SP_HL => {
-                if self.print_instructions {
+                if self.tracing() {
                     instruction_string.push_str(&format!("LD SP,HL"));
                 }
                 self.reg_sp = self.hl();
@@ -318,7 +458,7 @@ impl Cpu {
             Instruction::LDHL_SPn => {
                 // Sign extending
                 let n = ((self.read_byte() as i8) as i16) as u16;
-                if self.print_instructions {
+                if self.tracing() {
                     instruction_string.push_str(&format!("LD HL, SP+${:02x}", n));
                 }
                 let result = self.reg_sp + n;
@@ -335,87 +475,86 @@ impl Cpu {
             }
             Instruction::LD_nn_SP => {
                 let nn = u8s_as_u16(self.read_nn());
-                if self.print_instructions {
+                if self.tracing() {
                     instruction_string.push_str(&format!("LD (${:04x}), SP", nn));
                 }
                 let (high, low) = u16_as_u8s(self.reg_sp);
                 self.write_mem(nn, low);
-                self.write_mem(nn + 1, high);
+                self.write_mem(nn.wrapping_add(1), high);
             }
 
             Instruction::PUSH_nn => {
                 match opcode {
                     0xF5 => {
-                        if self.print_instructions {
+                        if self.tracing() {
                             instruction_string.push_str(&format!("PUSH AF"));
                         }
                         self.push_stack_u16(self.af());
                     }
                     0xC5 => {
-                        if self.print_instructions {
+                        if self.tracing() {
                             instruction_string.push_str(&format!("PUSH BC"));
                         }
                         self.push_stack_u16(self.bc());
                     }
                     0xD5 => {
-                        if self.print_instructions {
+                        if self.tracing() {
                             instruction_string.push_str(&format!("PUSH DE"));
                         }
                         self.push_stack_u16(self.de());
                     }
                     0xE5 => {
-                        if self.print_instructions {
+                        if self.tracing() {
                             instruction_string.push_str(&format!("PUSH HL"));
                         }
                         self.push_stack_u16(self.hl());
                     }
                     _ => unreachable!(),
                 };
-                // Need to add 12 more to total 16
-                self.add_cycles(12);
+                // Internal delay, fetch and 2 writes total 16
+                self.add_cycles(4);
             }
             Instruction::POP_nn => {
                 let value = self.pop_stack_u16();
                 match opcode {
                     0xF1 => {
-                        if self.print_instructions {
+                        if self.tracing() {
                             instruction_string.push_str(&format!("POP AF"));
                         }
                         self.set_af(value);
                     }
                     0xC1 => {
-                        if self.print_instructions {
+                        if self.tracing() {
                             instruction_string.push_str(&format!("POP BC"));
                         }
                         self.set_bc(value);
                     }
                     0xD1 => {
-                        if self.print_instructions {
+                        if self.tracing() {
                             instruction_string.push_str(&format!("POP DE"));
                         }
                         self.set_de(value);
                     }
                     0xE1 => {
-                        if self.print_instructions {
+                        if self.tracing() {
                             instruction_string.push_str(&format!("POP HL"));
                         }
                         self.set_hl(value);
                     }
                     _ => unreachable!(),
                 }
-                // Add 8 more to total 12
-                self.add_cycles(8);
+                // Fetch and 2 reads total 12
             }
 
             Instruction::ADD_n(n) => {
                 let n = if n == 8 {
                     let value = self.read_byte();
-                    if self.print_instructions {
+                    if self.tracing() {
                         instruction_string.push_str(&format!("ADD ${:02x}", value));
                     }
                     value
                 } else {
-                    if self.print_instructions {
+                    if self.tracing() {
                         instruction_string.push_str(&format!("ADD {}", reg_char(n)));
                     }
                     self.read_reg_r(n)
@@ -432,12 +571,12 @@ impl Cpu {
             Instruction::ADC_n(n) => {
                 let n = if n == 8 {
                     let value = self.read_byte();
-                    if self.print_instructions {
+                    if self.tracing() {
                         instruction_string.push_str(&format!("ADC ${:02x}", value));
                     }
                     value
                 } else {
-                    if self.print_instructions {
+                    if self.tracing() {
                         instruction_string.push_str(&format!("ADC {}", reg_char(n)));
                     }
                     self.read_reg_r(n)
@@ -455,12 +594,12 @@ impl Cpu {
             Instruction::SUB_n(n) => {
                 let n = if n == 8 {
                     let value = self.read_byte();
-                    if self.print_instructions {
+                    if self.tracing() {
                         instruction_string.push_str(&format!("SUB ${:02x}", value));
                     }
                     value
                 } else {
-                    if self.print_instructions {
+                    if self.tracing() {
                         instruction_string.push_str(&format!("SUB {}", reg_char(n)));
                     }
                     self.read_reg_r(n)
@@ -478,12 +617,12 @@ impl Cpu {
             Instruction::SBC_n(n) => {
                 let n = if n == 8 {
                     let value = self.read_byte();
-                    if self.print_instructions {
+                    if self.tracing() {
                         instruction_string.push_str(&format!("SBC ${:02x}", value));
                     }
                     value
                 } else {
-                    if self.print_instructions {
+                    if self.tracing() {
                         instruction_string.push_str(&format!("SBC {}", reg_char(n)));
                     }
                     self.read_reg_r(n)
@@ -491,23 +630,24 @@ impl Cpu {
                 let carry = if self.flag_c() { 1 } else { 0 };
 
                 let result = self.reg_a as i16 - n as i16 - carry;
-                let carrybits = (self.reg_a ^ n) as i16 ^ carry ^ result;
+                // Borrow from bit 4 and from bit 8
+                let half_borrow = (self.reg_a & 0xF) as i16 - (n & 0xF) as i16 - carry < 0;
                 self.reg_a = result as u8;
 
                 self.set_flag_z(self.reg_a == 0);
                 self.set_flag_n(true);
-                self.set_flag_h(carrybits & 0x10 != 0);
-                self.set_flag_c(carrybits & 0x100 != 0);
+                self.set_flag_h(half_borrow);
+                self.set_flag_c(result < 0);
             }
             Instruction::AND_n(n) => {
                 let n = if n == 8 {
                     let value = self.read_byte();
-                    if self.print_instructions {
+                    if self.tracing() {
                         instruction_string.push_str(&format!("AND ${:02x}", value));
                     }
                     value
                 } else {
-                    if self.print_instructions {
+                    if self.tracing() {
                         instruction_string.push_str(&format!("AND {}", reg_char(n)));
                     }
                     self.read_reg_r(n)
@@ -522,12 +662,12 @@ impl Cpu {
             Instruction::OR_n(n) => {
                 let n = if n == 8 {
                     let value = self.read_byte();
-                    if self.print_instructions {
+                    if self.tracing() {
                         instruction_string.push_str(&format!("OR ${:02x}", value));
                     }
                     value
                 } else {
-                    if self.print_instructions {
+                    if self.tracing() {
                         instruction_string.push_str(&format!("OR {}", reg_char(n)));
                     }
                     self.read_reg_r(n)
@@ -542,12 +682,12 @@ impl Cpu {
             Instruction::XOR_n(n) => {
                 let n = if n == 8 {
                     let value = self.read_byte();
-                    if self.print_instructions {
+                    if self.tracing() {
                         instruction_string.push_str(&format!("XOR ${:02x}", value));
                     }
                     value
                 } else {
-                    if self.print_instructions {
+                    if self.tracing() {
                         instruction_string.push_str(&format!("XOR {}", reg_char(n)));
                     }
                     self.read_reg_r(n)
@@ -562,12 +702,12 @@ impl Cpu {
             Instruction::CP_n(n) => {
                 let n = if n == 8 {
                     let value = self.read_byte();
-                    if self.print_instructions {
+                    if self.tracing() {
                         instruction_string.push_str(&format!("CP ${:02x}", value));
                     }
                     value
                 } else {
-                    if self.print_instructions {
+                    if self.tracing() {
                         instruction_string.push_str(&format!("CP {}", reg_char(n)));
                     }
                     self.read_reg_r(n)
@@ -578,7 +718,7 @@ impl Cpu {
                 self.set_flag_h((self.reg_a.wrapping_sub(n)) & 0xF > self.reg_a & 0xF);
             }
             Instruction::INC_n(r) => {
-                if self.print_instructions {
+                if self.tracing() {
                     instruction_string.push_str(&format!("INC {}", reg_char(r)));
                 }
 
@@ -591,7 +731,7 @@ impl Cpu {
                 self.write_reg_r(r, result);
             }
             Instruction::DEC_n(r) => {
-                if self.print_instructions {
+                if self.tracing() {
                     instruction_string.push_str(&format!("DEC {}", reg_char(r)));
                 }
 
@@ -607,25 +747,25 @@ impl Cpu {
             Instruction::ADD_HL_nn(nn) => {
                 let nn = match nn {
                     0 => {
-                        if self.print_instructions {
+                        if self.tracing() {
                             instruction_string.push_str(&format!("ADD HL, BC"));
                         }
                         self.bc()
                     }
                     1 => {
-                        if self.print_instructions {
+                        if self.tracing() {
                             instruction_string.push_str(&format!("ADD HL, DE"));
                         }
                         self.de()
                     }
                     2 => {
-                        if self.print_instructions {
+                        if self.tracing() {
                             instruction_string.push_str(&format!("ADD HL, HL"));
                         }
                         self.hl()
                     }
                     3 => {
-                        if self.print_instructions {
+                        if self.tracing() {
                             instruction_string.push_str(&format!("ADD HL, SP"));
                         }
                         self.reg_sp
@@ -646,7 +786,7 @@ impl Cpu {
             Instruction::ADD_SP_n => {
                 // sign extend
                 let n = ((self.read_byte() as i8) as i16) as u16;
-                if self.print_instructions {
+                if self.tracing() {
                     instruction_string.push_str(&format!("ADD SP, ${:x}", n));
                 }
                 let result = self.reg_sp + n;
@@ -662,28 +802,28 @@ impl Cpu {
             Instruction::INC_nn(nn) => {
                 match nn {
                     0 => {
-                        if self.print_instructions {
+                        if self.tracing() {
                             instruction_string.push_str(&format!("INC BC"));
                         }
                         let value = self.bc();
                         self.set_bc(value + 1);
                     }
                     1 => {
-                        if self.print_instructions {
+                        if self.tracing() {
                             instruction_string.push_str(&format!("INC DE"));
                         }
                         let value = self.de();
                         self.set_de(value + 1);
                     }
                     2 => {
-                        if self.print_instructions {
+                        if self.tracing() {
                             instruction_string.push_str(&format!("INC HL"));
                         }
                         let value = self.hl();
                         self.set_hl(value + 1);
                     }
                     3 => {
-                        if self.print_instructions {
+                        if self.tracing() {
                             instruction_string.push_str(&format!("INC SP"));
                         }
                         self.reg_sp += 1;
@@ -695,28 +835,28 @@ impl Cpu {
             Instruction::DEC_nn(nn) => {
                 match nn {
                     0 => {
-                        if self.print_instructions {
+                        if self.tracing() {
                             instruction_string.push_str(&format!("DEC BC"));
                         }
                         let value = self.bc();
                         self.set_bc(value - 1);
                     }
                     1 => {
-                        if self.print_instructions {
+                        if self.tracing() {
                             instruction_string.push_str(&format!("DEC DE"));
                         }
                         let value = self.de();
                         self.set_de(value - 1);
                     }
                     2 => {
-                        if self.print_instructions {
+                        if self.tracing() {
                             instruction_string.push_str(&format!("DEC HL"));
                         }
                         let value = self.hl();
                         self.set_hl(value - 1);
                     }
                     3 => {
-                        if self.print_instructions {
+                        if self.tracing() {
                             instruction_string.push_str(&format!("DEC SP"));
                         }
                         self.reg_sp -= 1;
@@ -727,7 +867,7 @@ impl Cpu {
             }
 
             Instruction::CPL => {
-                if self.print_instructions {
+                if self.tracing() {
                     instruction_string.push_str(&format!("CPL"));
                 }
                 self.reg_a = !self.reg_a;
@@ -735,7 +875,7 @@ impl Cpu {
                 self.set_flag_n(true);
             }
             Instruction::CCF => {
-                if self.print_instructions {
+                if self.tracing() {
                     instruction_string.push_str(&format!("CCF"));
                 }
                 self.set_flag_c(!self.flag_c());
@@ -743,7 +883,7 @@ impl Cpu {
                 self.set_flag_h(false);
             }
             Instruction::SCF => {
-                if self.print_instructions {
+                if self.tracing() {
                     instruction_string.push_str(&format!("SCF"));
                 }
                 self.set_flag_c(true);
@@ -751,40 +891,44 @@ impl Cpu {
                 self.set_flag_h(false);
             }
             Instruction::NOP => {
-                if self.print_instructions {
+                if self.tracing() {
                     instruction_string.push_str(&format!("NOP"));
                 }
             }
             Instruction::HALT => {
-                if self.print_instructions {
+                if self.tracing() {
                     instruction_string.push_str(&format!("HALT"));
                 }
-                self.halt = true;
+                if !self.flag_ime && self.interconnect.check_interrupt() {
+                    self.halt_bug = true;
+                } else {
+                    self.halt = true;
+                }
             }
             Instruction::STOP => {
                 // STOP always follows a 00
                 let byte = self.read_byte();
-                if self.print_instructions {
+                if self.tracing() {
                     instruction_string.push_str(&format!("STOP"));
                 }
                 self.stop = true;
                 self.interconnect.ppu.turn_lcd_off();
             }
             Instruction::DI => {
-                if self.print_instructions {
+                if self.tracing() {
                     instruction_string.push_str(&format!("DI"));
                 }
                 self.flag_disabling_interrupts = true;
             }
             Instruction::EI => {
-                if self.print_instructions {
+                if self.tracing() {
                     instruction_string.push_str(&format!("EI"));
                 }
                 self.flag_enabling_interrupts = true;
             }
 
             Instruction::RLCA => {
-                if self.print_instructions {
+                if self.tracing() {
                     instruction_string.push_str(&format!("RLCA"));
                 }
                 let bit7 = self.reg_a >> 7;
@@ -797,7 +941,7 @@ impl Cpu {
                 self.set_flag_c(bit7 == 1);
             }
             Instruction::RLA => {
-                if self.print_instructions {
+                if self.tracing() {
                     instruction_string.push_str(&format!("RLA"));
                 }
                 let bit7 = self.reg_a >> 7;
@@ -810,7 +954,7 @@ impl Cpu {
                 self.set_flag_c(bit7 == 1);
             }
             Instruction::RRCA => {
-                if self.print_instructions {
+                if self.tracing() {
                     instruction_string.push_str(&format!("RRCA"));
                 }
                 let bit0 = self.reg_a & 1;
@@ -823,7 +967,7 @@ impl Cpu {
                 self.set_flag_c(bit0 == 1);
             }
             Instruction::RRA => {
-                if self.print_instructions {
+                if self.tracing() {
                     instruction_string.push_str(&format!("RRA"));
                 }
                 let bit0 = self.reg_a & 1;
@@ -838,22 +982,25 @@ impl Cpu {
 
             Instruction::JP_nn => {
                 let address = u8s_as_u16(self.read_nn());
-                if self.print_instructions {
+                if self.tracing() {
                     instruction_string.push_str(&format!("JP ${:04x}", address));
                 }
+                // Internal delay for setting pc
+                self.add_cycles(4);
                 self.reg_pc = address;
             }
             Instruction::JP_cc_nn(cc) => {
                 let address = u8s_as_u16(self.read_nn());
-                if self.print_instructions {
+                if self.tracing() {
                     instruction_string.push_str(&format!("JP {} ${:04x}", cc_to_char(cc), address));
                 }
                 if self.check_cc(cc) {
+                    self.add_cycles(4);
                     self.reg_pc = address;
                 }
             }
             Instruction::JP_HLptr => {
-                if self.print_instructions {
+                if self.tracing() {
                     instruction_string.push_str(&format!("JP (HL)"));
                 }
                 self.reg_pc = self.hl();
@@ -861,7 +1008,7 @@ impl Cpu {
             Instruction::JR_n => {
                 // Sign extend
                 let n = ((self.read_byte() as i8) as i16) as u16;
-                if self.print_instructions {
+                if self.tracing() {
                     instruction_string.push_str(&format!("JR {}", n as i16));
                 }
                 self.reg_pc = self.reg_pc.wrapping_add(n);
@@ -870,47 +1017,51 @@ impl Cpu {
             Instruction::JR_cc_n(cc) => {
                 // Sign extend
                 let n = ((self.read_byte() as i8) as i16) as u16;
-                if self.print_instructions {
+                if self.tracing() {
                     instruction_string.push_str(&format!("JR {} {}", cc_to_char(cc), n as i16));
                 }
+                // Not taken it's only the fetches
                 if self.check_cc(cc) {
                     self.reg_pc = self.reg_pc.wrapping_add(n);
+                    self.add_cycles(4);
                 }
-                self.add_cycles(4);
             }
 
             Instruction::CALL_nn => {
                 let nn = u8s_as_u16(self.read_nn());
-                if self.print_instructions {
+                if self.tracing() {
                     instruction_string.push_str(&format!("CALL ${:04x}", nn));
                 }
+                // Internal delay before the push
+                self.add_cycles(4);
                 self.push_stack_u16(self.reg_pc);
                 self.reg_pc = nn;
-                self.add_cycles(8);
             }
 
             Instruction::CALL_cc_nn(cc) => {
                 let nn = u8s_as_u16(self.read_nn());
-                if self.print_instructions {
+                if self.tracing() {
                     instruction_string.push_str(&format!("CALL {} ${:04x}", cc_to_char(cc), nn));
                 }
+                // Not taken it's only the fetches
                 if self.check_cc(cc) {
+                    self.add_cycles(4);
                     self.push_stack_u16(self.reg_pc);
                     self.reg_pc = nn;
                 }
-                self.add_cycles(8);
             }
 
             Instruction::RST_n(n) => {
-                if self.print_instructions {
+                if self.tracing() {
                     instruction_string.push_str(&format!("RST ${:02x}H", n));
                 }
+                // Internal delay before the push
+                self.add_cycles(4);
                 self.push_stack_u16(self.reg_pc);
                 self.reg_pc = n as u16;
-                self.add_cycles(28);
             }
             Instruction::RET => {
-                if self.print_instructions {
+                if self.tracing() {
                     instruction_string.push_str(&format!("RET"));
                 }
                 let address = self.pop_stack_u16();
@@ -918,26 +1069,31 @@ impl Cpu {
                 self.add_cycles(4);
             }
             Instruction::RET_cc(cc) => {
-                if self.print_instructions {
+                if self.tracing() {
                     instruction_string.push_str(&format!("RET {}", cc_to_char(cc)));
                 }
+                // Checking the condition takes a machine cycle
+                self.add_cycles(4);
                 if self.check_cc(cc) {
                     let address = self.pop_stack_u16();
                     self.reg_pc = address;
+                    self.add_cycles(4);
                 }
-                self.add_cycles(4);
             }
             Instruction::RETI => {
-                if self.print_instructions {
+                if self.tracing() {
                     instruction_string.push_str("RETI");
                 }
                 let address = self.pop_stack_u16();
                 self.reg_pc = address;
+                // Enabled right away, a delayed EI or DI can't change it afterwards
                 self.flag_ime = true;
-                self.add_cycles(8);
+                self.flag_enabling_interrupts = false;
+                self.flag_disabling_interrupts = false;
+                self.add_cycles(4);
             }
             Instruction::DAA => {
-                if self.print_instructions {
+                if self.tracing() {
                     instruction_string.push_str("DAA");
                 }
                 let mut a = self.reg_a as u16;
@@ -967,11 +1123,111 @@ impl Cpu {
 
                 self.reg_a = a as u8;
             }
-            Instruction::CB => self.handle_cb_opcode(),
+            Instruction::CB => {
+                cb_opcode = Some(self.handle_cb_opcode());
+            }
         }
-        if self.print_instructions && instr != Instruction::CB {
+        if self.tracing() && instr != Instruction::CB {
             self.send_instr_text(instruction_string);
         }
+
+        if self.profiling {
+            let cycles = (self.cycles - start_cycles) as u64;
+            let entry = match cb_opcode {
+                Some(cb_opcode) => self.cb_opcode_profile.entry(cb_opcode),
+                None => self.opcode_profile.entry(opcode),
+            };
+            let entry = entry.or_insert((0, 0));
+            entry.0 += 1;
+            entry.1 += cycles;
+        }
+    }
+
+    pub fn set_profiling(&mut self, b: bool) {
+        self.profiling = b;
+    }
+
+    // Opcode -> (executions, cycles). CB prefixed instructions are in cb_opcode_profile
+    pub fn opcode_profile(&self) -> HashMap<u8, (u64, u64)> {
+        self.opcode_profile.clone()
+    }
+
+    pub fn cb_opcode_profile(&self) -> HashMap<u8, (u64, u64)> {
+        self.cb_opcode_profile.clone()
+    }
+
+    // Instruction text is needed for printing or the crash report trace
+    fn tracing(&self) -> bool {
+        self.print_instructions || self.trace_len > 0
+    }
+
+    fn record_trace(&mut self, text: String) {
+        if self.trace_len == 0 {
+            return;
+        }
+        if self.trace.len() == self.trace_len {
+            self.trace.pop_front();
+        }
+        self.trace.push_back(text);
+    }
+
+    // Keeps the last len instructions for crash reports, 0 turns it off
+    pub fn set_trace_len(&mut self, len: usize) {
+        self.trace_len = len;
+        while self.trace.len() > len {
+            self.trace.pop_front();
+        }
+    }
+
+    // Clock cycles from the last serviced interrupt becoming pending
+    // to its handler starting, dispatch included
+    pub fn last_interrupt_latency(&self) -> Option<u32> {
+        self.last_interrupt_latency
+    }
+
+    pub fn recent_instructions(&self) -> impl Iterator<Item = &String> {
+        self.trace.iter()
+    }
+
+    pub fn write_crash_report<W: Write>(&self, w: &mut W, reason: &str) -> io::Result<()> {
+        writeln!(w, "Crashed: {}", reason)?;
+        writeln!(w)?;
+        writeln!(w, "Registers:")?;
+        for (name, value) in self.registers().iter() {
+            writeln!(w, "  {}: 0x{:04x}", name, value)?;
+        }
+        writeln!(w)?;
+        writeln!(
+            w,
+            "Interrupts: IME {}, IE 0x{:02x}, IF 0x{:02x}, halt {}",
+            self.flag_ime,
+            self.interconnect.read_mem(0xFFFF),
+            self.interconnect.read_mem(0xFF0F),
+            self.halt
+        )?;
+        writeln!(w)?;
+        writeln!(w, "Last {} instructions:", self.trace.len())?;
+        for text in self.trace.iter() {
+            writeln!(w, "  {}", text)?;
+        }
+        Ok(())
+    }
+
+    fn break_at_illegal(&mut self, pc: u16) {
+        println!("Illegal opcode at 0x{:04x}, pausing", pc);
+        // Dump the bytes around the opcode
+        for address in (0..8).map(|i| pc.wrapping_sub(4).wrapping_add(i)) {
+            let marker = if address == pc { ">" } else { " " };
+            println!(
+                "{}0x{:04x}: 0x{:02x}",
+                marker,
+                address,
+                self.interconnect.read_mem(address)
+            );
+        }
+        self.print_registers();
+        self.illegal_opcode_pc = Some(pc);
+        self.paused = true;
     }
 
     fn print_stack_size(&self) {
@@ -991,22 +1247,22 @@ impl Cpu {
         }
     }
 
-    fn handle_cb_opcode(&mut self) {
+    // Returns the opcode following CB
+    fn handle_cb_opcode(&mut self) -> u8 {
         {
             // CB means a bit operation. Find out which one
+            // The CB prefix and this fetch are both billed by read_byte
             let opcode = self.read_byte();
             let inst = instruction::parse_cb(opcode);
 
-            self.add_cycles(4);
-
             let mut instruction_string = String::with_capacity(20);
-            if self.print_instructions {
-                instruction_string.push_str(&format!("0x{:04x} ", self.reg_pc - 2));
+            if self.tracing() {
+                instruction_string.push_str(&format!("0x{:04x} ", self.reg_pc.wrapping_sub(2)));
             }
 
             match inst {
                 CB_Instruction::BIT_b_r(b, r) => {
-                    if self.print_instructions {
+                    if self.tracing() {
                         instruction_string =
                             instruction_string + &format!("BIT {}, {}", b, reg_char(r));
                     }
@@ -1017,7 +1273,7 @@ impl Cpu {
                     self.set_flag_n(false);
                 }
                 CB_Instruction::SET_b_r(b, r) => {
-                    if self.print_instructions {
+                    if self.tracing() {
                         instruction_string =
                             instruction_string + &format!("SET {}, {}", b, reg_char(r));
                     }
@@ -1026,7 +1282,7 @@ impl Cpu {
                     self.write_reg_r(r, value);
                 }
                 CB_Instruction::RES_b_r(b, r) => {
-                    if self.print_instructions {
+                    if self.tracing() {
                         instruction_string =
                             instruction_string + &format!("RES {}, {}", b, reg_char(r));
                     }
@@ -1036,7 +1292,7 @@ impl Cpu {
                 }
 
                 CB_Instruction::RL_n(n) => {
-                    if self.print_instructions {
+                    if self.tracing() {
                         instruction_string.push_str(&format!("RL {}", reg_char(n)));
                     }
                     let mut value = self.read_reg_r(n);
@@ -1052,7 +1308,7 @@ impl Cpu {
                     self.write_reg_r(n, value);
                 }
                 CB_Instruction::RLC_n(n) => {
-                    if self.print_instructions {
+                    if self.tracing() {
                         instruction_string.push_str(&format!("RLC {}", reg_char(n)));
                     }
                     let mut value = self.read_reg_r(n);
@@ -1069,7 +1325,7 @@ impl Cpu {
                 }
 
                 CB_Instruction::SLA_n(n) => {
-                    if self.print_instructions {
+                    if self.tracing() {
                         instruction_string.push_str(&format!("SLA {}", reg_char(n)));
                     }
                     let mut value = self.read_reg_r(n);
@@ -1084,7 +1340,7 @@ impl Cpu {
                     self.write_reg_r(n, value);
                 }
                 CB_Instruction::RRC_n(n) => {
-                    if self.print_instructions {
+                    if self.tracing() {
                         instruction_string.push_str(&format!("RRC {}", reg_char(n)));
                     }
                     let mut value = self.read_reg_r(n);
@@ -1100,7 +1356,7 @@ impl Cpu {
                     self.write_reg_r(n, value);
                 }
                 CB_Instruction::SRA_n(n) => {
-                    if self.print_instructions {
+                    if self.tracing() {
                         instruction_string.push_str(&format!("SRA {}", reg_char(n)));
                     }
                     let mut value = self.read_reg_r(n);
@@ -1118,7 +1374,7 @@ impl Cpu {
                     self.write_reg_r(n, value);
                 }
                 CB_Instruction::SRL_n(n) => {
-                    if self.print_instructions {
+                    if self.tracing() {
                         instruction_string.push_str(&format!("SRL {}", reg_char(n)));
                     }
                     let mut value = self.read_reg_r(n);
@@ -1133,7 +1389,7 @@ impl Cpu {
                     self.write_reg_r(n, value);
                 }
                 CB_Instruction::RR_n(n) => {
-                    if self.print_instructions {
+                    if self.tracing() {
                         instruction_string.push_str(&format!("RR {}", reg_char(n)));
                     }
                     let mut value = self.read_reg_r(n);
@@ -1149,7 +1405,7 @@ impl Cpu {
                     self.write_reg_r(n, value);
                 }
                 CB_Instruction::SWAP_n(n) => {
-                    if self.print_instructions {
+                    if self.tracing() {
                         instruction_string.push_str(&format!("SWAP {}", reg_char(n)));
                     }
                     let mut value = self.read_reg_r(n);
@@ -1166,9 +1422,10 @@ impl Cpu {
                 }
             }
 
-            if self.print_instructions {
+            if self.tracing() {
                 self.send_instr_text(instruction_string);
             }
+            opcode
         }
     }
 
@@ -1243,11 +1500,19 @@ impl Cpu {
     }
     fn read_mem(&mut self, address: u16) -> u8 {
         self.add_cycles(4);
+        if self.accurate_timing {
+            let ticks = self.scaled_ticks();
+            self.interconnect.tick_ahead(ticks);
+        }
         self.interconnect.read_mem(address)
     }
 
     fn write_mem(&mut self, address: u16, value: u8) {
         self.add_cycles(4);
+        if self.accurate_timing {
+            let ticks = self.scaled_ticks();
+            self.interconnect.tick_ahead(ticks);
+        }
         self.interconnect.write_mem(address, value);
     }
 
@@ -1266,10 +1531,11 @@ impl Cpu {
         }
     }
 
+    // Instruction fetch and immediate reads. The 4 cycles are billed
+    // by the memory access, nothing should be added on top
     fn read_byte(&mut self) -> u8 {
-        self.add_cycles(4);
         let ret = self.read_mem(self.reg_pc);
-        self.reg_pc += 1;
+        self.reg_pc = self.reg_pc.wrapping_add(1);
         ret
     }
 
@@ -1374,6 +1640,59 @@ impl Cpu {
         self.reg_l = l;
     }
 
+    pub fn save_state(&self, w: &mut StateWriter) {
+        for &reg in [
+            self.reg_a, self.reg_b, self.reg_c, self.reg_d, self.reg_e, self.reg_f, self.reg_h,
+            self.reg_l,
+        ]
+        .iter()
+        {
+            w.u8(reg);
+        }
+        w.u16(self.reg_sp);
+        w.u16(self.reg_pc);
+        w.bool(self.flag_ime);
+        w.bool(self.flag_disabling_interrupts);
+        w.bool(self.flag_enabling_interrupts);
+        w.bool(self.halt);
+        w.bool(self.stop);
+        w.bool(self.halt_bug);
+        w.u32(self.cycles as u32);
+        self.interconnect.save_state(w);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), EmuError> {
+        self.reg_a = r.u8()?;
+        self.reg_b = r.u8()?;
+        self.reg_c = r.u8()?;
+        self.reg_d = r.u8()?;
+        self.reg_e = r.u8()?;
+        self.reg_f = r.u8()?;
+        self.reg_h = r.u8()?;
+        self.reg_l = r.u8()?;
+        self.reg_sp = r.u16()?;
+        self.reg_pc = r.u16()?;
+        self.flag_ime = r.bool()?;
+        self.flag_disabling_interrupts = r.bool()?;
+        self.flag_enabling_interrupts = r.bool()?;
+        self.halt = r.bool()?;
+        self.stop = r.bool()?;
+        self.halt_bug = r.bool()?;
+        self.cycles = r.u32()? as i32;
+        self.interconnect.load_state(r)
+    }
+
+    pub fn registers(&self) -> [(&'static str, u16); 6] {
+        [
+            ("AF", self.af()),
+            ("BC", self.bc()),
+            ("DE", self.de()),
+            ("HL", self.hl()),
+            ("SP", self.reg_sp),
+            ("PC", self.reg_pc),
+        ]
+    }
+
     pub fn set_print_instruction(&mut self, b: bool) {
         self.print_instructions = b;
     }
@@ -1384,6 +1703,35 @@ impl Cpu {
     pub fn reset_console_tx(&mut self) {
         self.console_tx = None;
     }
+
+    pub fn set_accurate_timing(&mut self, b: bool) {
+        self.accurate_timing = b;
+    }
+
+    pub fn set_clock_scale(&mut self, scale: f64) -> io::Result<()> {
+        if !scale.is_finite() || scale <= 0.0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Clock scale must be a positive number, got {}", scale),
+            ));
+        }
+        self.clock_scale = scale;
+        Ok(())
+    }
+
+    pub fn set_break_on_illegal(&mut self, b: bool) {
+        self.break_on_illegal = b;
+    }
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+    pub fn resume(&mut self) {
+        self.paused = false;
+    }
+    // Address of the last illegal opcode that paused the cpu
+    pub fn illegal_opcode_pc(&self) -> Option<u16> {
+        self.illegal_opcode_pc
+    }
 }
 
 fn reg_char(r: u8) -> &'static str {
@@ -1424,6 +1772,464 @@ fn u8s_as_u16(val: (u8, u8)) -> u16 {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::cartridge::Cartridge;
+    use crate::ppu::PpuMode;
+    use crate::testutil::assemble;
+
+    // Cpu in post boot state with the program placed at the entry point 0x100
+    fn test_cpu(program: &[u8]) -> Cpu {
+        let mut rom = vec![0; 0x8000];
+        rom[0x100..0x100 + program.len()].copy_from_slice(program);
+        let ic = Interconnect::new_headless(vec![0; 0x100], Cartridge::new(rom));
+        let mut cpu = Cpu::new(ic);
+        cpu.post_boot_init();
+        cpu
+    }
+
+    fn cgb_rom() -> Vec<u8> {
+        let mut rom = vec![0; 0x8000];
+        rom[0x143] = 0x80;
+        rom
+    }
+
+    #[test]
+    fn test_post_boot_a_register() {
+        let mut cpu = Cpu::new(Interconnect::new_headless(
+            vec![0; 0x100],
+            Cartridge::new(cgb_rom()),
+        ));
+        cpu.post_boot_init();
+        assert_eq!(cpu.reg_a, 0x11);
+
+        let mut ic = Interconnect::new_headless(vec![0; 0x100], Cartridge::new(cgb_rom()));
+        ic.set_force_dmg(true);
+        let mut cpu = Cpu::new(ic);
+        cpu.post_boot_init();
+        assert_eq!(cpu.reg_a, 0x01);
+    }
+
+    #[test]
+    fn test_accurate_timing_mid_instruction_access() {
+        // LDH A, ($41) reads STAT on the last machine cycle of the instruction.
+        // Ppu is 3 machine cycles from switching from mode 2 to mode 3
+        let cpu_near_mode_3 = || {
+            let mut cpu = test_cpu(&[0xF0, 0x41]);
+            for _ in 0..17 {
+                cpu.interconnect.ppu.update();
+            }
+            cpu
+        };
+        let mut cpu = cpu_near_mode_3();
+        cpu.step();
+        assert_eq!(cpu.reg_a & 0b11, 2);
+
+        let mut cpu = cpu_near_mode_3();
+        cpu.set_accurate_timing(true);
+        cpu.step();
+        assert_eq!(cpu.reg_a & 0b11, 3);
+    }
+
+    #[test]
+    fn test_accurate_timing_mid_instruction_oam_access() {
+        // LD A, (HL) and LD (HL), A access OAM on their second machine cycle.
+        // Ppu is 2 machine cycles from leaving HBlank for line 1's OAM search
+        let to_hblank = |cpu: &mut Cpu| {
+            while cpu.interconnect.ppu.mode() != PpuMode::HBlank {
+                cpu.interconnect.ppu.update();
+            }
+        };
+        let mut cpu = test_cpu(&[]);
+        to_hblank(&mut cpu);
+        let mut hblank_updates = 0;
+        while cpu.interconnect.ppu.mode() != PpuMode::OamSearch {
+            cpu.interconnect.ppu.update();
+            hblank_updates += 1;
+        }
+
+        let cpu_near_oam_search = |program: &[u8], accurate| {
+            let mut cpu = test_cpu(program);
+            cpu.set_accurate_timing(accurate);
+            cpu.interconnect.ppu.set_strict_access(true);
+            cpu.interconnect.ppu.load_oam(0, &[0x42]);
+            cpu.reg_h = 0xFE;
+            cpu.reg_l = 0x00;
+            cpu.reg_a = 0x10;
+            to_hblank(&mut cpu);
+            for _ in 0..hblank_updates - 2 {
+                cpu.interconnect.ppu.update();
+            }
+            cpu
+        };
+
+        // Read at the end of the instruction, still in HBlank
+        let mut cpu = cpu_near_oam_search(&[0x7E], false);
+        cpu.step();
+        assert_eq!(cpu.reg_a, 0x42);
+        // Read in the machine cycle OAM search starts, the ppu has OAM
+        let mut cpu = cpu_near_oam_search(&[0x7E], true);
+        cpu.step();
+        assert_eq!(cpu.reg_a, 0xFF);
+
+        let mut cpu = cpu_near_oam_search(&[0x77], false);
+        cpu.step();
+        assert_eq!(cpu.interconnect.ppu.peek_sprite_mem(0xFE00), 0x10);
+        // The write is dropped
+        let mut cpu = cpu_near_oam_search(&[0x77], true);
+        cpu.step();
+        assert_eq!(cpu.interconnect.ppu.peek_sprite_mem(0xFE00), 0x42);
+    }
+
+    #[test]
+    fn test_sbc_flags() {
+        // a, n, carry in, result, Z, H, C
+        let cases = [
+            (0x00, 0x00, true, 0xFF, false, true, true),
+            (0x01, 0x00, true, 0x00, true, false, false),
+            (0x10, 0x00, true, 0x0F, false, true, false),
+            (0x10, 0x0F, true, 0x00, true, true, false),
+            (0x3B, 0x2A, false, 0x11, false, false, false),
+            (0x3B, 0x4F, true, 0xEB, false, true, true),
+            (0x3B, 0x3B, false, 0x00, true, false, false),
+            (0xFF, 0xFF, true, 0xFF, false, true, true),
+            (0x80, 0x7F, true, 0x00, true, true, false),
+        ];
+        for &(a, n, carry, result, z, h, c) in cases.iter() {
+            // SBC A, B
+            let mut cpu = test_cpu(&[0x98]);
+            cpu.reg_a = a;
+            cpu.reg_b = n;
+            cpu.reg_f = 0;
+            cpu.set_flag_c(carry);
+            cpu.step();
+
+            let case = format!("{:02x} - {:02x} - {}", a, n, carry as u8);
+            assert_eq!(cpu.reg_a, result, "{}", case);
+            assert_eq!(cpu.flag_z(), z, "Z {}", case);
+            assert!(cpu.flag_n(), "N {}", case);
+            assert_eq!(cpu.flag_h(), h, "H {}", case);
+            assert_eq!(cpu.flag_c(), c, "C {}", case);
+        }
+    }
+
+    #[test]
+    fn test_boot_starts_at_entry_point() {
+        // LD A, $12; LD B, A; JR -2. The padding at 0x00FE is RST $38
+        let boot = vec![0x3E, 0x12, 0x47, 0x18, 0xFE];
+        let mut cpu = Cpu::new(Interconnect::new_headless(
+            boot,
+            Cartridge::new(vec![0; 0x8000]),
+        ));
+        assert_eq!(cpu.reg_pc, 0x0000);
+        for _ in 0..20 {
+            cpu.step();
+        }
+        assert_eq!(cpu.reg_b, 0x12);
+        assert_eq!(cpu.reg_pc, 0x0003);
+        assert!(cpu.interconnect.booting());
+    }
+
+    #[test]
+    fn test_custom_boot_rom_handoff() {
+        let mut rom = vec![0; 0x8000];
+        rom[0] = 0x42;
+        // LD A, $01; LDH ($50), A
+        let boot = vec![0x3E, 0x01, 0xE0, 0x50];
+        let mut cpu = Cpu::new(Interconnect::new_headless(boot, Cartridge::new(rom)));
+
+        assert!(cpu.interconnect.booting());
+        assert_eq!(cpu.interconnect.read_mem(0x0000), 0x3E);
+        while cpu.reg_pc < 4 {
+            cpu.step();
+        }
+        assert!(!cpu.interconnect.booting());
+        assert!(cpu.interconnect.take_boot_rom_disabled());
+        assert!(!cpu.interconnect.take_boot_rom_disabled());
+        assert_eq!(cpu.interconnect.read_mem(0x0000), 0x42);
+    }
+
+    #[test]
+    fn test_verbose_boot_logs_logo_tile() {
+        // LD HL, $8010; LD A, $F0; LD B, 16
+        // loop: LD (HL+), A; DEC B; JR NZ, loop
+        // LD A, $80; LDH ($26), A; JR -2
+        let boot = vec![
+            0x21, 0x10, 0x80, 0x3E, 0xF0, 0x06, 0x10, 0x22, 0x05, 0x20, 0xFC, 0x3E, 0x80, 0xE0,
+            0x26, 0x18, 0xFE,
+        ];
+        let mut ic = Interconnect::new_headless(boot, Cartridge::new(vec![0; 0x8000]));
+        ic.set_verbose_boot(true);
+        let mut cpu = Cpu::new(ic);
+        for _ in 0..1000 {
+            cpu.step();
+        }
+        assert_eq!(
+            cpu.interconnect.boot_log(),
+            &[
+                "Boot: tile 1 written".to_string(),
+                "Boot: sound register 0xff26 = 0x80".to_string()
+            ]
+        );
+    }
+
+    #[test]
+    fn test_assembled_program() {
+        let mut cpu = test_cpu(&assemble("start: LD A, $05; ADD A, $03; JP start"));
+        // Run until the jump has gone back to the start
+        loop {
+            cpu.step();
+            if cpu.reg_pc == 0x100 {
+                break;
+            }
+        }
+        assert_eq!(cpu.reg_a, 8);
+    }
+
+    #[test]
+    fn test_ld_nn_sp_wraps() {
+        // LD ($FFFF), SP
+        let mut cpu = test_cpu(&[0x08, 0xFF, 0xFF]);
+        cpu.reg_sp = 0xABCD;
+        cpu.interconnect.set_count_accesses(true);
+        cpu.step();
+        assert_eq!(cpu.cycles, 20);
+        assert_eq!(cpu.interconnect.read_mem(0xFFFF), 0xCD);
+        // High byte wraps around to 0x0000, which is rom so it can't be read back
+        let stats = cpu.interconnect.access_stats();
+        assert_eq!(stats[&MemoryRegion::Rom].writes, 1);
+        assert_eq!(stats[&MemoryRegion::Io].writes, 1);
+        assert_eq!(cpu.reg_pc, 0x103);
+
+        // The high byte of nn is read from 0x0000 when pc wraps.
+        // LD ($C000), SP with the opcode in HRAM and the low byte in IE
+        let mut rom = vec![0; 0x8000];
+        rom[0] = 0xC0;
+        let mut cpu = Cpu::new(Interconnect::new_headless(vec![], Cartridge::new(rom)));
+        cpu.post_boot_init();
+        cpu.interconnect.write_mem(0xFFFE, 0x08);
+        cpu.interconnect.write_mem(0xFFFF, 0x00);
+        cpu.reg_pc = 0xFFFE;
+        cpu.reg_sp = 0xABCD;
+        cpu.step();
+        assert_eq!(cpu.cycles, 20);
+        assert_eq!(cpu.reg_pc, 0x0001);
+        assert_eq!(cpu.interconnect.read_mem(0xC000), 0xCD);
+        assert_eq!(cpu.interconnect.read_mem(0xC001), 0xAB);
+
+        // LD ($C0FF), SP
+        let mut cpu = test_cpu(&[0x08, 0xFF, 0xC0]);
+        cpu.reg_sp = 0xABCD;
+        cpu.step();
+        assert_eq!(cpu.cycles, 20);
+        assert_eq!(cpu.interconnect.read_mem(0xC0FF), 0xCD);
+        assert_eq!(cpu.interconnect.read_mem(0xC100), 0xAB);
+    }
+
+    #[test]
+    fn test_inc_hl_indirect() {
+        // INC (HL)
+        let mut cpu = test_cpu(&[0x34]);
+        cpu.set_hl(0xC000);
+        cpu.interconnect.write_mem(0xC000, 0xFF);
+        cpu.set_flag_c(true);
+        cpu.step();
+        assert_eq!(cpu.cycles, 12);
+        assert_eq!(cpu.interconnect.read_mem(0xC000), 0x00);
+        assert!(cpu.flag_z());
+        assert!(!cpu.flag_n());
+        assert!(cpu.flag_h());
+        // Carry is left alone
+        assert!(cpu.flag_c());
+    }
+
+    #[test]
+    fn test_run_cycles_one_frame() {
+        let mut cpu = test_cpu(&[]);
+        let (clocks_per_frame, _) = crate::frame_timing(crate::DEFAULT_FPS);
+        cpu.run_cycles(clocks_per_frame / 2);
+        let ly = cpu.interconnect.read_mem(0xFF44);
+        assert!(ly > 70 && ly < 80, "ly {}", ly);
+
+        cpu.run_cycles(clocks_per_frame / 2);
+        // Close to the end of the frame, in vblank
+        let ly = cpu.interconnect.read_mem(0xFF44);
+        assert!(ly >= 144, "ly {}", ly);
+    }
+
+    #[test]
+    fn test_ei_halt_services_pending_interrupt() {
+        let mut rom = vec![0; 0x8000];
+        // VBlank handler: LD A, $42; JR -2
+        rom[0x40..0x44].copy_from_slice(&[0x3E, 0x42, 0x18, 0xFE]);
+        // EI; HALT; NOP
+        rom[0x100..0x103].copy_from_slice(&[0xFB, 0x76, 0x00]);
+        let mut cpu = Cpu::new(Interconnect::new_headless(
+            vec![0; 0x100],
+            Cartridge::new(rom),
+        ));
+        cpu.post_boot_init();
+        cpu.interconnect.write_mem(0xFFFF, 0x01);
+        cpu.interconnect.write_mem(0xFF0F, 0x01);
+        cpu.reg_a = 0;
+
+        for _ in 0..8 {
+            cpu.step();
+        }
+        // Handler ran with interrupts disabled, returning after the HALT
+        assert_eq!(cpu.reg_a, 0x42);
+        assert!(cpu.reg_pc >= 0x40 && cpu.reg_pc < 0x48);
+        assert!(!cpu.flag_ime);
+        assert_eq!(cpu.pop_stack_u16(), 0x102);
+        assert_eq!(cpu.interconnect.read_mem(0xFF0F) & 0x01, 0);
+    }
+
+    #[test]
+    fn test_halt_bug() {
+        // HALT; INC B; NOP
+        let mut cpu = test_cpu(&[0x76, 0x04, 0x00]);
+        cpu.interconnect.write_mem(0xFFFF, 0x04);
+        cpu.interconnect.write_mem(0xFF0F, 0x04);
+        assert!(!cpu.flag_ime);
+        cpu.reg_b = 0;
+
+        // HALT and two INC Bs, 4 cycles each
+        cpu.step();
+        assert!(cpu.halt_bug);
+        // The double read survives a save state
+        let mut w = StateWriter::new();
+        cpu.save_state(&mut w);
+        let mut cpu = test_cpu(&[0x76, 0x04, 0x00]);
+        cpu.load_state(&mut StateReader::new(&w.into_vec()))
+            .unwrap();
+        for _ in 0..5 {
+            cpu.step();
+        }
+        assert!(!cpu.halt);
+        assert_eq!(cpu.reg_pc, 0x102);
+        assert_eq!(cpu.reg_b, 2);
+        // Not serviced with IME off
+        assert_eq!(cpu.interconnect.read_mem(0xFF0F) & 0x04, 0x04);
+    }
+
+    #[test]
+    fn test_opcode_profile() {
+        // loop: INC B; SWAP A; JR loop
+        let mut cpu = test_cpu(&[0x04, 0xCB, 0x37, 0x18, 0xFB]);
+        cpu.set_profiling(true);
+        // Each instruction takes a step and then burns a step per 4 cycles
+        for _ in 0..10 * (2 + 3 + 4) {
+            cpu.step();
+        }
+        let profile = cpu.opcode_profile();
+        assert_eq!(profile[&0x04], (10, 40));
+        assert_eq!(profile[&0x18], (10, 120));
+        assert_eq!(profile.len(), 2);
+        assert_eq!(cpu.cb_opcode_profile()[&0x37], (10, 80));
+    }
+
+    #[test]
+    fn test_fetch_cycles() {
+        // NOP; NOP; LD A, 0x12; NOP; SWAP A; BIT 0, (HL); NOP
+        let program = [0x00, 0x00, 0x3E, 0x12, 0x00, 0xCB, 0x37, 0xCB, 0x46, 0x00];
+        let mut cpu = test_cpu(&program);
+        cpu.cycles = 0;
+        let mut totals = vec![];
+        while cpu.reg_pc < 0x100 + program.len() as u16 {
+            cpu.do_next_instrution();
+            totals.push(cpu.cycles);
+        }
+        // 1 M-cycle per fetched byte, plus 1 for reading (HL)
+        assert_eq!(totals, vec![4, 8, 16, 20, 28, 40, 44]);
+        assert_eq!(cpu.reg_a, 0x21);
+
+        // 4 per fetch and memory access, plus internal delays.
+        // Z is set after boot, so NZ isn't taken and Z is
+        let cases: [(&[u8], i32); 12] = [
+            (&[0xC5], 16),             // PUSH BC
+            (&[0xC1], 12),             // POP BC
+            (&[0xEF], 16),             // RST $28
+            (&[0xD9], 16),             // RETI
+            (&[0xC9], 16),             // RET
+            (&[0xC8], 20),             // RET Z
+            (&[0xC0], 8),              // RET NZ
+            (&[0x28, 0x02], 12),       // JR Z, 2
+            (&[0x20, 0x02], 8),        // JR NZ, 2
+            (&[0xC3, 0x00, 0x02], 16), // JP $0200
+            (&[0xCA, 0x00, 0x02], 16), // JP Z, $0200
+            (&[0xC2, 0x00, 0x02], 12), // JP NZ, $0200
+        ];
+        for (program, cycles) in cases.iter() {
+            let mut cpu = test_cpu(program);
+            assert!(cpu.flag_z());
+            cpu.push_stack_u16(0x0150);
+            cpu.cycles = 0;
+            cpu.do_next_instrution();
+            assert_eq!(cpu.cycles, *cycles, "opcode 0x{:02x}", program[0]);
+        }
+    }
+
+    #[test]
+    fn test_clock_scale() {
+        // Clock cycles the cpu runs while the ppu draws 10 lines
+        let cycles_for_lines = |scale, accurate| {
+            // JR -2 forever
+            let mut cpu = test_cpu(&[0x18, 0xFE]);
+            cpu.set_clock_scale(scale).unwrap();
+            cpu.set_accurate_timing(accurate);
+            let mut lines = 0;
+            let mut cycles = 0;
+            let mut ly = cpu.interconnect.read_mem(0xFF44);
+            // Start counting right at the beginning of a line
+            while lines <= 10 {
+                let ran = cpu.run_cycles(4);
+                if lines > 0 {
+                    cycles += ran;
+                }
+                if cpu.interconnect.read_mem(0xFF44) != ly {
+                    ly = cpu.interconnect.read_mem(0xFF44);
+                    lines += 1;
+                }
+            }
+            cycles as i32
+        };
+        for &accurate in &[false, true] {
+            let normal = cycles_for_lines(1.0, accurate);
+            // Within a machine cycle of half
+            assert!((cycles_for_lines(2.0, accurate) - normal / 2).abs() <= 4);
+            assert!((cycles_for_lines(0.5, accurate) - normal * 2).abs() <= 4);
+        }
+
+        let mut cpu = test_cpu(&[]);
+        for &scale in &[0.0, -1.0, std::f64::NAN, std::f64::INFINITY] {
+            assert!(cpu.set_clock_scale(scale).is_err());
+        }
+        assert_eq!(cpu.clock_scale, 1.0);
+    }
+
+    #[test]
+    fn test_pc_wraps_at_top_of_memory() {
+        let mut cpu = test_cpu(&[]);
+        // IE at 0xFFFF holds 0x00, a NOP
+        cpu.interconnect.write_mem(0xFFFF, 0x00);
+        cpu.reg_pc = 0xFFFF;
+        cpu.step();
+        assert_eq!(cpu.reg_pc, 0x0000);
+    }
+
+    #[test]
+    fn test_break_on_illegal_opcode() {
+        let mut cpu = test_cpu(&[0xDD, 0x00]);
+        cpu.set_break_on_illegal(true);
+        cpu.step();
+        assert!(cpu.is_paused());
+        assert_eq!(cpu.illegal_opcode_pc(), Some(0x100));
+
+        // Nothing runs while paused
+        let pc = cpu.reg_pc;
+        for _ in 0..10 {
+            cpu.step();
+        }
+        assert_eq!(cpu.reg_pc, pc);
+    }
 
     #[test]
     fn test_u8s_as_u16() {
@@ -1436,4 +2242,139 @@ mod tests {
         assert_eq!(u16_as_u8s(0x1234), (0x12, 0x34));
         assert_eq!(u16_as_u8s(0xFFFF), (0xFF, 0xFF));
     }
+
+    #[test]
+    fn test_logic_hl_operand() {
+        // AND (HL); OR (HL); XOR (HL)
+        let mut cpu = test_cpu(&[0xA6, 0xB6, 0xAE]);
+        cpu.reg_h = 0xC0;
+        cpu.reg_l = 0x00;
+        cpu.interconnect.write_mem(0xC000, 0x0F);
+        cpu.reg_a = 0xF0;
+        cpu.cycles = 0;
+        cpu.do_next_instrution();
+        assert_eq!(cpu.cycles, 8);
+        assert_eq!(cpu.reg_a, 0x00);
+        assert!(cpu.flag_z());
+        assert!(!cpu.flag_n());
+        assert!(cpu.flag_h());
+        assert!(!cpu.flag_c());
+
+        cpu.reg_a = 0xF0;
+        cpu.set_flag_c(true);
+        cpu.cycles = 0;
+        cpu.do_next_instrution();
+        assert_eq!(cpu.cycles, 8);
+        assert_eq!(cpu.reg_a, 0xFF);
+        assert!(!cpu.flag_z());
+        assert!(!cpu.flag_h());
+        assert!(!cpu.flag_c());
+
+        cpu.set_flag_h(true);
+        cpu.set_flag_c(true);
+        cpu.cycles = 0;
+        cpu.do_next_instrution();
+        assert_eq!(cpu.cycles, 8);
+        assert_eq!(cpu.reg_a, 0xF0);
+        assert!(!cpu.flag_z());
+        assert!(!cpu.flag_h());
+        assert!(!cpu.flag_c());
+    }
+
+    #[test]
+    fn test_interrupt_latency() {
+        // LD BC, $1234; NOP
+        let mut cpu = test_cpu(&[0x01, 0x34, 0x12, 0x00]);
+        cpu.flag_ime = true;
+        cpu.step();
+        assert_eq!(cpu.reg_pc, 0x103);
+        let remaining = cpu.cycles as u32;
+        assert_eq!(remaining, 12);
+
+        // Timer interrupt arrives while LD is still running
+        cpu.interconnect.write_mem(0xFFFF, 0x04);
+        cpu.interconnect.write_mem(0xFF0F, 0xE4);
+        while cpu.reg_pc < 0x50 || cpu.reg_pc > 0x58 {
+            cpu.step();
+        }
+        assert_eq!(
+            cpu.last_interrupt_latency(),
+            Some(remaining + INTERRUPT_DISPATCH_CYCLES as u32)
+        );
+        assert_eq!(cpu.pop_stack_u16(), 0x103);
+    }
+
+    #[test]
+    fn test_interrupt_dispatch_cycles() {
+        // NOP
+        let mut cpu = test_cpu(&[0x00]);
+        cpu.flag_ime = true;
+        cpu.interconnect.write_mem(0xFFFF, 0x04);
+        cpu.interconnect.write_mem(0xFF0F, 0xE4);
+        cpu.cycles = 0;
+
+        // Dispatch, then the NOP at the timer vector
+        cpu.step();
+        assert_eq!(cpu.reg_pc, 0x51);
+        assert_eq!(cpu.cycles, INTERRUPT_DISPATCH_CYCLES + 4);
+        assert_eq!(
+            cpu.last_interrupt_latency(),
+            Some(INTERRUPT_DISPATCH_CYCLES as u32)
+        );
+        assert_eq!(cpu.pop_stack_u16(), 0x100);
+    }
+
+    #[test]
+    fn test_reti_enables_interrupts_immediately() {
+        // EI; RETI
+        let mut cpu = test_cpu(&[0xFB, 0xD9]);
+        cpu.push_stack_u16(0x0150);
+        cpu.cycles = 0;
+        cpu.interconnect.write_mem(0xFFFF, 0x04);
+        cpu.interconnect.write_mem(0xFF0F, 0xE4);
+
+        // EI, and RETI while the EI is still taking effect
+        cpu.step();
+        cpu.step();
+        assert!(!cpu.flag_ime);
+        cpu.step();
+        assert_eq!(cpu.reg_pc, 0x0150);
+        assert!(cpu.flag_ime);
+        assert!(!cpu.flag_enabling_interrupts);
+
+        // Serviced at the first check after RETI
+        while cpu.cycles > 0 {
+            cpu.step();
+        }
+        cpu.step();
+        assert!(cpu.reg_pc >= 0x50 && cpu.reg_pc < 0x58);
+        assert_eq!(cpu.pop_stack_u16(), 0x0150);
+    }
+
+    #[test]
+    fn test_call_cycles() {
+        // CALL NZ, $0200
+        let program = [0xC4, 0x00, 0x02];
+        let mut cpu = test_cpu(&program);
+        cpu.set_flag_z(true);
+        cpu.cycles = 0;
+        cpu.do_next_instrution();
+        assert_eq!(cpu.cycles, 12);
+        assert_eq!(cpu.reg_pc, 0x103);
+
+        let mut cpu = test_cpu(&program);
+        cpu.set_flag_z(false);
+        let sp = cpu.reg_sp;
+        cpu.cycles = 0;
+        cpu.do_next_instrution();
+        assert_eq!(cpu.cycles, 24);
+        assert_eq!(cpu.reg_pc, 0x200);
+        assert_eq!(cpu.reg_sp, sp - 2);
+
+        // CALL $0200
+        let mut cpu = test_cpu(&[0xCD, 0x00, 0x02]);
+        cpu.cycles = 0;
+        cpu.do_next_instrution();
+        assert_eq!(cpu.cycles, 24);
+    }
 }
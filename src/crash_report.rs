@@ -0,0 +1,89 @@
+use crate::cpu::Cpu;
+use std::cell::RefCell;
+use std::fs::File;
+use std::panic;
+use std::path::Path;
+
+// Instructions kept for the report
+pub const TRACE_LEN: usize = 64;
+
+thread_local! {
+    // Message and location of the last panic, set by the hook
+    static LAST_PANIC: RefCell<Option<String>> = RefCell::new(None);
+}
+
+// Remembers where the panic happened for the report.
+// The default message is still printed
+pub fn install_hook() {
+    let default_hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        LAST_PANIC.with(|last| *last.borrow_mut() = Some(info.to_string()));
+        default_hook(info);
+    }));
+}
+
+// Runs f, and if it panics writes the cpu state to path before passing the panic on
+pub fn catch_crash<P, F, R>(cpu: &mut Cpu, path: P, f: F) -> R
+where
+    P: AsRef<Path>,
+    F: FnOnce(&mut Cpu) -> R,
+{
+    let result = panic::catch_unwind(panic::AssertUnwindSafe(|| f(cpu)));
+    match result {
+        Ok(r) => r,
+        Err(payload) => {
+            let reason = LAST_PANIC
+                .with(|last| last.borrow_mut().take())
+                .or_else(|| payload.downcast_ref::<&str>().map(|s| s.to_string()))
+                .or_else(|| payload.downcast_ref::<String>().cloned())
+                .unwrap_or_else(|| "unknown panic".to_string());
+            let written =
+                File::create(&path).and_then(|mut file| cpu.write_crash_report(&mut file, &reason));
+            match written {
+                Ok(()) => eprintln!("Crash report written to {}", path.as_ref().display()),
+                Err(e) => eprintln!("Couldn't write crash report: {}", e),
+            }
+            panic::resume_unwind(payload)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cartridge::Cartridge;
+    use crate::interconnect::Interconnect;
+    use std::fs;
+
+    #[test]
+    fn test_crash_report_on_panic() {
+        let mut rom = vec![0; 0x8000];
+        // INC B, INC C, INC D
+        rom[0x100..0x103].copy_from_slice(&[0x04, 0x0C, 0x14]);
+        let mut cpu = Cpu::new(Interconnect::new_headless(vec![], Cartridge::new(rom)));
+        cpu.post_boot_init();
+        cpu.set_trace_len(2);
+
+        let path = std::env::temp_dir().join("rustboy_test_crash_report.txt");
+        let result = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+            catch_crash(&mut cpu, &path, |cpu| {
+                // 3 instructions of 4 cycles
+                for _ in 0..5 {
+                    cpu.step();
+                    cpu.interconnect.update();
+                }
+                panic!("controlled panic");
+            })
+        }));
+        assert!(result.is_err());
+
+        let report = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert!(report.contains("controlled panic"));
+        assert!(report.contains("PC: 0x0103"));
+        // Only the last 2 instructions are kept
+        assert!(!report.contains("INC B"));
+        assert!(report.contains("0x0101 INC C"));
+        assert!(report.contains("0x0102 INC D"));
+    }
+}
@@ -0,0 +1,490 @@
+use super::cpu::Cpu;
+use super::interconnect::Interconnect;
+use crate::cartridge::Cartridge;
+use crate::memory_map::*;
+use crate::save_state::{StateReader, StateWriter, STATE_MAGIC, STATE_VERSION};
+use crate::utils::fnv1a;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+// Ties the cpu and the devices together and steps them in lockstep
+pub struct Emulator {
+    pub cpu: Cpu,
+    // Clock cycles run through step
+    cycles: u64,
+}
+
+// How the emulator gets to the cartridge entry point
+pub enum BootMode {
+    // Runs the boot rom and keeps whatever state it leaves behind.
+    // Different boot roms don't all leave the same registers
+    Real(Vec<u8>),
+    // Starts at the entry point with the state of the DMG boot rom
+    SkipDmg,
+    // Starts at the entry point with the state of the CGB boot rom
+    SkipCgb,
+}
+
+// Result reported by a test rom
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum TestOutcome {
+    Passed,
+    Failed,
+    TimedOut,
+}
+
+#[derive(Debug)]
+pub enum EmuError {
+    Io(io::Error),
+    // Save state that can't be loaded
+    InvalidState(String),
+}
+
+impl fmt::Display for EmuError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EmuError::Io(e) => write!(f, "{}", e),
+            EmuError::InvalidState(reason) => write!(f, "Invalid save state: {}", reason),
+        }
+    }
+}
+
+impl From<io::Error> for EmuError {
+    fn from(e: io::Error) -> Self {
+        EmuError::Io(e)
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum StateRegion {
+    Register(&'static str),
+    Vram,
+    Wram,
+    Oam,
+    Hram,
+}
+
+// A difference between two emulator states.
+// Address is the bus address for memory and 0 for registers
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct StateDiff {
+    pub region: StateRegion,
+    pub address: u16,
+    pub a: u16,
+    pub b: u16,
+}
+
+impl Emulator {
+    pub fn new(interconnect: Interconnect) -> Self {
+        Emulator {
+            cpu: Cpu::new(interconnect),
+            cycles: 0,
+        }
+    }
+
+    pub fn with_boot_mode(cartridge: Cartridge, mode: BootMode) -> Self {
+        Emulator::boot_with(Interconnect::new, cartridge, mode)
+    }
+
+    // Runs without opening a window
+    pub fn headless(cartridge: Cartridge, mode: BootMode) -> Self {
+        Emulator::boot_with(Interconnect::new_headless, cartridge, mode)
+    }
+
+    fn boot_with(
+        interconnect: fn(Vec<u8>, Cartridge) -> Interconnect,
+        cartridge: Cartridge,
+        mode: BootMode,
+    ) -> Self {
+        match mode {
+            BootMode::Real(boot) => Emulator::new(interconnect(boot, cartridge)),
+            BootMode::SkipDmg | BootMode::SkipCgb => {
+                let mut emu = Emulator::new(interconnect(vec![], cartridge));
+                emu.cpu.skip_boot(match mode {
+                    BootMode::SkipCgb => true,
+                    _ => false,
+                });
+                emu
+            }
+        }
+    }
+
+    // One machine cycle
+    pub fn step(&mut self) {
+        self.cpu.step();
+        self.cpu.update_devices();
+        self.cycles += 4;
+    }
+
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    // Runs until the next vblank, so every call gives one frame
+    pub fn run_frame(&mut self) {
+        let frame = self.cpu.interconnect.ppu.frame_count();
+        while self.cpu.interconnect.ppu.frame_count() == frame {
+            self.step();
+        }
+    }
+
+    // Runs until a test rom reports a result, or max_cycles clock cycles have passed
+    pub fn run_test(&mut self, max_cycles: u64) -> TestOutcome {
+        let mut cycles = 0;
+        let mut serial_len = 0;
+        while cycles < max_cycles {
+            self.step();
+            cycles += 4;
+
+            // Mooneye tests load fibonacci numbers on success, 0x42 on failure
+            let registers = self.cpu.registers();
+            match (registers[1].1, registers[2].1, registers[3].1) {
+                (0x0305, 0x080D, 0x1522) => return TestOutcome::Passed,
+                (0x4242, 0x4242, 0x4242) => return TestOutcome::Failed,
+                _ => {}
+            }
+            // Blargg tests print the result over serial
+            let serial = self.cpu.interconnect.serial_output();
+            if serial.len() != serial_len {
+                serial_len = serial.len();
+                if contains(serial, b"Passed") {
+                    return TestOutcome::Passed;
+                }
+                if contains(serial, b"Failed") {
+                    return TestOutcome::Failed;
+                }
+            }
+        }
+        TestOutcome::TimedOut
+    }
+
+    pub fn save_state_to_vec(&self) -> Vec<u8> {
+        let mut w = StateWriter::new();
+        w.bytes(STATE_MAGIC);
+        w.u8(STATE_VERSION);
+        self.cpu.save_state(&mut w);
+        w.into_vec()
+    }
+
+    // Nothing changes on error. The devices are loaded one by one,
+    // so the current state is put back if a later part fails
+    pub fn load_state_from_slice(&mut self, data: &[u8]) -> Result<(), EmuError> {
+        let backup = self.save_state_to_vec();
+        let result = self.read_state(data);
+        if result.is_err() {
+            self.read_state(&backup)
+                .expect("Couldn't restore the state before loading");
+        }
+        result
+    }
+
+    fn read_state(&mut self, data: &[u8]) -> Result<(), EmuError> {
+        let mut r = StateReader::new(data);
+        let mut magic = [0; 4];
+        r.bytes(&mut magic)?;
+        if &magic != STATE_MAGIC {
+            return Err(EmuError::InvalidState("not a save state".to_string()));
+        }
+        let version = r.u8()?;
+        if version != STATE_VERSION {
+            return Err(EmuError::InvalidState(format!(
+                "unsupported version {}",
+                version
+            )));
+        }
+        self.cpu.load_state(&mut r)?;
+        if !r.finished() {
+            return Err(EmuError::InvalidState("trailing data".to_string()));
+        }
+        Ok(())
+    }
+
+    pub fn save_state<P: AsRef<Path>>(&self, path: P) -> Result<(), EmuError> {
+        fs::write(path, self.save_state_to_vec())?;
+        Ok(())
+    }
+
+    pub fn load_state<P: AsRef<Path>>(&mut self, path: P) -> Result<(), EmuError> {
+        let data = fs::read(path)?;
+        self.load_state_from_slice(&data)
+    }
+
+    // Hash of everything in a save state: registers, memory and the devices.
+    // Comparing it between runs finds where they stop matching
+    pub fn state_hash(&self) -> u64 {
+        let mut w = StateWriter::new();
+        self.cpu.save_state(&mut w);
+        fnv1a(&w.into_vec())
+    }
+
+    // Lists every register and memory byte that differs between the two states
+    pub fn diff_state(&self, other: &Emulator) -> Vec<StateDiff> {
+        let mut diffs = vec![];
+
+        let registers = self.cpu.registers();
+        let other_registers = other.cpu.registers();
+        for (&(name, a), &(_, b)) in registers.iter().zip(other_registers.iter()) {
+            if a != b {
+                diffs.push(StateDiff {
+                    region: StateRegion::Register(name),
+                    address: 0,
+                    a,
+                    b,
+                });
+            }
+        }
+
+        let ic = &self.cpu.interconnect;
+        let other_ic = &other.cpu.interconnect;
+        let regions = [
+            (
+                StateRegion::Vram,
+                VRAM_START,
+                ic.ppu.vram(),
+                other_ic.ppu.vram(),
+            ),
+            (
+                StateRegion::Wram,
+                INTERNAL_RAM_START,
+                ic.internal_ram(),
+                other_ic.internal_ram(),
+            ),
+            (
+                StateRegion::Oam,
+                SPRITE_MEM_START,
+                ic.ppu.oam(),
+                other_ic.ppu.oam(),
+            ),
+            (
+                StateRegion::Hram,
+                INTERNAL_RAM2_START,
+                &ic.internal_ram2[..],
+                &other_ic.internal_ram2[..],
+            ),
+        ];
+        for &(region, start, a, b) in regions.iter() {
+            diff_memory(&mut diffs, region, start, a, b);
+        }
+        diffs
+    }
+}
+
+fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+    haystack.windows(needle.len()).any(|w| w == needle)
+}
+
+fn diff_memory(diffs: &mut Vec<StateDiff>, region: StateRegion, start: u16, a: &[u8], b: &[u8]) {
+    for (i, (&a, &b)) in a.iter().zip(b.iter()).enumerate() {
+        if a != b {
+            diffs.push(StateDiff {
+                region,
+                address: start + i as u16,
+                a: a as u16,
+                b: b as u16,
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_emulator() -> Emulator {
+        let ic = Interconnect::new_headless(vec![0; 0x100], Cartridge::new(vec![0; 0x8000]));
+        Emulator::new(ic)
+    }
+
+    #[test]
+    fn test_diff_state() {
+        let a = test_emulator();
+        let mut b = test_emulator();
+        assert!(a.diff_state(&b).is_empty());
+
+        b.cpu.interconnect.write_mem(0xC123, 0x55);
+        assert_eq!(
+            a.diff_state(&b),
+            vec![StateDiff {
+                region: StateRegion::Wram,
+                address: 0xC123,
+                a: 0x00,
+                b: 0x55,
+            }]
+        );
+    }
+
+    fn program_emulator(program: &[u8]) -> Emulator {
+        let mut rom = vec![0; 0x8000];
+        rom[0x100..0x100 + program.len()].copy_from_slice(program);
+        let ic = Interconnect::new_headless(vec![0; 0x100], Cartridge::new(rom));
+        let mut emu = Emulator::new(ic);
+        emu.cpu.post_boot_init();
+        emu
+    }
+
+    #[test]
+    fn test_boot_mode() {
+        // Register values as (AF, PC)
+        let regs = |emu: &Emulator| {
+            let regs = emu.cpu.registers();
+            (regs[0].1, regs[5].1)
+        };
+        let emu = Emulator::headless(Cartridge::new(vec![0; 0x8000]), BootMode::SkipCgb);
+        assert_eq!(regs(&emu), (0x1180, 0x100));
+        assert!(!emu.cpu.interconnect.booting());
+
+        let emu = Emulator::headless(Cartridge::new(vec![0; 0x8000]), BootMode::SkipDmg);
+        assert_eq!(regs(&emu), (0x01B0, 0x100));
+
+        // The boot rom sets the registers itself
+        let boot = vec![0x3E, 0x42]; // LD A, 0x42
+        let mut emu = Emulator::headless(Cartridge::new(vec![0; 0x8000]), BootMode::Real(boot));
+        assert!(emu.cpu.interconnect.booting());
+        assert_eq!(regs(&emu), (0, 0));
+        for _ in 0..3 {
+            emu.step();
+        }
+        assert_eq!(regs(&emu), (0x4200, 2));
+    }
+
+    #[test]
+    fn test_run_frame() {
+        // JR -2 forever
+        let mut emu = program_emulator(&[0x18, 0xFE]);
+        emu.run_frame();
+        let start = emu.cpu.interconnect.ppu.frame_count();
+        for _ in 0..5 {
+            emu.run_frame();
+        }
+        assert_eq!(emu.cpu.interconnect.ppu.frame_count(), start + 5);
+        // Stopped right at vblank
+        assert_eq!(emu.cpu.interconnect.read_mem(0xFF44), 144);
+    }
+
+    #[test]
+    fn test_run_test_times_out() {
+        // JR -2 forever
+        let mut emu = program_emulator(&[0x18, 0xFE]);
+        assert_eq!(emu.run_test(10_000), TestOutcome::TimedOut);
+    }
+
+    #[test]
+    fn test_run_test_mooneye_pass() {
+        // LD B, 3; LD C, 5; LD D, 8; LD E, 13; LD H, 21; LD L, 34; LD B, B; JR -2
+        let program = [
+            0x06, 3, 0x0E, 5, 0x16, 8, 0x1E, 13, 0x26, 21, 0x2E, 34, 0x40, 0x18, 0xFE,
+        ];
+        let mut emu = program_emulator(&program);
+        assert_eq!(emu.run_test(10_000), TestOutcome::Passed);
+    }
+
+    #[test]
+    fn test_run_test_serial_failed() {
+        // Sends "Failed" over serial, then JR -2
+        let mut program = vec![];
+        for &c in b"Failed" {
+            // LD A, c; LDH ($01), A; LD A, $81; LDH ($02), A
+            program.extend_from_slice(&[0x3E, c, 0xE0, 0x01, 0x3E, 0x81, 0xE0, 0x02]);
+        }
+        program.extend_from_slice(&[0x18, 0xFE]);
+        let mut emu = program_emulator(&program);
+        assert_eq!(emu.run_test(10_000), TestOutcome::Failed);
+        assert_eq!(emu.cpu.interconnect.serial_output(), b"Failed");
+    }
+
+    #[test]
+    fn test_state_round_trip_through_vec() {
+        let mut a = test_emulator();
+        a.cpu.interconnect.write_mem(0xC010, 0x42);
+        a.cpu.interconnect.write_mem(0xFF80, 0x24);
+        for _ in 0..100 {
+            a.step();
+        }
+        let state = a.save_state_to_vec();
+
+        let mut b = test_emulator();
+        b.load_state_from_slice(&state).unwrap();
+        assert!(a.diff_state(&b).is_empty());
+
+        // Mutate after saving, loading again goes back to the saved state
+        let saved_registers = a.cpu.registers();
+        a.cpu.interconnect.write_mem(0xC010, 0x99);
+        a.step();
+        assert!(!a.diff_state(&b).is_empty());
+        a.load_state_from_slice(&state).unwrap();
+        assert_eq!(a.cpu.registers(), saved_registers);
+        assert_eq!(a.cpu.interconnect.read_mem(0xC010), 0x42);
+        assert!(a.diff_state(&b).is_empty());
+    }
+
+    #[test]
+    fn test_load_invalid_state() {
+        let mut emu = test_emulator();
+        let state = emu.save_state_to_vec();
+        emu.cpu.interconnect.write_mem(0xC000, 0x42);
+        let hash = emu.state_hash();
+        // Fails at the end, after everything else was read
+        assert!(emu
+            .load_state_from_slice(&state[..state.len() - 1])
+            .is_err());
+        assert!(emu.load_state_from_slice(b"nope").is_err());
+        assert_eq!(emu.state_hash(), hash);
+        assert_eq!(emu.cpu.interconnect.read_mem(0xC000), 0x42);
+    }
+
+    #[test]
+    fn test_state_hash() {
+        // LD HL,0xC000, then INC (HL) and INC HL forever
+        let program = [0x21, 0x00, 0xC0, 0x34, 0x23, 0x18, 0xFC];
+        let mut a = program_emulator(&program);
+        let mut b = program_emulator(&program);
+        assert_eq!(a.state_hash(), b.state_hash());
+        for _ in 0..10_000 {
+            a.step();
+            b.step();
+        }
+        assert_eq!(a.cycles(), b.cycles());
+        assert_eq!(a.state_hash(), b.state_hash());
+
+        let hash = b.state_hash();
+        b.cpu.interconnect.write_mem(0xD000, 0x01);
+        assert_ne!(b.state_hash(), hash);
+        b.step();
+        assert_ne!(a.state_hash(), b.state_hash());
+    }
+
+    #[test]
+    fn test_headless_frame() {
+        let mut emu = Emulator::headless(Cartridge::new(vec![0; 0x8000]), BootMode::SkipDmg);
+        assert!(emu.cpu.interconnect.ppu.main_window.is_none());
+        emu.run_frame();
+        assert_eq!(emu.cpu.interconnect.ppu.frame_count(), 1);
+        assert!(emu.cpu.interconnect.ppu.frame_ready());
+        assert_eq!(emu.cpu.interconnect.ppu.read(0xFF44), Some(144));
+    }
+
+    #[test]
+    fn test_run_frame_with_lcd_off() {
+        // LD A, $11; LDH ($40), A; JR -2
+        let mut emu = program_emulator(&[0x3E, 0x11, 0xE0, 0x40, 0x18, 0xFE]);
+        let frame = emu.cpu.interconnect.ppu.frame_count();
+        emu.run_frame();
+        emu.run_frame();
+        assert_eq!(emu.cpu.interconnect.ppu.frame_count(), frame + 2);
+        assert_eq!(emu.cpu.interconnect.read_mem(0xFF40), 0x11);
+    }
+
+    #[test]
+    fn test_state_layout_matches_version() {
+        // Changing what gets saved needs a new STATE_VERSION, so old states
+        // are rejected instead of loading as garbage. Update both together
+        let emu = test_emulator();
+        assert_eq!(
+            (STATE_VERSION, emu.save_state_to_vec().len()),
+            (8, 148009),
+            "save state layout changed, bump STATE_VERSION"
+        );
+    }
+}
@@ -0,0 +1,171 @@
+use crate::ppu::{Palette, VIEWPORT_HEIGHT, VIEWPORT_WIDTH};
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::path::Path;
+
+// Frames are captured at a varying rate, so every frame gets the same delay.
+// In 1/100 seconds, close to 60 fps
+const FRAME_DELAY: u16 = 2;
+
+// Collects presented frames and writes them out as an animated gif
+pub struct GifRecorder {
+    // The shades the ppu draws with, the gif uses the same colors
+    palette: Palette,
+    recording: bool,
+    // One palette index per pixel
+    frames: Vec<Vec<u8>>,
+}
+
+impl GifRecorder {
+    pub fn new(palette: Palette) -> Self {
+        GifRecorder {
+            palette,
+            recording: false,
+            frames: vec![],
+        }
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.recording
+    }
+
+    pub fn start(&mut self) {
+        self.frames.clear();
+        self.recording = true;
+    }
+
+    pub fn stop(&mut self) {
+        self.recording = false;
+    }
+
+    pub fn frame_count(&self) -> usize {
+        self.frames.len()
+    }
+
+    // Takes the viewport pixels when recording
+    pub fn capture(&mut self, viewport: &[u32]) {
+        if !self.recording {
+            return;
+        }
+        let frame = viewport
+            .iter()
+            .map(|&pixel| self.palette_index(pixel))
+            .collect();
+        self.frames.push(frame);
+    }
+
+    pub fn write<W: Write>(&self, w: W) -> io::Result<()> {
+        let mut palette = vec![];
+        for color in self.palette.colors.iter() {
+            palette.extend_from_slice(&color.to_be_bytes()[1..]);
+        }
+        let mut encoder =
+            gif::Encoder::new(w, VIEWPORT_WIDTH as u16, VIEWPORT_HEIGHT as u16, &palette)
+                .map_err(gif_error)?;
+        encoder
+            .set_repeat(gif::Repeat::Infinite)
+            .map_err(gif_error)?;
+        for pixels in self.frames.iter() {
+            let mut frame = gif::Frame::from_indexed_pixels(
+                VIEWPORT_WIDTH as u16,
+                VIEWPORT_HEIGHT as u16,
+                pixels,
+                None,
+            );
+            frame.delay = FRAME_DELAY;
+            encoder.write_frame(&frame).map_err(gif_error)?;
+        }
+        Ok(())
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        self.write(io::BufWriter::new(File::create(path)?))
+    }
+
+    // Closest palette shade to the pixel
+    fn palette_index(&self, pixel: u32) -> u8 {
+        let distance = |color: u32| {
+            (0..3)
+                .map(|i| {
+                    let shift = i * 8;
+                    let d = ((pixel >> shift) & 0xff) as i32 - ((color >> shift) & 0xff) as i32;
+                    d * d
+                })
+                .sum::<i32>()
+        };
+        let colors = &self.palette.colors;
+        (0..colors.len())
+            .min_by_key(|&i| distance(colors[i]))
+            .unwrap() as u8
+    }
+}
+
+fn gif_error(e: gif::EncodingError) -> io::Error {
+    match e {
+        gif::EncodingError::Io(e) => e,
+        e => io::Error::new(io::ErrorKind::Other, e.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record_two_frames() {
+        let mut recorder = GifRecorder::new(Palette::GRAYSCALE);
+        let white = vec![0xffffff; VIEWPORT_WIDTH * VIEWPORT_HEIGHT];
+        // Not recording yet
+        recorder.capture(&white);
+        assert_eq!(recorder.frame_count(), 0);
+
+        recorder.start();
+        recorder.capture(&white);
+        let mut striped = white.clone();
+        for pixel in striped.iter_mut().step_by(3) {
+            *pixel = 0;
+        }
+        recorder.capture(&striped);
+        recorder.stop();
+        recorder.capture(&white);
+        assert_eq!(recorder.frame_count(), 2);
+
+        let mut data = vec![];
+        recorder.write(&mut data).unwrap();
+
+        let mut decoder = gif::DecodeOptions::new().read_info(&data[..]).unwrap();
+        assert_eq!(decoder.width() as usize, VIEWPORT_WIDTH);
+        assert_eq!(decoder.height() as usize, VIEWPORT_HEIGHT);
+        let mut frames = vec![];
+        while let Some(frame) = decoder.read_next_frame().unwrap() {
+            assert_eq!(frame.delay, FRAME_DELAY);
+            frames.push(frame.buffer.to_vec());
+        }
+        assert_eq!(frames.len(), 2);
+        assert!(frames[0].iter().all(|&i| i == 0));
+        assert_eq!(&frames[1][..4], &[3, 0, 0, 3]);
+    }
+
+    #[test]
+    fn test_record_with_palette() {
+        let green = Palette::DMG_GREEN;
+        let mut recorder = GifRecorder::new(green);
+        recorder.start();
+        let mut pixels = vec![green.color(0); VIEWPORT_WIDTH * VIEWPORT_HEIGHT];
+        pixels[1] = green.color(3);
+        pixels[2] = green.color(2);
+        recorder.capture(&pixels);
+
+        let mut data = vec![];
+        recorder.write(&mut data).unwrap();
+        let mut decoder = gif::DecodeOptions::new().read_info(&data[..]).unwrap();
+        let mut expected = vec![];
+        for color in green.colors.iter() {
+            expected.extend_from_slice(&color.to_be_bytes()[1..]);
+        }
+        assert_eq!(decoder.global_palette(), Some(&expected[..]));
+        let frame = decoder.read_next_frame().unwrap().unwrap();
+        assert_eq!(&frame.buffer[..4], &[0, 3, 2, 0]);
+    }
+}
@@ -92,6 +92,8 @@ pub enum CB_Instruction {
 
 pub fn parse(byte: u8) -> Option<Instruction> {
     match byte {
+        // Would be LD (HL), (HL)
+        0x76 => Some(Instruction::HALT),
         0x40...0x7F => {
             let r1 = (byte >> 3) & 7;
             let r2 = byte & 7;
@@ -155,7 +157,6 @@ pub fn parse(byte: u8) -> Option<Instruction> {
         0x3F => Some(Instruction::CCF),
         0x37 => Some(Instruction::SCF),
         0x00 => Some(Instruction::NOP),
-        0x76 => Some(Instruction::HALT),
         0x10 => Some(Instruction::STOP),
         0xF3 => Some(Instruction::DI),
         0xFB => Some(Instruction::EI),
@@ -1,12 +1,16 @@
 use super::cartridge::*;
+use super::emulator::EmuError;
 use super::joypad::*;
 use super::ppu::*;
+use super::save_state::{StateReader, StateWriter};
 use super::sound_subsystem::*;
 use super::timer::*;
 use super::utils::check_bit;
 use crate::memory_map::*;
 use enum_primitive_derive::*;
 use num_traits::{FromPrimitive, ToPrimitive};
+use std::cell::Cell;
+use std::collections::HashMap;
 
 #[derive(Debug, PartialEq, PartialOrd, Clone, Copy, Primitive)]
 // The value is interrupt priority
@@ -18,6 +22,55 @@ pub enum Interrupt {
     Joypad = 4,
 }
 
+// Memory map areas for the access counters
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+pub enum MemoryRegion {
+    Rom,
+    Vram,
+    ExternalRam,
+    // Including echo ram
+    Wram,
+    // Including the unusable area after it
+    Oam,
+    // Including the interrupt enable register
+    Io,
+    Hram,
+}
+
+impl MemoryRegion {
+    // Position in MEMORY_REGIONS and the access counters
+    pub fn index(self) -> usize {
+        match self {
+            MemoryRegion::Rom => 0,
+            MemoryRegion::Vram => 1,
+            MemoryRegion::ExternalRam => 2,
+            MemoryRegion::Wram => 3,
+            MemoryRegion::Oam => 4,
+            MemoryRegion::Io => 5,
+            MemoryRegion::Hram => 6,
+        }
+    }
+}
+
+// The boot rom is mapped over 0x0000 - 0x00FF until 0xFF50 is written
+pub const BOOT_ROM_SIZE: usize = 0x100;
+
+const MEMORY_REGIONS: [MemoryRegion; 7] = [
+    MemoryRegion::Rom,
+    MemoryRegion::Vram,
+    MemoryRegion::ExternalRam,
+    MemoryRegion::Wram,
+    MemoryRegion::Oam,
+    MemoryRegion::Io,
+    MemoryRegion::Hram,
+];
+
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub struct AccessStats {
+    pub reads: u64,
+    pub writes: u64,
+}
+
 pub struct Interconnect {
     boot: Vec<u8>,
     cartridge: Cartridge,
@@ -33,33 +86,218 @@ pub struct Interconnect {
     interrupt_flag: u8,
     interrupt_enable: u8,
 
+    // Nothing is connected to the serial port, sent bytes are kept here
+    serial_data: u8,
+    serial_output: Vec<u8>,
+
     booting: bool,
+    // Set when 0xFF50 disables the boot rom, until taken
+    boot_rom_disabled: bool,
+    force_dmg: bool,
+    // Log logo tile and sound writes while booting
+    verbose_boot: bool,
+    boot_log: Vec<String>,
+    // Read the keyboard of the window when polling
+    window_input: bool,
+    input_polling: InputPolling,
+    // Line of the last poll check, a new line polls with InputPolling::Scanline
+    polled_line: u8,
+
+    // Accesses per MemoryRegion, only counted when turned on as it's
+    // on every memory access. Reads are counted through a shared reference
+    count_accesses: bool,
+    read_counts: [Cell<u64>; 7],
+    write_counts: [u64; 7],
+    // Reads and writes to echo ram. Games almost never use it on purpose
+    echo_accesses: Cell<u64>,
+    log_echo_accesses: bool,
+
+    // Machine cycles already run by the cpu in the middle of an instruction.
+    // Skipped on the following update calls so devices don't run ahead
+    ticks_ahead: u32,
 }
 
 impl Interconnect {
-    pub fn new(boot: Vec<u8>, mut cartridge: Cartridge) -> Self {
+    // Boot rom can be any program up to BOOT_ROM_SIZE bytes. Shorter ones are
+    // padded, longer ones cut off. Check the size when loading it from a file
+    pub fn new(boot: Vec<u8>, cartridge: Cartridge) -> Self {
+        Interconnect::with_ppu(boot, cartridge, Ppu::new())
+    }
+
+    // No window is opened, input only comes from update_button
+    pub fn new_headless(boot: Vec<u8>, cartridge: Cartridge) -> Self {
+        Interconnect::with_ppu(boot, cartridge, Ppu::new_headless())
+    }
+
+    fn with_ppu(mut boot: Vec<u8>, mut cartridge: Cartridge, mut ppu: Ppu) -> Self {
+        boot.resize(BOOT_ROM_SIZE, 0xFF);
+        ppu.set_cgb_mode(cartridge.supports_cgb());
         Interconnect {
             cartridge,
             boot,
             internal_ram2: vec![0; INTERNAL_RAM2_LENGTH as usize].into_boxed_slice(),
             internal_ram: vec![0; INTERNAL_RAM_LENGTH as usize].into_boxed_slice(),
-            ppu: Ppu::new(),
+            ppu,
             sound: SoundSubsystem::new(),
             timer: Timer::new(),
             joypad: Joypad::new(),
             interrupt_flag: 0,
             interrupt_enable: 0,
+            serial_data: 0,
+            serial_output: vec![],
             booting: true,
+            boot_rom_disabled: false,
+            force_dmg: false,
+            verbose_boot: false,
+            boot_log: vec![],
+            window_input: true,
+            input_polling: InputPolling::Frame,
+            polled_line: 0,
+            count_accesses: false,
+            read_counts: Default::default(),
+            write_counts: [0; 7],
+            echo_accesses: Cell::new(0),
+            log_echo_accesses: false,
+            ticks_ahead: 0,
+        }
+    }
+
+    // Report as DMG even if the cartridge supports CGB
+    pub fn set_force_dmg(&mut self, b: bool) {
+        self.force_dmg = b;
+        self.ppu.set_cgb_mode(self.cgb_mode());
+    }
+
+    pub fn set_verbose_boot(&mut self, b: bool) {
+        self.verbose_boot = b;
+    }
+
+    pub fn boot_log(&self) -> &[String] {
+        &self.boot_log
+    }
+
+    // Turn off to only take input from update_button
+    pub fn set_window_input(&mut self, b: bool) {
+        self.window_input = b;
+    }
+
+    pub fn set_input_polling(&mut self, polling: InputPolling) {
+        self.input_polling = polling;
+    }
+
+    // Host key state, seen by the game on the next poll
+    pub fn set_host_button(&mut self, btn: Button, pressed: bool) {
+        self.joypad.set_host_button(btn, pressed);
+    }
+
+    pub fn update_button(&mut self, btn: Button, pressed: bool) {
+        if self.joypad.update_button(btn, pressed) {
+            // joypad interrupt
+            self.interrupt_flag |= 1 << 4;
+        }
+    }
+
+    pub fn cgb_mode(&self) -> bool {
+        self.cartridge.supports_cgb() && !self.force_dmg
+    }
+
+    // Sets the io registers to the state the boot rom leaves them in
+    // Used when skipping the boot rom
+    pub fn post_boot_init(&mut self) {
+        self.ppu.post_boot_init();
+        self.sound.post_boot_init();
+        self.timer.post_boot_init();
+        self.joypad.post_boot_init();
+        self.interrupt_flag = 0x01;
+        self.interrupt_enable = 0;
+        self.booting = false;
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.bytes(&self.internal_ram2);
+        w.bytes(&self.internal_ram);
+        w.u8(self.interrupt_flag);
+        w.u8(self.interrupt_enable);
+        w.bool(self.booting);
+        w.u32(self.ticks_ahead);
+        self.cartridge.save_state(w);
+        self.ppu.save_state(w);
+        self.sound.save_state(w);
+        self.timer.save_state(w);
+        self.joypad.save_state(w);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), EmuError> {
+        r.bytes(&mut self.internal_ram2)?;
+        r.bytes(&mut self.internal_ram)?;
+        self.interrupt_flag = r.u8()?;
+        self.interrupt_enable = r.u8()?;
+        self.booting = r.bool()?;
+        self.ticks_ahead = r.u32()?;
+        self.cartridge.load_state(r)?;
+        self.ppu.load_state(r)?;
+        self.sound.load_state(r)?;
+        self.timer.load_state(r)?;
+        self.joypad.load_state(r)
+    }
+
+    pub fn set_count_accesses(&mut self, b: bool) {
+        self.count_accesses = b;
+    }
+
+    pub fn access_stats(&self) -> HashMap<MemoryRegion, AccessStats> {
+        MEMORY_REGIONS
+            .iter()
+            .map(|&region| {
+                let stats = AccessStats {
+                    reads: self.read_counts[region.index()].get(),
+                    writes: self.write_counts[region.index()],
+                };
+                (region, stats)
+            })
+            .collect()
+    }
+
+    pub fn reset_access_stats(&mut self) {
+        self.read_counts = Default::default();
+        self.write_counts = [0; 7];
+        self.echo_accesses.set(0);
+    }
+
+    // Print every echo ram access, they are usually bugs in the game or the emulator
+    pub fn set_log_echo_accesses(&mut self, b: bool) {
+        self.log_echo_accesses = b;
+    }
+
+    // Print the SGB commands the game sends, they aren't emulated
+    pub fn set_log_sgb(&mut self, b: bool) {
+        self.joypad.set_log_sgb(b);
+    }
+
+    pub fn echo_accesses(&self) -> u64 {
+        self.echo_accesses.get()
+    }
+
+    fn count_echo_access(&self, address: u16, kind: &str) {
+        self.echo_accesses.set(self.echo_accesses.get() + 1);
+        if self.log_echo_accesses {
+            println!("Echo ram {} at 0x{:04x}", kind, address);
         }
     }
 
     pub fn write_mem(&mut self, address: u16, value: u8) {
+        if self.count_accesses {
+            self.write_counts[memory_region(address).index()] += 1;
+        }
         if self.cartridge.write_mem(address, value) {
             return;
         }
         match address {
             0xFF50 => {
-                // Stop boot mode
+                // Stop boot mode. Nothing maps the boot rom back in
+                if self.booting {
+                    self.boot_rom_disabled = true;
+                }
                 self.booting = false;
             }
             VRAM_START..VRAM_END => self.ppu.write_vram(address, value),
@@ -68,18 +306,19 @@ impl Interconnect {
                 self.internal_ram[(address - INTERNAL_RAM_START) as usize] = value;
             }
             ECHO_RAM_START..ECHO_RAM_END => {
+                self.count_echo_access(address, "write");
                 self.internal_ram[(address - ECHO_RAM_START) as usize] = value;
             }
             INTERNAL_RAM2_START..INTERNAL_RAM2_END => {
                 self.internal_ram2[(address - INTERNAL_RAM2_START) as usize] = value;
             }
-            SPRITE_MEM_START..SPRITE_MEM_END => {
-                if value > 0 {
-                    panic!("Sprite area: 0x{:04x}, value: 0x{:02x}", address, value);
-                }
-                self.ppu.write_sprite_mem(address, value);
-            }
+            SPRITE_MEM_START..SPRITE_MEM_END => self.ppu.write_sprite_mem(address, value),
             INTERRUPT_REGISTER => self.interrupt_enable = value,
+            VRAM_BANK_REGISTER | CGB_PALETTE_START..CGB_PALETTE_END if self.cgb_mode() => {
+                self.ppu.write(address, value);
+            }
+            // CGB vram and wram bank selects, nothing to select on DMG
+            VRAM_BANK_REGISTER | WRAM_BANK_REGISTER => {}
             0xFEA0...0xFEFF => println!(
                 "Write to not usable area: 0x{:04x}, value: 0x{:02x}",
                 address, value
@@ -93,9 +332,49 @@ impl Interconnect {
                 address, value
             ),
         }
+        if self.verbose_boot && self.booting {
+            self.log_boot_write(address, value);
+        }
+    }
+
+    // Shows how far the boot rom got: the logo tiles and the boot sound
+    fn log_boot_write(&mut self, address: u16, value: u8) {
+        let message = match address {
+            // Log a tile once its last byte is written, skip clearing vram
+            VRAM_START..0x9800 if address % 16 == 15 => {
+                let tile = (address - VRAM_START) / 16;
+                let start = tile as usize * 16;
+                if self.ppu.vram()[start..start + 16].iter().all(|&b| b == 0) {
+                    return;
+                }
+                format!("Boot: tile {} written", tile)
+            }
+            0xFF10..=0xFF26 => format!("Boot: sound register 0x{:04x} = 0x{:02x}", address, value),
+            _ => return,
+        };
+        println!("{}", message);
+        self.boot_log.push(message);
     }
 
     pub fn read_mem(&self, address: u16) -> u8 {
+        if self.count_accesses {
+            let count = &self.read_counts[memory_region(address).index()];
+            count.set(count.get() + 1);
+        }
+        if (ECHO_RAM_START..ECHO_RAM_END).contains(&address) {
+            self.count_echo_access(address, "read");
+        }
+        self.read(address, true)
+    }
+
+    // Same value the cpu would read, but isn't counted or logged as an access.
+    // Never advances time, safe to call any number of times
+    pub fn read_pure(&self, address: u16) -> u8 {
+        self.read(address, false)
+    }
+
+    // Reads of unusable and unknown addresses are printed with log
+    fn read(&self, address: u16, log: bool) -> u8 {
         if self.booting && address <= 0xFF {
             return self.boot[address as usize];
         }
@@ -105,7 +384,7 @@ impl Interconnect {
         // Find out where the address points
         match address {
             VRAM_START..VRAM_END => self.ppu.read_vram(address),
-            IO_PORTS_START..IO_PORTS_END => self.io_port_read(address),
+            IO_PORTS_START..IO_PORTS_END => self.io_port_read(address, log),
             INTERNAL_RAM_START..INTERNAL_RAM_END => {
                 self.internal_ram[(address - INTERNAL_RAM_START) as usize]
             }
@@ -115,19 +394,42 @@ impl Interconnect {
             }
             SPRITE_MEM_START..SPRITE_MEM_END => self.ppu.read_sprite_mem(address),
             INTERRUPT_REGISTER => self.interrupt_enable,
-            0xFEA0...0xFEFF => {
-                println!("Read to not usable area: 0x{:04x}", address);
-                0xFF
+            VRAM_BANK_REGISTER | CGB_PALETTE_START..CGB_PALETTE_END if self.cgb_mode() => {
+                self.ppu.read(address).unwrap()
             }
-            0xFF4C..0xFF80 => {
-                println!("Read to not usable area: 0x{:04x}", address);
+            // CGB vram and wram bank selects, read as 0xFF on DMG
+            VRAM_BANK_REGISTER | WRAM_BANK_REGISTER => 0xFF,
+            0xFEA0...0xFEFF | 0xFF4C..0xFF80 => {
+                if log {
+                    println!("Read to not usable area: 0x{:04x}", address);
+                }
                 0xFF
             }
             _ => panic!("Interconnect: Can't read memory address: 0x{:04x}", address),
         }
     }
 
-    fn io_port_read(&self, address: u16) -> u8 {
+    // Debug read that works in any ppu mode
+    pub fn peek(&self, address: u16) -> u8 {
+        match address {
+            VRAM_START..VRAM_END => self.ppu.peek_vram(address),
+            SPRITE_MEM_START..SPRITE_MEM_END => self.ppu.peek_sprite_mem(address),
+            _ => self.read_pure(address),
+        }
+    }
+
+    // Debug write that works in any ppu mode and can change the rom.
+    // Other addresses are written normally
+    pub fn poke(&mut self, address: u16, value: u8) {
+        match address {
+            ROM_BANK0_START..SWITCH_ROM_BANK_END => self.cartridge.poke_rom(address, value),
+            VRAM_START..VRAM_END => self.ppu.poke_vram(address, value),
+            SPRITE_MEM_START..SPRITE_MEM_END => self.ppu.poke_sprite_mem(address, value),
+            _ => self.write_mem(address, value),
+        }
+    }
+
+    fn io_port_read(&self, address: u16, log: bool) -> u8 {
         if address == 0xFF46 {
             // DMA address. Only write
             return 0xFF;
@@ -149,9 +451,17 @@ impl Interconnect {
             return ret;
         }
         match address {
-            0xFF0F => self.interrupt_flag,
+            // Upper 3 bits are unused and always read 1
+            0xFF0F => self.interrupt_flag | 0xE0,
+            // Unused, open bus
+            0xFF03 | 0xFF08..0xFF0F => 0xFF,
+            0xFF01 => self.serial_data,
+            // Transfers are never in progress
+            0xFF02 => 0x7E,
             _ => {
-                println!("Read to unknown IO port: {:04x}", address);
+                if log {
+                    println!("Read to unknown IO port: {:04x}", address);
+                }
                 0xFF
             }
         }
@@ -159,11 +469,13 @@ impl Interconnect {
 
     fn io_port_write(&mut self, address: u16, value: u8) {
         if address == 0xFF46 {
-            // dma, move chosen area to sprite mem
+            // dma, move chosen area to sprite mem.
+            // The value is the high byte of the source address
             let start_add = (value as u16) << 8;
             for i in 0..=0x9F {
                 let val = self.read_mem(start_add + i);
-                self.ppu.write_sprite_mem(0xFE00 + i, val);
+                // DMA reaches OAM in any mode
+                self.ppu.poke_sprite_mem(0xFE00 + i, val);
             }
             self.ppu.add_cycles(200);
             return;
@@ -182,15 +494,15 @@ impl Interconnect {
         }
         match address {
             0xFF0F => self.interrupt_flag = value,
-            0xFF01 => {
-                //println!("Can't send serial data!");
-            }
+            // Unused
+            0xFF03 | 0xFF08..0xFF0F => {}
+            0xFF01 => self.serial_data = value,
             0xFF02 => {
                 if value >= 0b1000_0000 {
-                    //println!(
-                    //    "Write to serial port: addr: 0x{:04x}, 0x{:02x}",
-                    //    address, value
-                    //);
+                    // Transfer finishes right away. No other side, so 0xFF is received
+                    self.serial_output.push(self.serial_data);
+                    self.serial_data = 0xFF;
+                    self.interrupt_flag |= 1 << 3;
                 }
             }
             _ => println!(
@@ -200,12 +512,11 @@ impl Interconnect {
         }
     }
 
-    pub fn get_interrupt(&mut self) -> Option<Interrupt> {
+    // Highest priority interrupt that is requested and enabled.
+    // Doesn't change anything, safe to call from debug views
+    pub fn peek_interrupt(&self) -> Option<Interrupt> {
         for i in 0..=4 {
             if check_bit(self.interrupt_flag, i) && check_bit(self.interrupt_enable, i) {
-                // Asking for an interrupt means cpu will take it.
-                // So reset the interrupt flag here, instead of after a call from cpu
-                self.interrupt_flag &= !(1 << i);
                 // From_u8 already returns an option. However if something breaks this'll panic then
                 return Some(Interrupt::from_u8(i).unwrap());
             }
@@ -213,13 +524,15 @@ impl Interconnect {
         None
     }
 
+    // Only for the cpu dispatching the interrupt, the request is cleared from IF
+    pub fn service_interrupt(&mut self) -> Option<Interrupt> {
+        let interrupt = self.peek_interrupt()?;
+        self.interrupt_flag &= !(1 << interrupt.to_u8().unwrap());
+        Some(interrupt)
+    }
+
     pub fn check_interrupt(&self) -> bool {
-        for i in 0..=4 {
-            if check_bit(self.interrupt_flag, i) && check_bit(self.interrupt_enable, i) {
-                return true;
-            }
-        }
-        false
+        self.peek_interrupt().is_some()
     }
 
     pub fn get_button_interrupt(&mut self) -> Option<Interrupt> {
@@ -233,30 +546,191 @@ impl Interconnect {
     }
 
     pub fn update(&mut self) {
-        if self.ppu.update() {
+        if !self.take_tick_ahead() {
+            self.tick();
+        }
+    }
+
+    // Runs a machine cycle's device ticks right now, usually 1.
+    // The machine cycle's later update call is skipped
+    pub fn tick_ahead(&mut self, ticks: u32) {
+        for _ in 0..ticks {
+            self.tick();
+        }
+        self.ticks_ahead += 1;
+    }
+
+    // True if this machine cycle was already run by tick_ahead
+    pub fn take_tick_ahead(&mut self) -> bool {
+        if self.ticks_ahead == 0 {
+            return false;
+        }
+        self.ticks_ahead -= 1;
+        true
+    }
+
+    // Advances the devices by one machine cycle
+    pub fn tick(&mut self) {
+        let vblank = self.ppu.update();
+        if vblank {
             // vblank interrupt
             self.interrupt_flag |= 1;
-            // Update joypad
-            if self.joypad.update(&self.ppu.main_window) {
-                // joypad interrupt
-                self.interrupt_flag |= 1 << 4;
-            }
+        }
+        let poll = match self.input_polling {
+            InputPolling::Frame => vblank,
+            InputPolling::Scanline => self.ppu.ly() != self.polled_line,
+        };
+        self.polled_line = self.ppu.ly();
+        if poll {
+            self.poll_input();
+        }
+
+        if self.ppu.take_stat_interrupt() {
+            self.interrupt_flag |= 1 << 1;
         }
 
         if self.timer.update() {
             self.interrupt_flag |= 1 << 2;
         }
+
+        self.sound.update();
+    }
+
+    fn poll_input(&mut self) {
+        // The window only sees new key events when its events are handled,
+        // which otherwise happens once a frame when it's presented
+        if self.window_input && self.input_polling == InputPolling::Scanline {
+            self.ppu.update_window();
+        }
+        if let (true, Some(window)) = (self.window_input, &self.ppu.main_window) {
+            self.joypad.read_window(window);
+        }
+        if self.joypad.poll() {
+            // joypad interrupt
+            self.interrupt_flag |= 1 << 4;
+        }
+    }
+
+    pub fn cartridge(&self) -> &Cartridge {
+        &self.cartridge
     }
 
     pub fn boot(&self) -> &Vec<u8> {
         &self.boot
     }
+
+    // Every byte sent over the serial port
+    pub fn serial_output(&self) -> &[u8] {
+        &self.serial_output
+    }
+
+    // Generated audio goes to the sink instead of being kept for take_samples
+    pub fn set_audio_sink(&mut self, sink: Box<dyn AudioSink>, sample_rate: u32) {
+        self.sound.set_sink(sink, sample_rate);
+    }
+
+    pub fn sound(&self) -> &SoundSubsystem {
+        &self.sound
+    }
+
+    pub fn internal_ram(&self) -> &[u8] {
+        &self.internal_ram
+    }
+
+    pub fn booting(&self) -> bool {
+        self.booting
+    }
+
+    // Returns true once after the boot rom has handed over to the cartridge
+    pub fn take_boot_rom_disabled(&mut self) -> bool {
+        let disabled = self.boot_rom_disabled;
+        self.boot_rom_disabled = false;
+        disabled
+    }
+}
+
+fn memory_region(address: u16) -> MemoryRegion {
+    match address {
+        ROM_BANK0_START..SWITCH_ROM_BANK_END => MemoryRegion::Rom,
+        VRAM_START..VRAM_END => MemoryRegion::Vram,
+        SWITCH_RAM_BANK_START..SWITCH_RAM_BANK_END => MemoryRegion::ExternalRam,
+        INTERNAL_RAM_START..ECHO_RAM_END => MemoryRegion::Wram,
+        SPRITE_MEM_START..IO_PORTS_START => MemoryRegion::Oam,
+        INTERNAL_RAM2_START..=0xFFFE => MemoryRegion::Hram,
+        // IO ports and the interrupt enable register at 0xFFFF
+        _ => MemoryRegion::Io,
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    fn test_interconnect() -> Interconnect {
+        Interconnect::new_headless(vec![0; 0x100], Cartridge::new(vec![0; 0x8000]))
+    }
+
+    #[test]
+    fn test_post_boot_io_registers() {
+        let mut ic = test_interconnect();
+        ic.post_boot_init();
+        assert_eq!(ic.read_mem(0xFF00), 0xCF); // P1
+        assert_eq!(ic.read_mem(0xFF01), 0x00); // SB
+        assert_eq!(ic.read_mem(0xFF02), 0x7E); // SC
+        assert_eq!(ic.read_mem(0xFF04), 0xAB); // DIV
+        assert_eq!(ic.read_mem(0xFF05), 0x00); // TIMA
+        assert_eq!(ic.read_mem(0xFF06), 0x00); // TMA
+        assert_eq!(ic.read_mem(0xFF07), 0xF8); // TAC
+        assert_eq!(ic.read_mem(0xFF0F), 0xE1); // IF
+        assert_eq!(ic.read_mem(0xFF10), 0x80); // NR10
+        assert_eq!(ic.read_mem(0xFF11), 0xBF); // NR11
+        assert_eq!(ic.read_mem(0xFF12), 0xF3); // NR12
+        assert_eq!(ic.read_mem(0xFF14), 0xBF); // NR14
+        assert_eq!(ic.read_mem(0xFF16), 0x3F); // NR21
+        assert_eq!(ic.read_mem(0xFF17), 0x00); // NR22
+        assert_eq!(ic.read_mem(0xFF19), 0xBF); // NR24
+        assert_eq!(ic.read_mem(0xFF1A), 0x7F); // NR30
+        assert_eq!(ic.read_mem(0xFF1C), 0x9F); // NR32
+        assert_eq!(ic.read_mem(0xFF1E), 0xBF); // NR34
+        assert_eq!(ic.read_mem(0xFF21), 0x00); // NR42
+        assert_eq!(ic.read_mem(0xFF22), 0x00); // NR43
+        assert_eq!(ic.read_mem(0xFF23), 0xBF); // NR44
+        assert_eq!(ic.read_mem(0xFF24), 0x77); // NR50
+        assert_eq!(ic.read_mem(0xFF25), 0xF3); // NR51
+        assert_eq!(ic.read_mem(0xFF26), 0xF1); // NR52
+        assert_eq!(ic.read_mem(0xFF40), 0x91); // LCDC
+        assert_eq!(ic.read_mem(0xFF41) & 0x80, 0x80); // STAT
+        assert_eq!(ic.read_mem(0xFF42), 0x00); // SCY
+        assert_eq!(ic.read_mem(0xFF43), 0x00); // SCX
+        assert_eq!(ic.read_mem(0xFF45), 0x00); // LYC
+        assert_eq!(ic.read_mem(0xFF46), 0xFF); // DMA
+        assert_eq!(ic.read_mem(0xFF47), 0xFC); // BGP
+        assert_eq!(ic.read_mem(0xFF48), 0xFF); // OBP0
+        assert_eq!(ic.read_mem(0xFF49), 0xFF); // OBP1
+        assert_eq!(ic.read_mem(0xFF4A), 0x00); // WY
+        assert_eq!(ic.read_mem(0xFF4B), 0x00); // WX
+        assert_eq!(ic.read_mem(0xFFFF), 0x00); // IE
+    }
+
+    #[test]
+    fn test_cgb_bank_registers_on_dmg() {
+        let mut ic = test_interconnect();
+        ic.write_mem(0xFF4F, 0x01);
+        ic.write_mem(0xFF70, 0x02);
+        assert_eq!(ic.read_mem(0xFF4F), 0xFF);
+        assert_eq!(ic.read_mem(0xFF70), 0xFF);
+    }
+
+    #[test]
+    fn test_unused_io_reads_open_bus() {
+        let mut ic = test_interconnect();
+        ic.write_mem(0xFF03, 0x12);
+        assert_eq!(ic.read_mem(0xFF03), 0xFF);
+        for address in 0xFF08..0xFF0F {
+            assert_eq!(ic.read_mem(address), 0xFF);
+        }
+    }
+
     #[test]
     fn test_check_bit() {
         assert!(check_bit(0b0100_0000, 6));
@@ -265,4 +739,321 @@ mod tests {
         assert!(!check_bit(0b0100_0001, 3));
         assert!(!check_bit(0b0100_0001, 7));
     }
+
+    #[test]
+    fn test_access_stats() {
+        for (i, &region) in MEMORY_REGIONS.iter().enumerate() {
+            assert_eq!(region.index(), i);
+        }
+
+        let mut ic = test_interconnect();
+        // Off by default
+        ic.read_mem(0x0150);
+        ic.write_mem(0xC000, 1);
+        assert!(ic
+            .access_stats()
+            .values()
+            .all(|&s| s == AccessStats::default()));
+
+        ic.set_count_accesses(true);
+        ic.read_mem(0x0150);
+        ic.read_mem(0x4000);
+        ic.write_mem(0x8000, 1);
+        ic.write_mem(0xC000, 1);
+        ic.write_mem(0xE000, 1);
+        ic.read_mem(0xC000);
+        ic.read_mem(0xFE00);
+        ic.write_mem(0xFF80, 1);
+        ic.read_mem(0xFFFF);
+
+        let stats = ic.access_stats();
+        let counts = |region| {
+            let s: AccessStats = stats[&region];
+            (s.reads, s.writes)
+        };
+        assert_eq!(counts(MemoryRegion::Rom), (2, 0));
+        assert_eq!(counts(MemoryRegion::Vram), (0, 1));
+        assert_eq!(counts(MemoryRegion::ExternalRam), (0, 0));
+        assert_eq!(counts(MemoryRegion::Wram), (1, 2));
+        assert_eq!(counts(MemoryRegion::Oam), (1, 0));
+        assert_eq!(counts(MemoryRegion::Io), (1, 0));
+        assert_eq!(counts(MemoryRegion::Hram), (0, 1));
+    }
+
+    #[test]
+    fn test_echo_accesses() {
+        let mut ic = test_interconnect();
+        ic.write_mem(0xC000, 0x42);
+        assert_eq!(ic.echo_accesses(), 0);
+
+        assert_eq!(ic.read_mem(0xE000), 0x42);
+        assert_eq!(ic.echo_accesses(), 1);
+
+        ic.write_mem(0xFDFF, 0x24);
+        assert_eq!(ic.read_mem(0xDDFF), 0x24);
+        assert_eq!(ic.echo_accesses(), 2);
+    }
+
+    #[test]
+    fn test_vblank_and_stat_mode_1_interrupts() {
+        let mut ic = test_interconnect();
+        ic.post_boot_init();
+        ic.interrupt_flag = 0;
+        // Mode 1 STAT interrupt
+        ic.write_mem(0xFF41, 1 << 4);
+        while ic.ppu.mode() != PpuMode::VBlank {
+            assert_eq!(ic.interrupt_flag & 0b11, 0);
+            ic.update();
+        }
+        assert_eq!(ic.read_mem(0xFF44), 144);
+        assert_eq!(ic.interrupt_flag & 0b11, 0b11);
+    }
+
+    #[test]
+    fn test_peek_interrupt_keeps_request() {
+        let mut ic = test_interconnect();
+        ic.write_mem(0xFFFF, 0b101);
+        ic.interrupt_flag = 0b100;
+        for _ in 0..3 {
+            assert_eq!(ic.peek_interrupt(), Some(Interrupt::TimerOverflow));
+        }
+        assert_eq!(ic.interrupt_flag, 0b100);
+
+        ic.interrupt_flag |= 1;
+        assert_eq!(ic.service_interrupt(), Some(Interrupt::VBLANK));
+        assert_eq!(ic.interrupt_flag, 0b100);
+        assert_eq!(ic.service_interrupt(), Some(Interrupt::TimerOverflow));
+        assert_eq!(ic.interrupt_flag, 0);
+        assert_eq!(ic.peek_interrupt(), None);
+    }
+
+    #[test]
+    fn test_timer_ticks_with_update() {
+        let mut ic = test_interconnect();
+        ic.post_boot_init();
+        ic.interrupt_flag = 0;
+        // Enabled, 262144 Hz: every 16 clock cycles, 4 updates
+        ic.write_mem(0xFF07, 0b101);
+        // Resetting DIV can increase TIMA, so it's cleared after
+        ic.write_mem(0xFF04, 0);
+        ic.write_mem(0xFF05, 0);
+        for _ in 0..4 * 10 {
+            ic.update();
+        }
+        assert_eq!(ic.read_mem(0xFF05), 10);
+        // DIV counts at 16384 Hz, every 64 updates
+        for _ in 0..64 * 3 - 4 * 10 {
+            ic.update();
+        }
+        assert_eq!(ic.read_mem(0xFF04), 3);
+
+        // Overflow requests the timer interrupt
+        ic.write_mem(0xFF05, 0xFF);
+        assert_eq!(ic.interrupt_flag & (1 << 2), 0);
+        for _ in 0..4 {
+            ic.update();
+        }
+        assert_eq!(ic.interrupt_flag & (1 << 2), 1 << 2);
+    }
+
+    #[test]
+    fn test_boot_rom_unmapped_for_good() {
+        let mut rom = vec![0; 0x8000];
+        rom[0] = 0x42;
+        let mut ic = Interconnect::new_headless(vec![0x31, 0xFE], Cartridge::new(rom));
+        assert_eq!(ic.read_mem(0x0000), 0x31);
+        // Past the boot rom is always the cartridge
+        assert_eq!(ic.read_mem(0x0100), 0x00);
+
+        ic.write_mem(0xFF50, 0x01);
+        assert_eq!(ic.read_mem(0x0000), 0x42);
+
+        // Any further write leaves it unmapped
+        ic.write_mem(0xFF50, 0x00);
+        ic.write_mem(0xFF50, 0x01);
+        assert!(!ic.booting());
+        assert_eq!(ic.read_mem(0x0000), 0x42);
+    }
+
+    #[test]
+    fn test_dma_copies_sprite_table() {
+        let mut ic = test_interconnect();
+        ic.post_boot_init();
+        // Sprite table at 0xC100
+        for i in 0..0xA0 {
+            ic.write_mem(0xC100 + i, i as u8 ^ 0x5A);
+        }
+        ic.write_mem(0xC1A0, 0xEE);
+        ic.write_mem(0xFF46, 0xC1);
+        for i in 0..0xA0 {
+            assert_eq!(ic.ppu.read_sprite_mem(0xFE00 + i), i as u8 ^ 0x5A);
+        }
+        // Only 0xA0 bytes are copied
+        assert_eq!(ic.read_mem(0xFEA0), 0xFF);
+    }
+
+    #[test]
+    fn test_write_sprite_mem_directly() {
+        let mut ic = test_interconnect();
+        ic.post_boot_init();
+        ic.write_mem(0xFE00, 0x50);
+        ic.write_mem(0xFE9F, 0x81);
+        assert_eq!(ic.read_mem(0xFE00), 0x50);
+        assert_eq!(ic.read_mem(0xFE9F), 0x81);
+    }
+
+    #[test]
+    fn test_poke_and_peek() {
+        let mut ic = test_interconnect();
+        ic.post_boot_init();
+        ic.poke(0xC123, 0x42);
+        assert_eq!(ic.peek(0xC123), 0x42);
+
+        // Rom is patched in the mapped bank
+        ic.poke(0x0150, 0x18);
+        assert_eq!(ic.peek(0x0150), 0x18);
+
+        // Vram while the ppu is drawing. Normal accesses are blocked
+        ic.ppu.set_strict_access(true);
+        while ic.ppu.read(0xFF41).unwrap() & 0b11 != 3 {
+            ic.update();
+        }
+        ic.write_mem(0x8010, 0x11);
+        assert_eq!(ic.read_mem(0x8010), 0xFF);
+        assert_ne!(ic.peek(0x8010), 0x11);
+        ic.poke(0x8010, 0x7E);
+        assert_eq!(ic.peek(0x8010), 0x7E);
+        assert_eq!(ic.read_mem(0x8010), 0xFF);
+    }
+
+    #[test]
+    fn test_stat_lyc_interrupt() {
+        let mut ic = test_interconnect();
+        ic.post_boot_init();
+        ic.interrupt_flag = 0;
+        // LYC interrupt only
+        ic.write_mem(0xFF41, 1 << 6);
+        ic.write_mem(0xFF45, 10);
+        while ic.read_mem(0xFF44) != 10 {
+            assert_eq!(ic.interrupt_flag & 0b10, 0);
+            ic.update();
+        }
+        assert_eq!(ic.interrupt_flag & 0b10, 0b10);
+    }
+
+    #[test]
+    fn test_stat_mode_2_interrupt() {
+        let mut ic = test_interconnect();
+        ic.post_boot_init();
+        // Start from the middle of a line
+        while ic.ppu.mode() != PpuMode::HBlank {
+            ic.update();
+        }
+        ic.interrupt_flag = 0;
+        // OAM search interrupt only
+        ic.write_mem(0xFF41, 1 << 5);
+        while ic.ppu.mode() != PpuMode::OamSearch {
+            assert_eq!(ic.interrupt_flag & 0b10, 0);
+            ic.update();
+        }
+        assert_eq!(ic.interrupt_flag & 0b10, 0b10);
+    }
+
+    #[test]
+    fn test_dma_with_strict_access() {
+        let mut ic = test_interconnect();
+        ic.post_boot_init();
+        ic.ppu.set_strict_access(true);
+        while ic.ppu.mode() != PpuMode::OamSearch {
+            ic.update();
+        }
+        ic.write_mem(0xC000, 0x42);
+        ic.write_mem(0xFF46, 0xC0);
+        assert_eq!(ic.read_mem(0xFE00), 0xFF);
+        assert_eq!(ic.ppu.peek_sprite_mem(0xFE00), 0x42);
+    }
+
+    #[test]
+    fn test_cgb_palette_and_vram_bank() {
+        let mut rom = vec![0; 0x8000];
+        rom[0x143] = 0x80;
+        let mut ic = Interconnect::new_headless(vec![0; 0x100], Cartridge::new(rom));
+        ic.post_boot_init();
+
+        // Color 1 of BG palette 2, auto increment
+        ic.write_mem(0xFF68, 0x80 | (2 * 8 + 2));
+        ic.write_mem(0xFF69, 0x1F);
+        ic.write_mem(0xFF69, 0x7C);
+        assert_eq!(ic.read_mem(0xFF68), 0xC0 | (2 * 8 + 4));
+        ic.write_mem(0xFF68, 2 * 8 + 2);
+        assert_eq!(ic.read_mem(0xFF69), 0x1F);
+        ic.write_mem(0xFF68, 2 * 8 + 3);
+        assert_eq!(ic.read_mem(0xFF69), 0x7C);
+        // OBJ palettes are separate
+        ic.write_mem(0xFF6A, 2 * 8 + 2);
+        assert_eq!(ic.read_mem(0xFF6B), 0);
+
+        ic.write_mem(0x8000, 0x11);
+        ic.write_mem(0xFF4F, 1);
+        assert_eq!(ic.read_mem(0xFF4F), 0xFF);
+        assert_eq!(ic.read_mem(0x8000), 0);
+        ic.write_mem(0x8000, 0x22);
+        ic.write_mem(0xFF4F, 0);
+        assert_eq!(ic.read_mem(0xFF4F), 0xFE);
+        assert_eq!(ic.read_mem(0x8000), 0x11);
+
+        // Forcing DMG drops the CGB registers
+        ic.set_force_dmg(true);
+        assert_eq!(ic.read_mem(0xFF4F), 0xFF);
+        assert_eq!(ic.read_mem(0xFF69), 0xFF);
+    }
+
+    #[test]
+    fn test_read_pure() {
+        let mut ic = test_interconnect();
+        ic.post_boot_init();
+        ic.set_count_accesses(true);
+        ic.write_mem(0xC010, 0x42);
+        let reads = ic.access_stats()[&MemoryRegion::Wram].reads;
+        let div = ic.read_pure(0xFF04);
+        for _ in 0..1000 {
+            assert_eq!(ic.read_pure(0xFF04), div);
+        }
+        assert_eq!(ic.read_pure(0xE010), 0x42);
+        assert_eq!(ic.access_stats()[&MemoryRegion::Wram].reads, reads);
+        assert_eq!(ic.echo_accesses(), 0);
+
+        // Reading as time passes, DIV goes up every 64 machine cycles
+        for _ in 0..64 {
+            ic.update();
+            ic.read_mem(0xFF04);
+        }
+        assert_eq!(ic.read_mem(0xFF04), div.wrapping_add(1));
+    }
+
+    #[test]
+    fn test_scanline_input_polling() {
+        for &polling in &[InputPolling::Frame, InputPolling::Scanline] {
+            let mut ic = test_interconnect();
+            ic.set_input_polling(polling);
+            // Select buttons
+            ic.write_mem(0xFF00, 0x10);
+            while ic.read_mem(0xFF44) != 10 {
+                ic.update();
+            }
+            ic.interrupt_flag = 0;
+            ic.set_host_button(Button::A, true);
+            while ic.read_mem(0xFF44) != 11 {
+                ic.update();
+            }
+            let pressed = ic.read_mem(0xFF00) & 1 == 0;
+            assert_eq!(pressed, polling == InputPolling::Scanline);
+            assert_eq!(ic.interrupt_flag & (1 << 4) != 0, pressed);
+
+            while ic.ppu.mode() != PpuMode::VBlank {
+                ic.update();
+            }
+            assert_eq!(ic.read_mem(0xFF00) & 1, 0);
+        }
+    }
 }
@@ -1,3 +1,6 @@
+use super::emulator::EmuError;
+use super::save_state::{StateReader, StateWriter};
+use super::sgb::{self, SgbDecoder};
 use super::utils::check_bit;
 use minifb::{Key, Window};
 
@@ -7,6 +10,16 @@ enum Mode {
     None,
 }
 
+// When the host keys are copied into the joypad.
+// Scanline also handles the window's events on every line, so key
+// changes show up before the next frame is presented
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum InputPolling {
+    Frame,
+    Scanline,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
 pub enum Button {
     Down,
     Up,
@@ -21,6 +34,20 @@ pub enum Button {
 pub struct Joypad {
     register: u8,
     keys: u8,
+    // Keys held on the host, copied into keys on poll
+    host_keys: u8,
+    // Analog stick positions inside this are ignored. 0.0 - 1.0
+    dead_zone: f32,
+    // Logs SGB packets sent through the register when set
+    sgb_decoder: Option<SgbDecoder>,
+}
+
+#[derive(Debug, PartialEq, Default)]
+pub struct Dpad {
+    pub up: bool,
+    pub down: bool,
+    pub left: bool,
+    pub right: bool,
 }
 
 impl Joypad {
@@ -28,8 +55,31 @@ impl Joypad {
         Joypad {
             register: 0,
             keys: 0,
+            host_keys: 0,
+            dead_zone: 0.25,
+            sgb_decoder: None,
         }
     }
+    // Register value left behind by the DMG boot rom
+    pub fn post_boot_init(&mut self) {
+        self.register = 0xCF;
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.u8(self.register);
+        w.u8(self.keys);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), EmuError> {
+        self.register = r.u8()?;
+        self.keys = r.u8()?;
+        Ok(())
+    }
+
+    pub fn set_log_sgb(&mut self, b: bool) {
+        self.sgb_decoder = if b { Some(SgbDecoder::new()) } else { None };
+    }
+
     pub fn read(&self, address: u16) -> Option<u8> {
         match address {
             0xFF00 => Some(self.register),
@@ -47,6 +97,9 @@ impl Joypad {
                 self.register |= value & 0xF0;
                 // Update the key values
                 self.update_register();
+                if let Some(id) = self.sgb_decoder.as_mut().and_then(|d| d.write(value)) {
+                    println!("SGB command 0x{:02x} {}", id, sgb::command_name(id));
+                }
             }
             _ => return false,
         }
@@ -54,35 +107,68 @@ impl Joypad {
     }
 
     pub fn update(&mut self, window: &Window) -> bool {
-        let mut interrupt = false;
+        self.read_window(window);
+        self.poll()
+    }
 
-        self.update_button(Button::A, window.is_key_down(Key::Z));
-        self.update_button(Button::B, window.is_key_down(Key::X));
-        self.update_button(Button::Select, window.is_key_down(Key::C));
-        self.update_button(Button::Start, window.is_key_down(Key::Space));
-        self.update_button(Button::Up, window.is_key_down(Key::Up));
-        self.update_button(Button::Down, window.is_key_down(Key::Down));
-        self.update_button(Button::Right, window.is_key_down(Key::Right));
-        self.update_button(Button::Left, window.is_key_down(Key::Left));
+    pub fn read_window(&mut self, window: &Window) {
+        self.set_host_button(Button::A, window.is_key_down(Key::Z));
+        self.set_host_button(Button::B, window.is_key_down(Key::X));
+        self.set_host_button(Button::Select, window.is_key_down(Key::C));
+        self.set_host_button(Button::Start, window.is_key_down(Key::Space));
+        self.set_host_button(Button::Up, window.is_key_down(Key::Up));
+        self.set_host_button(Button::Down, window.is_key_down(Key::Down));
+        self.set_host_button(Button::Right, window.is_key_down(Key::Right));
+        self.set_host_button(Button::Left, window.is_key_down(Key::Left));
+    }
 
-        // TODO: handle interrupt stuff
-        false
+    pub fn set_host_button(&mut self, btn: Button, pressed: bool) {
+        let bit = get_button_bit(btn);
+        if pressed {
+            self.host_keys |= 1 << bit;
+        } else {
+            self.host_keys &= !(1 << bit);
+        }
+    }
+
+    // Copies the host keys in. Returns true if a key was pressed
+    pub fn poll(&mut self) -> bool {
+        let pressed = self.host_keys & !self.keys;
+        self.keys = self.host_keys;
+        self.update_register();
+        pressed != 0
     }
 
+    pub fn set_dead_zone(&mut self, dead_zone: f32) {
+        self.dead_zone = dead_zone.clamp(0.0, 1.0);
+    }
+
+    // Feeds an analog stick position as d-pad presses
+    pub fn update_stick(&mut self, x: f32, y: f32) -> bool {
+        let dpad = stick_to_dpad(x, y, self.dead_zone);
+        let mut interrupt = false;
+        interrupt |= self.update_button(Button::Up, dpad.up);
+        interrupt |= self.update_button(Button::Down, dpad.down);
+        interrupt |= self.update_button(Button::Left, dpad.left);
+        interrupt |= self.update_button(Button::Right, dpad.right);
+        interrupt
+    }
+
+    // Changes the key right away, without waiting for a poll
     pub fn update_button(&mut self, btn: Button, pressed: bool) -> bool {
+        self.set_host_button(btn, pressed);
         let bit = get_button_bit(btn);
         if pressed {
             let old_value = self.keys;
             // Change the bit for down button to 1
             self.keys |= 1 << bit;
-            // Check for interrupt
-            if check_bit(old_value, bit) {
-                return true;
-            }
-        } else {
-            // Button is up, so change the bit to 0
-            self.keys &= !(1 << bit);
+            self.update_register();
+            // Interrupt only when the button goes down
+            return !check_bit(old_value, bit);
         }
+        // Button is up, so change the bit to 0
+        self.keys &= !(1 << bit);
+        self.update_register();
         false
     }
     fn update_register(&mut self) {
@@ -111,6 +197,18 @@ impl Joypad {
     }
 }
 
+// Axes go from -1.0 to 1.0, positive x is right and positive y is down.
+// Each axis past the dead zone presses that direction
+pub fn stick_to_dpad(x: f32, y: f32, dead_zone: f32) -> Dpad {
+    let dead_zone = dead_zone.clamp(0.0, 1.0);
+    Dpad {
+        up: y < -dead_zone,
+        down: y > dead_zone,
+        left: x < -dead_zone,
+        right: x > dead_zone,
+    }
+}
+
 fn get_button_bit(btn: Button) -> u8 {
     match btn {
         Button::Right => 0,
@@ -124,3 +222,60 @@ fn get_button_bit(btn: Button) -> u8 {
         Button::Start => 7,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stick_to_dpad() {
+        assert_eq!(
+            stick_to_dpad(0.9, 0.0, 0.25),
+            Dpad {
+                right: true,
+                ..Dpad::default()
+            }
+        );
+        assert_eq!(stick_to_dpad(0.2, -0.2, 0.25), Dpad::default());
+        assert_eq!(
+            stick_to_dpad(-0.5, -0.7, 0.25),
+            Dpad {
+                up: true,
+                left: true,
+                ..Dpad::default()
+            }
+        );
+    }
+
+    #[test]
+    fn test_update_stick() {
+        let mut joypad = Joypad::new();
+        joypad.set_dead_zone(0.25);
+        joypad.update_stick(0.9, 0.0);
+        // Select directions
+        joypad.write(0xFF00, 0x20);
+        assert_eq!(joypad.read(0xFF00).unwrap() & 0x0F, 0b1110);
+
+        joypad.update_stick(0.1, 0.0);
+        joypad.write(0xFF00, 0x20);
+        assert_eq!(joypad.read(0xFF00).unwrap() & 0x0F, 0b1111);
+    }
+
+    #[test]
+    fn test_poll_host_keys() {
+        let mut joypad = Joypad::new();
+        // Select buttons
+        joypad.write(0xFF00, 0x10);
+        joypad.set_host_button(Button::A, true);
+        assert_eq!(joypad.read(0xFF00).unwrap() & 0x0F, 0b1111);
+
+        assert!(joypad.poll());
+        assert_eq!(joypad.read(0xFF00).unwrap() & 0x0F, 0b1110);
+        // Still held, no new press
+        assert!(!joypad.poll());
+
+        joypad.set_host_button(Button::A, false);
+        assert!(!joypad.poll());
+        assert_eq!(joypad.read(0xFF00).unwrap() & 0x0F, 0b1111);
+    }
+}
@@ -0,0 +1,168 @@
+#![feature(nll)]
+#![allow(unused)]
+#![feature(exclusive_range_pattern)]
+
+extern crate core;
+extern crate minifb;
+
+use std::time::{Duration, Instant};
+
+pub mod cartridge;
+pub mod console;
+pub mod cpu;
+pub mod crash_report;
+pub mod emulator;
+pub mod gif_recorder;
+pub mod instruction;
+pub mod interconnect;
+pub mod joypad;
+pub mod memory_map;
+pub mod ppu;
+pub mod save_state;
+pub mod scheduler;
+pub mod self_test;
+pub mod sgb;
+pub mod sound_subsystem;
+#[cfg(test)]
+mod testutil;
+pub mod timer;
+pub mod utils;
+pub mod wav_sink;
+
+pub const DEFAULT_FPS: f64 = 60.0;
+pub const CPU_SPEED: u64 = 4194304;
+
+// Clock cycles and wall time of one frame at the given rate
+pub fn frame_timing(fps: f64) -> (u32, Duration) {
+    let clocks = (CPU_SPEED as f64 / fps).round() as u32;
+    (clocks, Duration::from_secs_f64(1.0 / fps))
+}
+
+// Wall clock the frames are paced by, faked in tests
+pub trait Clock {
+    fn now(&self) -> Duration;
+    fn sleep(&mut self, duration: Duration);
+}
+
+pub struct SystemClock {
+    start: Instant,
+}
+
+impl SystemClock {
+    pub fn new() -> Self {
+        SystemClock {
+            start: Instant::now(),
+        }
+    }
+}
+
+impl Clock for SystemClock {
+    fn now(&self) -> Duration {
+        self.start.elapsed()
+    }
+    fn sleep(&mut self, duration: Duration) {
+        std::thread::sleep(duration);
+    }
+}
+
+// Runs a frame's worth of emulated cycles per frame of wall time.
+// Fast-forward runs speed frames' worth instead, so every device
+// still advances by emulated cycles and keeps the same ratios
+pub struct FramePacer<C: Clock> {
+    clock: C,
+    clocks_per_frame: u32,
+    frame_time: Duration,
+    speed: u32,
+}
+
+impl<C: Clock> FramePacer<C> {
+    pub fn new(clock: C, fps: f64) -> Self {
+        let (clocks_per_frame, frame_time) = frame_timing(fps);
+        FramePacer {
+            clock,
+            clocks_per_frame,
+            frame_time,
+            speed: 1,
+        }
+    }
+
+    pub fn set_speed(&mut self, speed: u32) {
+        self.speed = speed.max(1);
+    }
+
+    pub fn speed(&self) -> u32 {
+        self.speed
+    }
+
+    pub fn frame_time(&self) -> Duration {
+        self.frame_time
+    }
+
+    // Gives run the clock cycles to run, then sleeps for the rest of the frame
+    pub fn run_frame<F: FnOnce(u32)>(&mut self, run: F) {
+        let start = self.clock.now();
+        run(self.clocks_per_frame * self.speed);
+        let elapsed = self.clock.now() - start;
+        if let Some(remaining) = self.frame_time.checked_sub(elapsed) {
+            self.clock.sleep(remaining);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cartridge::Cartridge;
+    use crate::emulator::{BootMode, Emulator};
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    // Only moves when slept on
+    struct MockClock {
+        now: Rc<Cell<Duration>>,
+    }
+
+    impl Clock for MockClock {
+        fn now(&self) -> Duration {
+            self.now.get()
+        }
+        fn sleep(&mut self, duration: Duration) {
+            self.now.set(self.now.get() + duration);
+        }
+    }
+
+    #[test]
+    fn test_fast_forward() {
+        // Frames emulated and wall time taken over 20 loop iterations
+        let run = |speed| {
+            let mut rom = vec![0; 0x8000];
+            // JR -2 forever
+            rom[0x100..0x102].copy_from_slice(&[0x18, 0xFE]);
+            let mut emu = Emulator::headless(Cartridge::new(rom), BootMode::SkipDmg);
+            let now = Rc::new(Cell::new(Duration::from_secs(0)));
+            let mut pacer = FramePacer::new(MockClock { now: now.clone() }, DEFAULT_FPS);
+            pacer.set_speed(speed);
+            for _ in 0..20 {
+                pacer.run_frame(|cycles| {
+                    emu.cpu.run_cycles(cycles);
+                });
+            }
+            (emu.cpu.interconnect.ppu.frame_count() as i64, now.get())
+        };
+        let (normal_frames, normal_time) = run(1);
+        let (fast_frames, fast_time) = run(4);
+        assert!((fast_frames - 4 * normal_frames).abs() <= 4);
+        assert_eq!(fast_time, normal_time);
+    }
+
+    #[test]
+    fn test_frame_timing() {
+        let (clocks, frame_time) = frame_timing(59.73);
+        assert_eq!(clocks, 70221);
+        assert_eq!(frame_time.as_micros(), 16742);
+
+        let (clocks, frame_time) = frame_timing(60.0);
+        assert_eq!(clocks, 69905);
+        assert_eq!(frame_time.as_micros(), 16666);
+    }
+}
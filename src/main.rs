@@ -1,11 +1,13 @@
-#![feature(nll)]
-#![allow(unused)]
-#![feature(exclusive_range_pattern)]
-
-extern crate core;
-extern crate minifb;
-
-use minifb::{Key, Window, WindowOptions};
+use minifb::{Key, Window};
+use rustboy::interconnect::BOOT_ROM_SIZE;
+use rustboy::joypad::InputPolling;
+use rustboy::ppu::Palette;
+use rustboy::sound_subsystem::SAMPLE_RATE;
+use rustboy::wav_sink::WavSink;
+use rustboy::{cartridge, console, crash_report, emulator, gif_recorder, self_test};
+use rustboy::{frame_timing, FramePacer, SystemClock, DEFAULT_FPS};
+use std::collections::HashMap;
+use std::fmt;
 use std::fs::File;
 use std::io;
 use std::io::prelude::*;
@@ -13,74 +15,357 @@ use std::io::BufReader;
 use std::path::Path;
 use std::sync::mpsc::channel;
 use std::thread;
-use std::thread::sleep;
-use std::time::{Duration, Instant};
-
-mod cartridge;
-mod console;
-mod cpu;
-mod instruction;
-mod interconnect;
-mod joypad;
-mod memory_map;
-mod ppu;
-mod sound_subsystem;
-mod timer;
-mod utils;
-
-const WIDTH: usize = 256;
-const HEIGHT: usize = 256;
-
-const FPS: u64 = 60;
-pub const CPU_SPEED: u64 = 4194304;
-const CLOCKS_PER_FRAME: u64 = CPU_SPEED / FPS;
-const MS_PER_FRAME: u64 = ((1 as f32 / FPS as f32) * 1000.0) as u64;
+
+// How many times faster than real time while Tab is held
+const FAST_FORWARD_SPEED: u32 = 4;
+// Smallest rom there is, two 16Kb banks
+const MIN_ROM_SIZE: usize = 0x8000;
+
+#[derive(Debug)]
+enum RomError {
+    NotFound(String),
+    TooSmall(usize),
+    UnsupportedMbc(u8),
+    Io(io::Error),
+}
+
+impl RomError {
+    // Process exit code, after the ones used by the test rom mode
+    fn exit_code(&self) -> i32 {
+        match self {
+            RomError::NotFound(_) => 3,
+            RomError::TooSmall(_) => 4,
+            RomError::UnsupportedMbc(_) => 5,
+            RomError::Io(_) => 6,
+        }
+    }
+}
+
+impl fmt::Display for RomError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RomError::NotFound(path) => write!(f, "Rom not found: {}", path),
+            RomError::TooSmall(size) => write!(
+                f,
+                "Rom is too small: {} bytes, at least {} expected",
+                size, MIN_ROM_SIZE
+            ),
+            RomError::UnsupportedMbc(byte) => write!(
+                f,
+                "Unsupported cartridge type 0x{:02x} ({})",
+                byte,
+                cartridge::cartridge_type_name(*byte)
+            ),
+            RomError::Io(e) => write!(f, "Couldn't read rom: {}", e),
+        }
+    }
+}
 
 fn main() -> io::Result<()> {
-    let boot = read_file("resources/boot/DMG_ROM.bin")?;
+    let args: Vec<String> = std::env::args().collect();
+    let break_on_illegal = args.iter().any(|a| a == "--break-on-illegal");
 
-    let rom = cartridge::Cartridge::new(read_file(
-        "resources/roms/cpu_instrs/individual/02-interrupts.gb",
-    )?);
+    if args.iter().any(|a| a == "--self-test") {
+        if self_test::run() {
+            println!("Self-test passed");
+            return Ok(());
+        }
+        eprintln!("Self-test failed");
+        std::process::exit(1);
+    }
 
-    let rom = cartridge::Cartridge::new(read_file("resources/roms/Tetris-USA.gb")?);
+    let rom_path = arg_value(&args, "--rom").unwrap_or("resources/roms/Tetris-USA.gb");
+    let mut rom = match load_rom(rom_path) {
+        Ok(rom) => rom,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(e.exit_code());
+        }
+    };
+    if let Some(patch) = arg_value(&args, "--patch") {
+        rom.apply_ips(&read_file(patch)?)?;
+    }
+    let save_path = cartridge::save_path(rom_path);
+    if rom.has_battery() {
+        load_save(&mut rom, &save_path)?;
+    }
 
-    let ic = interconnect::Interconnect::new(boot, rom);
-    let mut cpu = cpu::Cpu::new(ic);
+    if args.iter().any(|a| a == "--info") {
+        println!("Cartridge type: {}", rom.type_name());
+        println!("CGB support: {}", rom.supports_cgb());
+        return Ok(());
+    }
+
+    let force_dmg = args.iter().any(|a| a == "--force-dmg");
+    let boot_mode = match load_boot_rom("resources/boot/DMG_ROM.bin")? {
+        Some(boot) => emulator::BootMode::Real(boot),
+        None if rom.supports_cgb() && !force_dmg => emulator::BootMode::SkipCgb,
+        None => emulator::BootMode::SkipDmg,
+    };
+    let mut emu = emulator::Emulator::with_boot_mode(rom, boot_mode);
+    emu.cpu.interconnect.set_force_dmg(force_dmg);
+    emu.cpu
+        .interconnect
+        .set_verbose_boot(args.iter().any(|a| a == "--verbose-boot"));
+    emu.cpu
+        .interconnect
+        .set_log_echo_accesses(args.iter().any(|a| a == "--log-echo"));
+    emu.cpu
+        .interconnect
+        .set_log_sgb(args.iter().any(|a| a == "--log-sgb"));
+    if args.iter().any(|a| a == "--poll-scanline") {
+        emu.cpu
+            .interconnect
+            .set_input_polling(InputPolling::Scanline);
+    }
+    emu.cpu
+        .interconnect
+        .ppu
+        .set_debug_sprite_overflow(args.iter().any(|a| a == "--debug-sprite-overflow"));
+    emu.cpu
+        .interconnect
+        .ppu
+        .set_strict_access(args.iter().any(|a| a == "--strict-access"));
+
+    // Audio only goes to a file, there's no audio device output
+    if let Some(path) = arg_value(&args, "--audio-wav") {
+        emu.cpu
+            .interconnect
+            .set_audio_sink(Box::new(WavSink::create(path, SAMPLE_RATE)?), SAMPLE_RATE);
+    }
+
+    if let Some(name) = arg_value(&args, "--palette") {
+        let palette = Palette::from_name(name).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Unknown palette: {}, use grayscale or dmg-green", name),
+            )
+        })?;
+        emu.cpu.interconnect.ppu.set_palette(palette);
+    }
 
     let (tx, rx) = channel::<console::CpuText>();
 
-    cpu.set_console_tx(tx);
-    cpu.set_print_instruction(false);
-    let fps_cap = true;
+    emu.cpu.set_console_tx(tx);
+    emu.cpu.set_print_instruction(false);
+    emu.cpu.set_break_on_illegal(break_on_illegal);
+    if let Some(scale) = arg_value(&args, "--clock-scale") {
+        emu.cpu
+            .set_clock_scale(parse_arg(scale, "--clock-scale")?)?;
+    }
+    let profile = args.iter().any(|a| a == "--profile");
+    let crash_report = args.iter().any(|a| a == "--crash-report");
+    if crash_report {
+        crash_report::install_hook();
+        emu.cpu.set_trace_len(crash_report::TRACE_LEN);
+    }
+    emu.cpu.set_profiling(profile);
+    let fps = match arg_value(&args, "--fps") {
+        Some(fps) => fps
+            .parse()
+            .ok()
+            .filter(|&fps: &f64| fps > 0.0)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Invalid --fps value"))?,
+        None => DEFAULT_FPS,
+    };
+    let (clocks_per_frame, frame_time) = frame_timing(fps);
+
+    // Batch mode for test roms, exits with the result
+    let max_cycles = match (
+        arg_value(&args, "--max-cycles"),
+        arg_value(&args, "--max-frames"),
+    ) {
+        (Some(cycles), _) => Some(parse_arg(cycles, "--max-cycles")?),
+        (None, Some(frames)) => {
+            Some(parse_arg::<u64>(frames, "--max-frames")? * clocks_per_frame as u64)
+        }
+        (None, None) => None,
+    };
+    if let Some(max_cycles) = max_cycles {
+        let outcome = emu.run_test(max_cycles);
+        println!("{:?}", outcome);
+        std::process::exit(match outcome {
+            emulator::TestOutcome::Passed => 0,
+            emulator::TestOutcome::Failed => 1,
+            emulator::TestOutcome::TimedOut => 2,
+        });
+    }
 
+    let mut pacer = FramePacer::new(SystemClock::new(), fps);
     let mut console = console::Console::new(rx);
     thread::spawn(move || console.start());
 
-    let mut start_time = Instant::now();
-    let mut clocks = 0;
+    // G starts and stops recording, F12 saves a screenshot
+    let mut recorder = gif_recorder::GifRecorder::new(emu.cpu.interconnect.ppu.palette());
+    let mut last_frame = emu.cpu.interconnect.ppu.frame_count();
+    let (mut screenshot_key, mut record_key) = (false, false);
 
-    while cpu.interconnect.ppu.main_window.is_open()
-        && !cpu.interconnect.ppu.main_window.is_key_down(Key::Escape)
-    {
-        if fps_cap && clocks > CLOCKS_PER_FRAME {
-            let elapsed = start_time.elapsed();
-            if let Some(dur) = Duration::from_millis(MS_PER_FRAME).checked_sub(elapsed) {
-                thread::sleep(dur);
+    while window(&emu).is_open() && !window(&emu).is_key_down(Key::Escape) {
+        if emu.cpu.is_paused() {
+            // Keep the window responsive while stopped
+            emu.cpu.interconnect.ppu.update_window();
+            thread::sleep(frame_time);
+            continue;
+        }
+        // Fast-forward, only showing some of the frames
+        let speed = if window(&emu).is_key_down(Key::Tab) {
+            FAST_FORWARD_SPEED
+        } else {
+            1
+        };
+        if speed != pacer.speed() {
+            pacer.set_speed(speed);
+            emu.cpu.interconnect.ppu.set_present_interval(speed as u64);
+        }
+        let cpu = &mut emu.cpu;
+        pacer.run_frame(|cycles| {
+            if crash_report {
+                crash_report::catch_crash(cpu, "crash-report.txt", |cpu| cpu.run_cycles(cycles));
+            } else {
+                cpu.run_cycles(cycles);
             }
-            start_time = Instant::now();
-            clocks = 0;
+        });
+
+        let ppu = &emu.cpu.interconnect.ppu;
+        if ppu.frame_count() != last_frame {
+            last_frame = ppu.frame_count();
+            recorder.capture(ppu.viewport());
         }
-        cpu.step();
-        cpu.interconnect.update();
-        if fps_cap {
-            clocks += 1;
+        if key_pressed(window(&emu), Key::F12, &mut screenshot_key) {
+            let path = format!("rustboy-{}.png", last_frame);
+            match ppu.save_screenshot(&path) {
+                Ok(()) => println!("Saved screenshot to {}", path),
+                Err(e) => println!("Couldn't save screenshot: {}", e),
+            }
+        }
+        if key_pressed(window(&emu), Key::G, &mut record_key) {
+            if recorder.is_recording() {
+                recorder.stop();
+                let path = format!("rustboy-{}.gif", last_frame);
+                match recorder.save(&path) {
+                    Ok(()) => println!("Saved recording to {}", path),
+                    Err(e) => println!("Couldn't save recording: {}", e),
+                }
+            } else {
+                println!("Recording");
+                recorder.start();
+            }
         }
     }
 
+    let cartridge = emu.cpu.interconnect.cartridge();
+    if cartridge.has_battery() {
+        std::fs::write(&save_path, cartridge.ram_snapshot())?;
+    }
+
+    if profile {
+        print_profile("", emu.cpu.opcode_profile());
+        print_profile("CB ", emu.cpu.cb_opcode_profile());
+    }
+
     Ok(())
 }
 
+// The emulator is created with a window in main
+fn window(emu: &emulator::Emulator) -> &Window {
+    emu.cpu.interconnect.ppu.main_window.as_ref().unwrap()
+}
+
+// True when the key went down since the last check. The window's own
+// pressed state can be cleared by the per scanline input polling
+fn key_pressed(window: &Window, key: Key, was_down: &mut bool) -> bool {
+    let down = window.is_key_down(key);
+    let pressed = down && !*was_down;
+    *was_down = down;
+    pressed
+}
+
+// Prints the opcodes that used the most cycles first
+fn print_profile(prefix: &str, profile: HashMap<u8, (u64, u64)>) {
+    let mut entries: Vec<_> = profile.into_iter().collect();
+    entries.sort_by_key(|&(_, (_, cycles))| std::cmp::Reverse(cycles));
+    for (opcode, (count, cycles)) in entries {
+        println!(
+            "{}0x{:02x}: {} times, {} cycles",
+            prefix, opcode, count, cycles
+        );
+    }
+}
+
+fn load_rom<P: AsRef<Path>>(path: P) -> Result<cartridge::Cartridge, RomError> {
+    let data = match read_file(&path) {
+        Ok(data) => data,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+            return Err(RomError::NotFound(path.as_ref().display().to_string()))
+        }
+        Err(e) => return Err(RomError::Io(e)),
+    };
+    if data.len() < MIN_ROM_SIZE {
+        return Err(RomError::TooSmall(data.len()));
+    }
+    let rom = cartridge::Cartridge::new(data);
+    if !rom.is_supported() {
+        return Err(RomError::UnsupportedMbc(rom.cartridge_type()));
+    }
+    Ok(rom)
+}
+
+// Boot rom to run, or None to start from the post boot state when it's missing
+fn load_boot_rom<P: AsRef<Path>>(path: P) -> io::Result<Option<Vec<u8>>> {
+    match read_file(&path) {
+        Ok(ref boot) if boot.len() > BOOT_ROM_SIZE => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "Boot rom {} is too big: {} bytes, at most {}",
+                path.as_ref().display(),
+                boot.len(),
+                BOOT_ROM_SIZE
+            ),
+        )),
+        Ok(boot) => Ok(Some(boot)),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+            println!(
+                "No boot rom found at {}, skipping boot",
+                path.as_ref().display()
+            );
+            Ok(None)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+// Restores the cartridge ram from a battery save, if there is one
+fn load_save(rom: &mut cartridge::Cartridge, path: &Path) -> io::Result<()> {
+    let ram = match read_file(path) {
+        Ok(ram) => ram,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    rom.restore_ram(&ram).map_err(|e| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Save file {} has the wrong size: {}", path.display(), e),
+        )
+    })
+}
+
+fn parse_arg<T: std::str::FromStr>(value: &str, name: &str) -> io::Result<T> {
+    value.parse().map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Invalid {} value: {}", name, value),
+        )
+    })
+}
+
+// Value following a command line flag, e.g. "--patch file.ips"
+fn arg_value<'a>(args: &'a [String], name: &str) -> Option<&'a str> {
+    args.iter()
+        .position(|a| a == name)
+        .and_then(|i| args.get(i + 1))
+        .map(|s| s.as_str())
+}
+
 fn read_file<P: AsRef<Path>>(path: P) -> io::Result<Vec<u8>> {
     let mut f = File::open(path)?;
     let mut buf_reader = BufReader::new(f);
@@ -88,3 +373,51 @@ fn read_file<P: AsRef<Path>>(path: P) -> io::Result<Vec<u8>> {
     buf_reader.read_to_end(&mut rom)?;
     Ok(rom)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_missing_boot_rom_skips_boot() {
+        assert!(load_boot_rom("resources/boot/missing.bin")
+            .unwrap()
+            .is_none());
+
+        let path = std::env::temp_dir().join("rustboy_test_boot_rom.bin");
+        std::fs::write(&path, [0x31, 0xFE, 0xFF]).unwrap();
+        let boot = load_boot_rom(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(boot, Some(vec![0x31, 0xFE, 0xFF]));
+
+        std::fs::write(&path, vec![0; BOOT_ROM_SIZE + 1]).unwrap();
+        let err = load_boot_rom(&path).unwrap_err();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_load_rom_errors() {
+        match load_rom("resources/roms/missing.gb") {
+            Err(RomError::NotFound(path)) => assert_eq!(path, "resources/roms/missing.gb"),
+            _ => panic!("Expected NotFound"),
+        }
+
+        let path = std::env::temp_dir().join("rustboy_test_load_rom.gb");
+        std::fs::write(&path, vec![0; 0x4000]).unwrap();
+        let small = load_rom(&path);
+        let mut rom = vec![0; 0x8000];
+        // MBC5
+        rom[0x147] = 0x19;
+        std::fs::write(&path, &rom).unwrap();
+        let mbc5 = load_rom(&path);
+        rom[0x147] = 0x01;
+        std::fs::write(&path, &rom).unwrap();
+        let mbc1 = load_rom(&path);
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(matches!(small, Err(RomError::TooSmall(0x4000))));
+        assert!(matches!(mbc5, Err(RomError::UnsupportedMbc(0x19))));
+        assert!(mbc1.is_ok());
+    }
+}
@@ -56,6 +56,13 @@ pub const INTERNAL_RAM2_LENGTH: u16 = INTERNAL_RAM2_END - INTERNAL_RAM2_START;
 
 pub const INTERRUPT_REGISTER: u16 = 0xFFFF;
 
+// CGB only bank select registers
+pub const VRAM_BANK_REGISTER: u16 = 0xFF4F;
+pub const WRAM_BANK_REGISTER: u16 = 0xFF70;
+// CGB palette index and data registers, BG at 0xFF68 and OBJ at 0xFF6A
+pub const CGB_PALETTE_START: u16 = 0xFF68;
+pub const CGB_PALETTE_END: u16 = 0xFF6C;
+
 /*
 2.5.4. Reserved Memory Locations
 0000 Restart $00 Address
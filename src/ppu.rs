@@ -1,17 +1,34 @@
 use super::interconnect::Interconnect;
 use super::memory_map;
+use crate::emulator::EmuError;
 use crate::memory_map::*;
-use crate::utils::check_bit;
+use crate::save_state::{StateReader, StateWriter};
+use crate::utils::{check_bit, fnv1a};
 use enum_primitive_derive::*;
 use minifb::Window;
 use minifb::{Key, Scale, WindowOptions};
 use num_traits::{FromPrimitive, ToPrimitive};
-
-const VIEWPORT_WIDTH: usize = 160;
-const VIEWPORT_HEIGHT: usize = 144;
-
-const WIDTH: usize = 256;
-const HEIGHT: usize = 256;
+use std::collections::VecDeque;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+pub const VIEWPORT_WIDTH: usize = 160;
+pub const VIEWPORT_HEIGHT: usize = 144;
+
+// Mode lengths in dots, 4 dots per machine cycle.
+// A line is 456 dots, vblank is 10 lines
+const OAM_SEARCH_DOTS: i32 = 80;
+const PIXEL_TRANSFER_DOTS: i32 = 172;
+const HBLANK_DOTS: i32 = 204;
+const LINE_DOTS: i32 = 456;
+const FRAME_DOTS: i32 = LINE_DOTS * 154;
+
+// Drawn on the left edge of lines with too many sprites, when debugging
+const OVERFLOW_MARKER_COLOR: u32 = 0xff0000;
+const OVERFLOW_MARKER_WIDTH: usize = 2;
+// 8 palettes of 4 colors, 2 bytes per color
+const CGB_PALETTE_RAM_LENGTH: usize = 64;
 // 20x18 tiles
 
 /*
@@ -71,6 +88,35 @@ Vert Sync: 59.73 Hz (61.17 Hz for SGB)
 0x9900 -> 0xA000 window map
 */
 
+// Screen colors for the 4 shades, lightest first
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Palette {
+    pub colors: [u32; 4],
+}
+
+impl Palette {
+    pub const GRAYSCALE: Palette = Palette {
+        colors: [0xffffff, 0x838484, 0x505151, 0],
+    };
+    // Greens of the original DMG screen
+    pub const DMG_GREEN: Palette = Palette {
+        colors: [0x9bbc0f, 0x8bac0f, 0x306230, 0x0f380f],
+    };
+
+    pub fn from_name(name: &str) -> Option<Palette> {
+        match name {
+            "grayscale" => Some(Palette::GRAYSCALE),
+            "dmg-green" => Some(Palette::DMG_GREEN),
+            _ => None,
+        }
+    }
+
+    // Screen color for a color number after BGP or OBP
+    pub fn color(&self, nr: u8) -> u32 {
+        self.colors[nr as usize & 0b11]
+    }
+}
+
 #[derive(Debug, PartialEq, Primitive, Clone, Copy)]
 pub enum Color {
     White = 0b00,
@@ -79,12 +125,93 @@ pub enum Color {
     Black = 0b11,
 }
 
-#[derive(Debug, PartialEq)]
-enum State {
-    OAMSearch,
-    PixelTransfer,
-    HBlank,
-    VBlank,
+// The value is the mode in the 2 low bits of STAT
+#[derive(Debug, PartialEq, Primitive, Clone, Copy)]
+pub enum PpuMode {
+    HBlank = 0,
+    VBlank = 1,
+    OamSearch = 2,
+    PixelTransfer = 3,
+}
+
+impl PpuMode {
+    pub fn from_stat(stat: u8) -> PpuMode {
+        PpuMode::from_u8(stat & 0b11).unwrap()
+    }
+
+    pub fn to_stat(self) -> u8 {
+        self as u8
+    }
+}
+
+// How pixel transfer draws a line
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum RendererMode {
+    // Whole line at once from vram when pixel transfer ends. Fast
+    Scanline,
+    // Fetcher and pixel FIFOs dot by dot like the hardware. Slow
+    Fifo,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum FetcherState {
+    GetTile,
+    GetDataLow,
+    GetDataHigh,
+    Push,
+}
+
+// The line being drawn by the FIFO renderer. Advanced one dot at a time
+// during pixel transfer, so register writes in the middle of the line
+// show up from the next fetched tile or drawn pixel
+struct PixelFifo {
+    bg: VecDeque<u8>,
+    // Color number, palette and priority of each sprite pixel
+    sprite: VecDeque<(u8, u8, bool)>,
+    // Found by the OAM search, fetched in x order as the line is drawn
+    sprites: Vec<Sprite>,
+    sprite_fetched: Vec<bool>,
+    fetcher_state: FetcherState,
+    // Tiles fetched on this line, or since the window started
+    fetcher_column: u8,
+    tile_nr: u8,
+    tile_line: u16,
+    tile_row: [u8; 8],
+    // Pixels scrolled off the left side are fetched and thrown away
+    discard: u8,
+    // Fetching the window instead of the background
+    window: bool,
+    dot: u32,
+    // Next screen column, VIEWPORT_WIDTH once the line is done
+    x: usize,
+}
+
+impl PixelFifo {
+    fn new() -> Self {
+        PixelFifo {
+            bg: VecDeque::with_capacity(8),
+            sprite: VecDeque::with_capacity(8),
+            sprites: vec![],
+            sprite_fetched: vec![],
+            fetcher_state: FetcherState::GetTile,
+            fetcher_column: 0,
+            tile_nr: 0,
+            tile_line: 0,
+            tile_row: [0; 8],
+            discard: 0,
+            window: false,
+            dot: 0,
+            x: VIEWPORT_WIDTH,
+        }
+    }
+
+    // Starts fetching again from the first tile
+    fn restart_fetcher(&mut self) {
+        self.bg.clear();
+        self.fetcher_state = FetcherState::GetTile;
+        self.fetcher_column = 0;
+        self.dot = 0;
+    }
 }
 
 #[allow(non_snake_case)]
@@ -101,23 +228,79 @@ pub struct Ppu {
     wy: u8,          // FF4A
     wx: u8,          // FF4B
 
-    pub main_window: Window,
+    // None when headless, frames are still drawn to viewport_buffer
+    pub main_window: Option<Window>,
 
     sprite_memory: Box<[u8]>,
     vram: Box<[u8]>,
 
-    buffer: Vec<u8>,
-    viewport_buffer: Vec<u32>,
+    // CGB only. The second vram bank is empty on DMG.
+    // Stored but not used for drawing yet
+    cgb_mode: bool,
+    vram_bank: u8, // FF4F
+    vram_bank1: Box<[u8]>,
+    bg_palette_index: u8,  // FF68
+    obj_palette_index: u8, // FF6A
+    bg_palette_ram: [u8; CGB_PALETTE_RAM_LENGTH],
+    obj_palette_ram: [u8; CGB_PALETTE_RAM_LENGTH],
 
-    cycles: i32,
-    state: State,
+    viewport_buffer: Vec<u32>,
+    palette: Palette,
+    // Background or window color number of each pixel on the current line,
+    // before the palette. Sprites behind the background only show over 0
+    line_bg: [u8; VIEWPORT_WIDTH],
+    // viewport_buffer changed since the last present
+    viewport_dirty: bool,
+    // The last presented frame differed from the one before it
+    frame_dirty: bool,
+    // The last update entered vblank, so viewport_buffer has a whole frame
+    frame_ready: bool,
+    // Frames presented so far, counted at vblank
+    frame_count: u64,
+    // Only every nth frame is shown in the window, for fast-forwarding
+    present_interval: u64,
+
+    // Dots left in the current mode, or the current line in vblank
+    dots: i32,
+    mode: PpuMode,
+    renderer: RendererMode,
+    fifo: PixelFifo,
+    // Marks lines with more sprites than get drawn
+    debug_sprite_overflow: bool,
+    // Blocks cpu OAM and vram accesses while the ppu is using them
+    strict_access: bool,
+    // Window row to draw next. Only advances on lines the window is drawn on
+    window_line: u8,
+
+    // All the enabled STAT interrupt conditions ORed together.
+    // The interrupt is only requested when this goes from low to high
+    stat_line: bool,
+    stat_interrupt: bool,
+
+    // Called with the old mode, new mode and ly on every mode change
+    mode_change_callback: Option<Box<dyn FnMut(u8, u8, u8)>>,
 }
 
 impl Ppu {
     pub fn new() -> Self {
+        Ppu::with_window(Some(create_window(
+            VIEWPORT_WIDTH,
+            VIEWPORT_HEIGHT,
+            "Rustboy",
+            Scale::X4,
+        )))
+    }
+
+    // Doesn't open a window, for tests and running without a display
+    pub fn new_headless() -> Self {
+        Ppu::with_window(None)
+    }
+
+    fn with_window(main_window: Option<Window>) -> Self {
         Ppu {
             LCD_control: 0x91,
-            LCDC_status: 0,
+            // Starts in OAM search
+            LCDC_status: 0b10,
             ly: 0,
             lyc: 0,
             scy: 0,
@@ -131,80 +314,386 @@ impl Ppu {
             sprite_memory: vec![0; SPRITE_MEM_LENGTH as usize].into_boxed_slice(),
             vram: vec![0; VRAM_LENGTH as usize].into_boxed_slice(),
 
-            main_window: create_window(VIEWPORT_WIDTH, VIEWPORT_HEIGHT, "Rustboy", Scale::X4),
+            cgb_mode: false,
+            vram_bank: 0,
+            vram_bank1: Box::new([]),
+            bg_palette_index: 0,
+            obj_palette_index: 0,
+            bg_palette_ram: [0; CGB_PALETTE_RAM_LENGTH],
+            obj_palette_ram: [0; CGB_PALETTE_RAM_LENGTH],
+
+            main_window,
 
-            buffer: vec![0; WIDTH * HEIGHT],
             viewport_buffer: vec![0; VIEWPORT_WIDTH * VIEWPORT_HEIGHT],
-            cycles: 0,
-            state: State::OAMSearch,
+            palette: Palette::GRAYSCALE,
+            line_bg: [0; VIEWPORT_WIDTH],
+            viewport_dirty: false,
+            frame_dirty: false,
+            frame_ready: false,
+            frame_count: 0,
+            present_interval: 1,
+            dots: OAM_SEARCH_DOTS,
+            mode: PpuMode::OamSearch,
+            renderer: RendererMode::Scanline,
+            fifo: PixelFifo::new(),
+            debug_sprite_overflow: false,
+            strict_access: false,
+            window_line: 0,
+            stat_line: false,
+            stat_interrupt: false,
+            mode_change_callback: None,
+        }
+    }
+
+    pub fn set_renderer(&mut self, renderer: RendererMode) {
+        self.renderer = renderer;
+        if renderer == RendererMode::Fifo && self.mode == PpuMode::PixelTransfer {
+            self.fifo_start_line();
         }
     }
 
+    pub fn set_present_interval(&mut self, interval: u64) {
+        self.present_interval = interval.max(1);
+    }
+
+    // Allocates the second vram bank and enables the CGB registers
+    pub fn set_cgb_mode(&mut self, b: bool) {
+        self.cgb_mode = b;
+        self.vram_bank = 0;
+        self.vram_bank1 = if b {
+            vec![0; VRAM_LENGTH as usize].into_boxed_slice()
+        } else {
+            Box::new([])
+        };
+    }
+
+    pub fn cgb_mode(&self) -> bool {
+        self.cgb_mode
+    }
+
+    // Used from the next drawn line on
+    pub fn set_palette(&mut self, palette: Palette) {
+        self.palette = palette;
+    }
+
+    pub fn palette(&self) -> Palette {
+        self.palette
+    }
+
+    pub fn set_strict_access(&mut self, b: bool) {
+        self.strict_access = b;
+    }
+
+    pub fn set_debug_sprite_overflow(&mut self, b: bool) {
+        self.debug_sprite_overflow = b;
+    }
+
+    // One machine cycle.
     // bool signifies whether a vblank interrupt or not
     pub fn update(&mut self) -> bool {
-        // If on cooldown, jump out
-        if self.cycles > 0 {
-            self.cycles -= 1;
-            return false;
+        let mut vblank = false;
+        for _ in 0..4 {
+            if self.step_dot() == Some(PpuMode::VBlank) {
+                vblank = true;
+            }
         }
-        match self.state {
-            State::OAMSearch => {
-                self.cycles = 20;
-                // Change status
-                self.state = State::PixelTransfer;
-                self.LCDC_status |= 0b11;
+        self.frame_ready = vblank;
+        vblank
+    }
+
+    // Advances a single dot. Returns the mode entered on this dot if it changed
+    pub fn step_dot(&mut self) -> Option<PpuMode> {
+        // Stopped at the start of line 0 while the display is off.
+        // A blank frame is still shown every frame's worth of dots
+        if !self.lcd_display_enabled() {
+            self.dots += 1;
+            if self.dots == FRAME_DOTS {
+                self.dots = 0;
+                self.present_blank();
             }
-            State::PixelTransfer => {
-                self.cycles = 43;
+            return None;
+        }
+        let old_mode = self.mode;
+        self.advance_dot();
+        self.update_coincidence_flag();
+        self.update_stat_line();
+
+        if self.mode == old_mode {
+            return None;
+        }
+        if let Some(ref mut callback) = self.mode_change_callback {
+            callback(old_mode.to_stat(), self.mode.to_stat(), self.ly);
+        }
+        Some(self.mode)
+    }
+
+    pub fn set_mode_change_callback(&mut self, callback: Box<dyn FnMut(u8, u8, u8)>) {
+        self.mode_change_callback = Some(callback);
+    }
+
+    fn advance_dot(&mut self) {
+        if self.mode == PpuMode::PixelTransfer && self.renderer == RendererMode::Fifo {
+            self.fifo_step();
+        }
+        // Still in the same mode
+        self.dots -= 1;
+        if self.dots > 0 {
+            return;
+        }
+        match self.mode {
+            PpuMode::OamSearch => {
+                self.dots = PIXEL_TRANSFER_DOTS;
+                self.set_mode(PpuMode::PixelTransfer);
+                if self.renderer == RendererMode::Fifo {
+                    self.fifo_start_line();
+                }
+            }
+            PpuMode::PixelTransfer => {
+                self.dots = HBLANK_DOTS;
 
                 self.pixel_transfer();
-                // Change status
-                self.state = State::HBlank;
-                self.LCDC_status &= !0b11;
+                self.set_mode(PpuMode::HBlank);
             }
-            State::HBlank => {
-                self.cycles = 51;
+            PpuMode::HBlank => {
                 self.ly += 1;
-                self.state = if self.ly == 144 {
-                    self.LCDC_status &= !0b11;
-                    self.LCDC_status |= 0b01;
-                    State::VBlank
+                if self.ly == 144 {
+                    // The frame is done, vblank interrupt as soon as vblank starts
+                    self.dots = LINE_DOTS;
+                    self.set_mode(PpuMode::VBlank);
+                    self.present();
                 } else {
-                    self.LCDC_status &= !0b11;
-                    self.LCDC_status |= 0b10;
-                    State::OAMSearch
-                };
+                    self.dots = OAM_SEARCH_DOTS;
+                    self.set_mode(PpuMode::OamSearch);
+                }
             }
-            State::VBlank => {
+            PpuMode::VBlank => {
                 self.ly += 1;
-                self.cycles = 114;
+                self.dots = LINE_DOTS;
 
                 if self.ly == 154 {
                     self.ly = 0;
-
-                    self.LCDC_status &= !0b11;
-                    self.LCDC_status |= 0b10;
-                    self.state = State::OAMSearch;
-                }
-                if self.ly == 145 {
-                    self.main_window
-                        .update_with_buffer(&*self.viewport_buffer)
-                        .unwrap();
-                    return true;
+                    self.window_line = 0;
+                    self.dots = OAM_SEARCH_DOTS;
+                    self.set_mode(PpuMode::OamSearch);
                 }
             }
         }
-        return false;
+    }
+
+    // Changes the state and the mode shown in STAT
+    fn set_mode(&mut self, mode: PpuMode) {
+        self.mode = mode;
+        self.LCDC_status = (self.LCDC_status & !0b11) | mode.to_stat();
+    }
+
+    pub fn mode(&self) -> PpuMode {
+        self.mode
+    }
+
+    pub fn ly(&self) -> u8 {
+        self.ly
+    }
+
+    // STAT bit 2, set while ly matches lyc
+    fn update_coincidence_flag(&mut self) {
+        if self.ly == self.lyc {
+            self.LCDC_status |= 1 << 2;
+        } else {
+            self.LCDC_status &= !(1 << 2);
+        }
+    }
+
+    // All the enabled conditions share one line, so a condition coming on
+    // while another one is already on doesn't request another interrupt
+    fn update_stat_line(&mut self) {
+        let line = match self.mode {
+            PpuMode::HBlank => self.mode_0_hblank_interrupt(),
+            PpuMode::VBlank => self.mode_1_vblank_interrupt(),
+            PpuMode::OamSearch => self.mode_2_oam_interrupt(),
+            PpuMode::PixelTransfer => false,
+        } || (self.lyc_ly_interrupt() && self.lyc_ly_flag());
+
+        if line && !self.stat_line {
+            self.stat_interrupt = true;
+        }
+        self.stat_line = line;
+    }
+
+    // Jumps straight to a line for tests, updating the coincidence flag
+    // and STAT interrupt the same way as reaching it normally
+    #[cfg(test)]
+    pub fn set_line(&mut self, ly: u8) {
+        self.ly = ly;
+        self.update_coincidence_flag();
+        self.update_stat_line();
+    }
+
+    // Returns true once for every STAT interrupt request
+    pub fn take_stat_interrupt(&mut self) -> bool {
+        let interrupt = self.stat_interrupt;
+        self.stat_interrupt = false;
+        interrupt
+    }
+
+    // Handles the window events without showing a new frame
+    pub fn update_window(&mut self) {
+        if let Some(ref mut window) = self.main_window {
+            window.update();
+        }
+    }
+
+    fn present_blank(&mut self) {
+        let blank = self.palette.color(0);
+        if self.viewport_buffer.iter().any(|&pixel| pixel != blank) {
+            self.viewport_buffer
+                .iter_mut()
+                .for_each(|pixel| *pixel = blank);
+            self.viewport_dirty = true;
+        }
+        self.present();
+    }
+
+    fn present(&mut self) {
+        if self.frame_count % self.present_interval != 0 {
+            // Skipped, the changes are shown with the next frame that isn't
+            self.update_window();
+            self.frame_count += 1;
+            return;
+        }
+        // Skip uploading the buffer if nothing changed, just handle window events
+        match self.main_window {
+            Some(ref mut window) if self.viewport_dirty => {
+                window.update_with_buffer(&*self.viewport_buffer).unwrap();
+            }
+            _ => self.update_window(),
+        }
+        self.frame_dirty = self.viewport_dirty;
+        self.viewport_dirty = false;
+        self.frame_count += 1;
+    }
+
+    pub fn frame_count(&self) -> u64 {
+        self.frame_count
+    }
+
+    pub fn frame_dirty(&self) -> bool {
+        self.frame_dirty
+    }
+
+    pub fn viewport(&self) -> &[u32] {
+        &self.viewport_buffer
+    }
+
+    pub fn frame_ready(&self) -> bool {
+        self.frame_ready
+    }
+
+    // The viewport as 4 bytes per pixel, red, green, blue and alpha
+    pub fn framebuffer_rgba(&self) -> Vec<u8> {
+        let mut rgba = Vec::with_capacity(self.viewport_buffer.len() * 4);
+        for &pixel in self.viewport_buffer.iter() {
+            let [_, r, g, b] = pixel.to_be_bytes();
+            rgba.extend_from_slice(&[r, g, b, 0xFF]);
+        }
+        rgba
+    }
+
+    // FNV-1a over the viewport pixels. Stable across runs and platforms
+    pub fn frame_hash(&self) -> u64 {
+        let bytes: Vec<u8> = self
+            .viewport_buffer
+            .iter()
+            .flat_map(|pixel| pixel.to_le_bytes().to_vec())
+            .collect();
+        fnv1a(&bytes)
+    }
+
+    // Register values left behind by the DMG boot rom
+    pub fn post_boot_init(&mut self) {
+        self.LCD_control = 0x91;
+        self.scy = 0;
+        self.scx = 0;
+        self.lyc = 0;
+        self.bgp = 0xFC;
+        self.obp0 = 0xFF;
+        self.obp1 = 0xFF;
+        self.wy = 0;
+        self.wx = 0;
     }
 
     pub fn turn_lcd_off(&mut self) {
         self.disable_lcd();
         // TODO: pause ppu and draw black?
     }
+    pub fn save_state(&self, w: &mut StateWriter) {
+        for &reg in [
+            self.LCD_control,
+            self.LCDC_status,
+            self.scy,
+            self.scx,
+            self.ly,
+            self.lyc,
+            self.bgp,
+            self.obp0,
+            self.obp1,
+            self.wy,
+            self.wx,
+        ]
+        .iter()
+        {
+            w.u8(reg);
+        }
+        w.bytes(&self.sprite_memory);
+        w.bytes(&self.vram);
+        w.u32(self.dots as u32);
+        w.u8(self.mode.to_stat());
+        w.bool(self.stat_line);
+        w.u8(self.window_line);
+        w.u8(self.vram_bank);
+        w.bytes(&self.vram_bank1);
+        w.u8(self.bg_palette_index);
+        w.u8(self.obj_palette_index);
+        w.bytes(&self.bg_palette_ram);
+        w.bytes(&self.obj_palette_ram);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), EmuError> {
+        self.LCD_control = r.u8()?;
+        self.LCDC_status = r.u8()?;
+        self.scy = r.u8()?;
+        self.scx = r.u8()?;
+        self.ly = r.u8()?;
+        self.lyc = r.u8()?;
+        self.bgp = r.u8()?;
+        self.obp0 = r.u8()?;
+        self.obp1 = r.u8()?;
+        self.wy = r.u8()?;
+        self.wx = r.u8()?;
+        r.bytes(&mut self.sprite_memory)?;
+        r.bytes(&mut self.vram)?;
+        self.dots = r.u32()? as i32;
+        self.mode = match PpuMode::from_u8(r.u8()?) {
+            Some(mode) => mode,
+            None => return Err(EmuError::InvalidState("ppu mode".to_string())),
+        };
+        self.stat_line = r.bool()?;
+        self.window_line = r.u8()?;
+        self.vram_bank = r.u8()?;
+        r.bytes(&mut self.vram_bank1)?;
+        self.bg_palette_index = r.u8()?;
+        self.obj_palette_index = r.u8()?;
+        r.bytes(&mut self.bg_palette_ram)?;
+        r.bytes(&mut self.obj_palette_ram)?;
+        // A line the FIFO renderer was in the middle of isn't finished
+        self.fifo = PixelFifo::new();
+        Ok(())
+    }
+
     pub fn read(&self, address: u16) -> Option<u8> {
         match address {
             0xFF40 => Some(self.LCD_control),
-            0xFF41 => Some(self.LCDC_status),
+            // Bit 7 is unused and always reads 1
+            0xFF41 => Some(self.LCDC_status | 0x80),
             0xFF42 => Some(self.scy),
             0xFF43 => Some(self.scx),
             0xFF44 => Some(self.ly),
@@ -214,6 +703,16 @@ impl Ppu {
             0xFF49 => Some(self.obp1),
             0xFF4A => Some(self.wy),
             0xFF4B => Some(self.wx),
+            // Unused bits read as 1
+            VRAM_BANK_REGISTER if self.cgb_mode => Some(self.vram_bank | 0xFE),
+            0xFF68 if self.cgb_mode => Some(self.bg_palette_index | 0x40),
+            0xFF69 if self.cgb_mode => {
+                Some(self.bg_palette_ram[(self.bg_palette_index & 0x3F) as usize])
+            }
+            0xFF6A if self.cgb_mode => Some(self.obj_palette_index | 0x40),
+            0xFF6B if self.cgb_mode => {
+                Some(self.obj_palette_ram[(self.obj_palette_index & 0x3F) as usize])
+            }
             _ => None,
         }
     }
@@ -222,78 +721,340 @@ impl Ppu {
         if !self.lcd_display_enabled() {
             return;
         }
-        self.draw_background();
-        self.draw_sprites();
+        let line_start = self.ly as usize * VIEWPORT_WIDTH;
+        let line_end = line_start + VIEWPORT_WIDTH;
+        // Kept on the stack, this runs for every line
+        let mut old_line = [0; VIEWPORT_WIDTH];
+        old_line.copy_from_slice(&self.viewport_buffer[line_start..line_end]);
+
+        let window_drawn = match self.renderer {
+            RendererMode::Scanline => {
+                self.draw_background();
+                self.draw_window();
+                self.draw_sprites();
+                self.window_visible()
+            }
+            RendererMode::Fifo => {
+                // Pixel transfer is always the same length here, the pixels
+                // the fetcher didn't get to in time are drawn right away
+                while self.fifo.x < VIEWPORT_WIDTH {
+                    self.fifo_step();
+                }
+                self.fifo.window
+            }
+        };
+        if window_drawn {
+            self.window_line = self.window_line.wrapping_add(1);
+        }
+        if self.debug_sprite_overflow && self.obj_enable() && self.sprite_overflow() {
+            for pixel in &mut self.viewport_buffer[line_start..line_start + OVERFLOW_MARKER_WIDTH] {
+                *pixel = OVERFLOW_MARKER_COLOR;
+            }
+        }
+
+        if self.viewport_buffer[line_start..line_end] != old_line[..] {
+            self.viewport_dirty = true;
+        }
     }
 
     fn draw_background(&mut self) {
-        // scy is the viewport top. ly is which line in the viewport
-        let line = self.ly as u16 + self.scy as u16;
-        let line = line % VIEWPORT_HEIGHT as u16;
-        // Same but for column
-        let column = self.scx;
+        let line_start = self.ly as usize * VIEWPORT_WIDTH;
+        if !self.bg_enable() {
+            // Blank on DMG, sprites still draw on top
+            for pixel in &mut self.viewport_buffer[line_start..line_start + VIEWPORT_WIDTH] {
+                *pixel = self.palette.color(0);
+            }
+            self.line_bg = [0; VIEWPORT_WIDTH];
+            return;
+        }
+        // scy is the viewport top. ly is which line in the viewport.
+        // Both wrap around the 256x256 map
+        let y = self.ly.wrapping_add(self.scy);
+        let map_row_addr = self.bg_tile_map_address() + (y as u16 / 8) * 32;
 
-        // Move background pixels
+        let mut row = [0; 8];
         for i in 0..VIEWPORT_WIDTH {
-            let color = self.buffer[(line as usize * WIDTH) + (column as usize + i) % WIDTH];
-            self.viewport_buffer[(self.ly as usize * VIEWPORT_WIDTH) + i] =
-                bg_bit_into_color(color);
+            let x = self.scx.wrapping_add(i as u8);
+            // Next tile, or the first one partly scrolled off
+            if i == 0 || x % 8 == 0 {
+                let tile_nr = self.get_from_vram(map_row_addr + x as u16 / 8);
+                row = self.tile_row(self.tile_data_address(tile_nr), y as u16 % 8);
+            }
+            self.line_bg[i] = row[x as usize % 8];
+            let color = self.bg_color(self.line_bg[i]) as u8;
+            self.viewport_buffer[line_start + i] = self.palette.color(color);
+        }
+    }
+
+    fn draw_window(&mut self) {
+        if !self.window_visible() {
+            return;
+        }
+        for x in 0..VIEWPORT_WIDTH {
+            if let Some(color) = self.window_color(x as i16) {
+                self.line_bg[x] = color;
+                let color = self.bg_color(color) as u8;
+                self.viewport_buffer[(self.ly as usize * VIEWPORT_WIDTH) + x] =
+                    self.palette.color(color);
+            }
+        }
+    }
+
+    // The window covers part of the current line.
+    // wx is the left edge + 7, so values past 166 are off the screen.
+    // Turning the background off hides the window too
+    fn window_visible(&self) -> bool {
+        self.bg_enable() && self.window_enable() && self.ly >= self.wy && self.wx <= 166
+    }
+
+    // Color number of the window at screen column x, None left of the window
+    fn window_color(&self, x: i16) -> Option<u8> {
+        let column = x - (self.wx as i16 - 7);
+        if column < 0 {
+            return None;
+        }
+        let column = column as u16;
+        let row = self.window_line as u16;
+        let tile_nr =
+            self.get_from_vram(self.window_tile_map_address() + (row / 8) * 32 + column / 8);
+        let colors = self.tile_row(self.tile_data_address(tile_nr), row % 8);
+        Some(colors[column as usize % 8])
+    }
+
+    fn fifo_start_line(&mut self) {
+        let mut fifo = PixelFifo::new();
+        if self.obj_enable() {
+            fifo.sprites = self.line_sprites();
         }
+        fifo.sprite_fetched = vec![false; fifo.sprites.len()];
+        fifo.discard = self.scx % 8;
+        fifo.x = 0;
+        self.fifo = fifo;
+    }
+
+    // One dot of pixel transfer: a fetcher step every other dot,
+    // and a pixel out of the FIFOs when there are any
+    fn fifo_step(&mut self) {
+        if self.fifo.x >= VIEWPORT_WIDTH {
+            return;
+        }
+        self.fifo.dot += 1;
+        if self.fifo.dot % 2 == 0 {
+            self.fetcher_step();
+        }
+
+        if self.fifo.bg.is_empty() {
+            return;
+        }
+        if self.fifo.discard > 0 {
+            self.fifo.bg.pop_front();
+            self.fifo.discard -= 1;
+            return;
+        }
+
+        // The window starts at wx - 7. The background fetched so far is thrown away
+        let x = self.fifo.x as i16;
+        if !self.fifo.window
+            && self.bg_enable()
+            && self.window_enable()
+            && self.ly >= self.wy
+            && x >= self.wx as i16 - 7
+        {
+            self.fifo.window = true;
+            self.fifo.restart_fetcher();
+            // Left of the screen when wx is under 7
+            self.fifo.discard = 7u8.saturating_sub(self.wx);
+            return;
+        }
+
+        // Fetch the sprites starting here, and the ones hanging off the left edge
+        for i in 0..self.fifo.sprites.len() {
+            let sprite = &self.fifo.sprites[i];
+            if self.fifo.sprite_fetched[i] || sprite.x > x {
+                continue;
+            }
+            self.fifo.sprite_fetched[i] = true;
+            let row = self.sprite_row(sprite);
+            let skip = (x - sprite.x) as usize;
+            for (j, &color) in row.iter().skip(skip).enumerate() {
+                // Earlier sprites keep their opaque pixels
+                let pixel = (color, sprite.palette_nr, sprite.above_bg);
+                match self.fifo.sprite.get_mut(j) {
+                    Some(old) if old.0 == 0 => *old = pixel,
+                    Some(_) => {}
+                    None => self.fifo.sprite.push_back(pixel),
+                }
+            }
+        }
+
+        let mut bg = self.fifo.bg.pop_front().unwrap();
+        if !self.bg_enable() {
+            bg = 0;
+        }
+        self.line_bg[x as usize] = bg;
+        let (sprite, palette_nr, above_bg) = self.fifo.sprite.pop_front().unwrap_or((0, 0, true));
+        // color of 0 is transparent for sprites
+        let color = if sprite != 0 && self.obj_enable() && (above_bg || bg == 0) {
+            self.obj_color(palette_nr, sprite)
+        } else if self.bg_enable() {
+            self.bg_color(bg)
+        } else {
+            Color::White
+        } as u8;
+        let pixel = self.palette.color(color);
+        let index = self.ly as usize * VIEWPORT_WIDTH + x as usize;
+        if self.viewport_buffer[index] != pixel {
+            self.viewport_buffer[index] = pixel;
+            self.viewport_dirty = true;
+        }
+        self.fifo.x += 1;
+    }
+
+    // Every fetcher step takes two dots. The registers are read when used
+    fn fetcher_step(&mut self) {
+        self.fifo.fetcher_state = match self.fifo.fetcher_state {
+            FetcherState::GetTile => {
+                let column = self.fifo.fetcher_column as u16;
+                let address = if self.fifo.window {
+                    let row = self.window_line as u16;
+                    self.fifo.tile_line = row % 8;
+                    self.window_tile_map_address() + (row / 8) * 32 + (column & 31)
+                } else {
+                    // scy is the viewport top and scx picks the first column.
+                    // Both wrap around the 256x256 map
+                    let y = self.ly.wrapping_add(self.scy) as u16;
+                    self.fifo.tile_line = y % 8;
+                    self.bg_tile_map_address()
+                        + (y / 8) * 32
+                        + ((self.scx as u16 / 8 + column) & 31)
+                };
+                self.fifo.tile_nr = self.get_from_vram(address);
+                FetcherState::GetDataLow
+            }
+            FetcherState::GetDataLow => FetcherState::GetDataHigh,
+            FetcherState::GetDataHigh => {
+                let tile_addr = self.tile_data_address(self.fifo.tile_nr);
+                self.fifo.tile_row = self.tile_row(tile_addr, self.fifo.tile_line);
+                FetcherState::Push
+            }
+            FetcherState::Push => {
+                // Waits until the fifo has room for the whole row
+                if self.fifo.bg.is_empty() {
+                    let row = self.fifo.tile_row;
+                    self.fifo.bg.extend(row.iter());
+                    self.fifo.fetcher_column = self.fifo.fetcher_column.wrapping_add(1);
+                    FetcherState::GetTile
+                } else {
+                    FetcherState::Push
+                }
+            }
+        };
     }
 
     fn draw_sprites(&mut self) {
         if !self.obj_enable() {
             return;
         }
-        let sprite_height = self.obj_height();
 
-        // Loop thru all the sprites
-        for sprite in (0..40).map(|x| x * 4) {
-            let sprite = create_sprite(&self.sprite_memory, sprite, false);
-            // Check if the sprite is on this line
-            if self.ly < sprite.y || self.ly >= sprite.y + sprite_height {
+        let mut sprites = self.line_sprites();
+        // Lower x is drawn on top, and lower OAM index when x is the same.
+        // Stable sort keeps the OAM order, then draw from the bottom up
+        sprites.sort_by_key(|sprite| sprite.x);
+        for sprite in sprites.into_iter().rev() {
+            // Check if x is visible at all
+            if sprite.x <= -8 || sprite.x >= VIEWPORT_WIDTH as i16 {
                 continue;
             }
-            // Check if x is visible
-            // FIXME:
-            if sprite.x == 0 || sprite.x >= 168 {
-                //continue;
-            }
-            // Draw the right line
-            // sprite.y - self.ly gives the distance from bottom of the sprite
-            // sprite_height - that to give it from top
-            let line_to_draw = self.ly - sprite.y;
-
-            if sprite_height == 8 {
-                let bytes_to_skip = line_to_draw as u16 * 2;
-                let tile_addr = 0x8000 + sprite.tile_nr as u16 * 16;
-                let byte1 = self.get_from_vram(tile_addr + bytes_to_skip);
-                let byte2 = self.get_from_vram(tile_addr + bytes_to_skip + 1);
-
-                for j in 0..8 {
-                    let buffer_col = sprite.x + j;
-                    if buffer_col > VIEWPORT_WIDTH as u8 {
-                        continue;
-                    }
-                    let color = ((byte1 >> (7 - j)) & 1) | (((byte2 >> (7 - j)) & 1) << 1);
-                    if color == 0 {
-                        // color of 0 is transparent for sprites
-                        continue;
-                    }
-
-                    self.viewport_buffer
-                        [(self.ly as usize * VIEWPORT_WIDTH) + buffer_col as usize] =
-                        bg_bit_into_color(color);
+            let row = self.sprite_row(&sprite);
+
+            for (j, &color) in row.iter().enumerate() {
+                // Clip the columns that are off the screen
+                let buffer_col = sprite.x + j as i16;
+                if buffer_col < 0 || buffer_col >= VIEWPORT_WIDTH as i16 {
+                    continue;
+                }
+                if color == 0 {
+                    // color of 0 is transparent for sprites
+                    continue;
                 }
+
+                // Behind the background, only showing over its color 0.
+                // Still covers the sprites under it
+                let bg = self.line_bg[buffer_col as usize];
+                let color = if sprite.above_bg || bg == 0 {
+                    self.obj_color(sprite.palette_nr, color)
+                } else {
+                    self.bg_color(bg)
+                } as u8;
+                self.viewport_buffer[(self.ly as usize * VIEWPORT_WIDTH) + buffer_col as usize] =
+                    self.palette.color(color);
             }
-            // TODO: sprite_height of 16
         }
     }
 
-    fn update_bg_tile(&mut self, map_addr: u16, tile_data_nr: u8) {
+    // The first 10 sprites in OAM order on this line. X doesn't matter,
+    // sprites off the sides still count towards the limit
+    fn line_sprites(&self) -> Vec<Sprite> {
+        (0..40)
+            .map(|x| create_sprite(&self.sprite_memory, x * 4, false))
+            .filter(|sprite| self.sprite_on_line(sprite))
+            .take(10)
+            .collect()
+    }
+
+    // More than the 10 drawn sprites are on this line
+    fn sprite_overflow(&self) -> bool {
+        (0..40)
+            .map(|x| create_sprite(&self.sprite_memory, x * 4, false))
+            .filter(|sprite| self.sprite_on_line(sprite))
+            .count()
+            > 10
+    }
+
+    // Check if the sprite is on this line
+    fn sprite_on_line(&self, sprite: &Sprite) -> bool {
+        let ly = self.ly as i16;
+        ly >= sprite.y && ly < sprite.y + self.obj_height() as i16
+    }
+
+    // Color numbers of the sprite's row on the current line
+    fn sprite_row(&self, sprite: &Sprite) -> [u8; 8] {
+        // Draw the right line, counting from the bottom when flipped
+        let height = self.obj_height() as u16;
+        let mut line_to_draw = (self.ly as i16 - sprite.y) as u16;
+        if sprite.y_flip {
+            line_to_draw = height - 1 - line_to_draw;
+        }
+        // 8x16 sprites ignore the low bit, the bottom half is the next tile.
+        // The row goes past the top tile's 16 bytes into it
+        let tile_nr = if height == 16 {
+            sprite.tile_nr & 0xFE
+        } else {
+            sprite.tile_nr
+        };
+        let tile_addr = 0x8000 + tile_nr as u16 * 16;
+        let mut row = self.tile_row(tile_addr, line_to_draw);
+        if sprite.x_flip {
+            row.reverse();
+        }
+        row
+    }
+
+    // Color numbers of one 8 pixel row of a tile
+    fn tile_row(&self, tile_addr: u16, row: u16) -> [u8; 8] {
+        let byte1 = self.get_from_vram(tile_addr + row * 2);
+        let byte2 = self.get_from_vram(tile_addr + row * 2 + 1);
+        let mut colors = [0; 8];
+        for (j, color) in colors.iter_mut().enumerate() {
+            *color = ((byte1 >> (7 - j)) & 1) | (((byte2 >> (7 - j)) & 1) << 1);
+        }
+        colors
+    }
+
+    fn tile_data_address(&self, tile_data_nr: u8) -> u16 {
         let tile_size = 16; // one tile is 16 bytes
         let tile_data_start = self.bg_window_tile_data();
-        let tile_addr = if tile_data_start == 0x8800 {
+        if tile_data_start == 0x8800 {
             // tile index is -128 - 127. 0 at 0x9000
             // Sign extend and change to i16 for address
             let tile_data_nr = tile_data_nr as i8 as i16;
@@ -301,34 +1062,6 @@ impl Ppu {
         } else {
             // tile index is 0-255. 0 at 0x8000
             tile_data_start + (tile_data_nr as u16 * tile_size as u16)
-        };
-
-        let tile_map_nr = map_addr - self.bg_tile_map_address();
-        // 32 tiles per row. so tile_nr/32 gives tile row. Then 8 pixels each tile
-        let buffer_start_row_pixel = (tile_map_nr / 32) * 8;
-        let buffer_start_column_pixel = (tile_map_nr % 32) * 8;
-
-        self.draw_tile(buffer_start_row_pixel, buffer_start_column_pixel, tile_addr);
-    }
-
-    fn draw_tile(
-        &mut self,
-        buffer_start_row_pixel: u16,
-        buffer_start_column_pixel: u16,
-        tile_addr: u16,
-    ) {
-        // Update the 8x8 area
-        for i in 0..8 {
-            let buffer_row = buffer_start_row_pixel + i;
-            let byte1 = self.get_from_vram(tile_addr + i * 2);
-            let byte2 = self.get_from_vram(tile_addr + i * 2 + 1);
-
-            for j in 0..8 {
-                let buffer_col = buffer_start_column_pixel + j;
-
-                let color = (byte1 >> (7 - j) & 1) | ((byte2 >> (7 - j) & 1) << 1);
-                self.buffer[(buffer_row as usize * WIDTH) + buffer_col as usize] = color;
-            }
         }
     }
 
@@ -338,76 +1071,181 @@ impl Ppu {
     }
 
     pub fn read_vram(&self, address: u16) -> u8 {
-        if self.state == State::PixelTransfer {
-            //return 0xFF;
+        if self.vram_blocked() {
+            return 0xFF;
         }
-        let address = address - VRAM_START;
-        self.vram[address as usize]
+        self.peek_vram(address)
     }
     pub fn write_vram(&mut self, address: u16, value: u8) {
-        if self.state == State::PixelTransfer {
-            //return;
+        if self.vram_blocked() {
+            return;
         }
-        let vram_address = address - VRAM_START;
-        self.vram[vram_address as usize] = value;
+        self.poke_vram(address, value);
+    }
 
-        if self.is_addr_in_bg_map(address) {
-            self.update_bg_tile(address, value);
+    // Vram access in any mode, for debugging
+    pub fn peek_vram(&self, address: u16) -> u8 {
+        if self.vram_bank == 1 {
+            return self.vram_bank1[(address - VRAM_START) as usize];
         }
+        self.get_from_vram(address)
     }
-
-    fn is_addr_in_bg_map(&self, address: u16) -> bool {
-        if self.bg_tile_map_address() == 0x9800 {
-            address >= 0x9800 && address < 0x9BFF
+    pub fn poke_vram(&mut self, address: u16, value: u8) {
+        let vram_address = (address - VRAM_START) as usize;
+        if self.vram_bank == 1 {
+            self.vram_bank1[vram_address] = value;
         } else {
-            address >= 0x9C00 && address < 0x9FFF
+            self.vram[vram_address] = value;
         }
     }
 
+    // The ppu uses OAM in modes 2 and 3, the cpu only sees it in
+    // modes 0 and 1 with strict access
+    fn oam_blocked(&self) -> bool {
+        self.strict_access
+            && (self.mode == PpuMode::PixelTransfer || self.mode == PpuMode::OamSearch)
+    }
+    // Vram is only in use during mode 3
+    fn vram_blocked(&self) -> bool {
+        self.strict_access && self.mode == PpuMode::PixelTransfer
+    }
+
     pub fn read_sprite_mem(&self, address: u16) -> u8 {
-        if self.state == State::PixelTransfer || self.state == State::OAMSearch {
-            //return 0xFF;
+        if self.oam_blocked() {
+            return 0xFF;
         }
-        let address = address - SPRITE_MEM_START;
-        self.sprite_memory[address as usize]
+        self.peek_sprite_mem(address)
     }
     pub fn write_sprite_mem(&mut self, address: u16, value: u8) {
-        if self.state == State::PixelTransfer || self.state == State::OAMSearch {
-            //return;
+        if self.oam_blocked() {
+            return;
+        }
+        self.poke_sprite_mem(address, value);
+    }
+
+    // OAM access in any mode, for debugging
+    pub fn peek_sprite_mem(&self, address: u16) -> u8 {
+        self.sprite_memory[(address - SPRITE_MEM_START) as usize]
+    }
+    pub fn poke_sprite_mem(&mut self, address: u16, value: u8) {
+        self.sprite_memory[(address - SPRITE_MEM_START) as usize] = value;
+    }
+
+    // Stages data straight into vram for tests, offset from 0x8000
+    #[cfg(test)]
+    pub fn load_vram(&mut self, offset: u16, data: &[u8]) {
+        let start = offset as usize;
+        self.vram[start..start + data.len()].copy_from_slice(data);
+    }
+
+    // Stages data straight into OAM for tests, offset from 0xFE00
+    #[cfg(test)]
+    pub fn load_oam(&mut self, offset: u16, data: &[u8]) {
+        let start = offset as usize;
+        self.sprite_memory[start..start + data.len()].copy_from_slice(data);
+    }
+
+    // Raw vram followed by OAM, for sharing rendering repros
+    pub fn export_vram<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let mut data = self.vram.to_vec();
+        data.extend_from_slice(&self.sprite_memory);
+        fs::write(path, data)
+    }
+
+    // Current viewport as a png. Before the first frame it's all black
+    pub fn save_screenshot<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let file = io::BufWriter::new(fs::File::create(path)?);
+        let mut encoder = png::Encoder::new(file, VIEWPORT_WIDTH as u32, VIEWPORT_HEIGHT as u32);
+        encoder.set_color(png::ColorType::RGBA);
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut writer = encoder.write_header()?;
+        writer.write_image_data(&self.framebuffer_rgba())?;
+        Ok(())
+    }
+
+    pub fn import_vram<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()> {
+        let data = fs::read(path)?;
+        if data.len() != self.vram.len() + self.sprite_memory.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Invalid vram snapshot: {} bytes", data.len()),
+            ));
         }
-        let address = address - SPRITE_MEM_START;
-        self.sprite_memory[address as usize] = value;
+        let (vram, oam) = data.split_at(self.vram.len());
+        self.vram.copy_from_slice(vram);
+        self.sprite_memory.copy_from_slice(oam);
+        Ok(())
+    }
+
+    pub fn vram(&self) -> &[u8] {
+        &self.vram
+    }
+    pub fn oam(&self) -> &[u8] {
+        &self.sprite_memory
     }
 
     pub fn write(&mut self, address: u16, value: u8) -> bool {
         match address {
-            0xFF40 => self.LCD_control = value,
-            0xFF41 => self.LCDC_status = value,
+            0xFF40 => {
+                let was_enabled = self.lcd_display_enabled();
+                self.LCD_control = value;
+                match (was_enabled, self.lcd_display_enabled()) {
+                    (true, false) => self.disable_lcd(),
+                    (false, true) => self.enable_lcd(),
+                    _ => {}
+                }
+            }
+            // Mode and coincidence bits are read only
+            0xFF41 => self.LCDC_status = (value & 0x78) | (self.LCDC_status & 0x07),
             0xFF42 => self.scy = value,
             0xFF43 => self.scx = value,
             0xFF44 => {
                 // ly is reset on write
                 self.ly = 154;
-                self.state = State::VBlank;
+                self.set_mode(PpuMode::VBlank);
+            }
+            0xFF45 => {
+                self.lyc = value;
+                // The coincidence flag follows right away
+                self.update_coincidence_flag();
+                self.update_stat_line();
             }
-            0xFF45 => self.lyc = value,
             0xFF47 => self.bgp = value,
             0xFF48 => self.obp0 = value,
             0xFF49 => self.obp1 = value,
             0xFF4A => self.wy = value,
             0xFF4B => self.wx = value,
+            VRAM_BANK_REGISTER if self.cgb_mode => self.vram_bank = value & 1,
+            0xFF68 if self.cgb_mode => self.bg_palette_index = value & 0xBF,
+            0xFF69 if self.cgb_mode => {
+                write_palette_ram(&mut self.bg_palette_ram, &mut self.bg_palette_index, value)
+            }
+            0xFF6A if self.cgb_mode => self.obj_palette_index = value & 0xBF,
+            0xFF6B if self.cgb_mode => write_palette_ram(
+                &mut self.obj_palette_ram,
+                &mut self.obj_palette_index,
+                value,
+            ),
 
             _ => return false,
         }
         true
     }
 
+    // ly goes back to 0 and the ppu waits in mode 0 until turned on
     fn disable_lcd(&mut self) {
         self.LCD_control &= !(1 << 7);
+        self.ly = 0;
+        self.window_line = 0;
+        self.dots = 0;
+        self.set_mode(PpuMode::HBlank);
     }
 
+    // Starts drawing from the top of the screen
     fn enable_lcd(&mut self) {
         self.LCD_control |= 1 << 7;
+        self.dots = OAM_SEARCH_DOTS;
+        self.set_mode(PpuMode::OamSearch);
     }
     fn lcd_display_enabled(&self) -> bool {
         self.LCD_control & (1 << 7) > 0
@@ -479,15 +1317,32 @@ impl Ppu {
         }
     }
 
+    // Sprites use OBP1 when their palette bit is set. Color 0 is transparent,
+    // so callers skip it before getting here
+    fn obj_color(&self, palette_nr: u8, value: u8) -> Color {
+        let palette = if palette_nr > 0 { self.obp1 } else { self.obp0 };
+        match value {
+            0 => color_for_00(palette),
+            1 => color_for_01(palette),
+            2 => color_for_10(palette),
+            3 => color_for_11(palette),
+            _ => Color::Black,
+        }
+    }
+
+    // Holds the current mode for c more machine cycles
     pub fn add_cycles(&mut self, c: i32) {
-        self.cycles += c;
+        self.dots += c * 4;
     }
 }
 
 #[derive(Debug)]
 struct Sprite {
-    y: u8,
-    x: u8,
+    // Screen row of the top edge, from the raw OAM y - 16.
+    // Negative when partially above the screen, raw y 0 is fully hidden
+    y: i16,
+    // Screen column of the left edge, negative when partially off the left side
+    x: i16,
     tile_nr: u8,
     above_bg: bool,
     y_flip: bool,
@@ -498,8 +1353,8 @@ struct Sprite {
 
 fn create_sprite(oam_mem: &[u8], address: usize, cgb_mode: bool) -> Sprite {
     Sprite {
-        y: oam_mem[address] - 16,
-        x: oam_mem[address + 1] - 8,
+        y: oam_mem[address] as i16 - 16,
+        x: oam_mem[address + 1] as i16 - 8,
         tile_nr: oam_mem[address + 2],
         above_bg: !check_bit(oam_mem[address + 3], 7),
         y_flip: check_bit(oam_mem[address + 3], 6),
@@ -522,13 +1377,11 @@ fn create_window(width: usize, height: usize, title: &str, scale: Scale) -> Wind
     return window;
 }
 
-fn bg_bit_into_color(bit: u8) -> u32 {
-    match bit {
-        0b00 => 0xffffff,
-        0b01 => 0x505151,
-        0b10 => 0x838484,
-        0b11 => 0,
-        _ => panic!("Invalid color!"),
+// Bit 7 of the index register moves it to the next byte after each write
+fn write_palette_ram(ram: &mut [u8; CGB_PALETTE_RAM_LENGTH], index: &mut u8, value: u8) {
+    ram[(*index & 0x3F) as usize] = value;
+    if check_bit(*index, 7) {
+        *index = 0x80 | (index.wrapping_add(1) & 0x3F);
     }
 }
 
@@ -548,6 +1401,876 @@ fn color_for_00(palette: u8) -> Color {
 #[cfg(test)]
 mod tests {
     use super::*;
+
+    fn run_frame(ppu: &mut Ppu) {
+        while !ppu.update() {}
+    }
+
+    // Moves all sprites off screen
+    fn hide_sprites(ppu: &mut Ppu) {
+        for sprite in (0..40).map(|x| 0xFE00 + x * 4) {
+            ppu.write_sprite_mem(sprite, 200);
+            ppu.write_sprite_mem(sprite + 1, 8);
+        }
+    }
+
+    fn run_line(ppu: &mut Ppu, ly: u8) {
+        while ppu.ly != ly || ppu.mode != PpuMode::HBlank {
+            ppu.update();
+        }
+    }
+
+    fn line_pixels(ppu: &Ppu, ly: usize) -> &[u32] {
+        &ppu.viewport_buffer[ly * VIEWPORT_WIDTH..(ly + 1) * VIEWPORT_WIDTH]
+    }
+
+    #[test]
+    fn test_load_vram_and_oam() {
+        let mut ppu = Ppu::new_headless();
+        ppu.write(0xFF40, 0x93);
+        ppu.write(0xFF47, 0xE4);
+        ppu.write(0xFF48, 0xE4);
+        hide_sprites(&mut ppu);
+        // Tile 1 black, drawn as the top left background tile and as sprite 0
+        ppu.load_vram(0x0010, &[0xFF; 16]);
+        ppu.load_vram(0x1800, &[1]);
+        ppu.load_oam(0, &[16 + 8, 8 + 16, 1, 0]);
+        run_frame(&mut ppu);
+
+        let black = Palette::GRAYSCALE.color(3);
+        let white = Palette::GRAYSCALE.color(0);
+        // Background tile
+        assert_eq!(&line_pixels(&ppu, 0)[0..8], &[black; 8]);
+        assert_eq!(line_pixels(&ppu, 0)[8], white);
+        // Sprite at 16,8
+        assert_eq!(line_pixels(&ppu, 8)[15], white);
+        assert_eq!(&line_pixels(&ppu, 8)[16..24], &[black; 8]);
+        assert_eq!(line_pixels(&ppu, 8)[24], white);
+    }
+
+    #[test]
+    fn test_sprite_clipped_at_left_edge() {
+        let mut ppu = Ppu::new_headless();
+        ppu.write(0xFF40, 0x93);
+        ppu.write(0xFF48, 0xE4);
+        hide_sprites(&mut ppu);
+        // Tile 1, right half black
+        for i in 0..16 {
+            ppu.write_vram(0x8010 + i, 0x0F);
+        }
+        ppu.write_sprite_mem(0xFE00, 16);
+        ppu.write_sprite_mem(0xFE01, 4);
+        ppu.write_sprite_mem(0xFE02, 1);
+        run_line(&mut ppu, 0);
+
+        let black = Palette::GRAYSCALE.color(3);
+        let white = Palette::GRAYSCALE.color(0);
+        // Only tile columns 4-7 are visible, at screen columns 0-3
+        assert_eq!(&line_pixels(&ppu, 0)[0..4], &[black; 4]);
+        assert_eq!(&line_pixels(&ppu, 0)[4..8], &[white; 4]);
+    }
+
+    #[test]
+    fn test_sprite_clipped_at_right_edge() {
+        let mut ppu = Ppu::new_headless();
+        ppu.write(0xFF40, 0x93);
+        ppu.write(0xFF48, 0xE4);
+        hide_sprites(&mut ppu);
+        // Tile 1, all black
+        for i in 0..16 {
+            ppu.write_vram(0x8010 + i, 0xFF);
+        }
+        ppu.write_sprite_mem(0xFE00, 16);
+        ppu.write_sprite_mem(0xFE01, 164);
+        ppu.write_sprite_mem(0xFE02, 1);
+        run_line(&mut ppu, 0);
+
+        let black = Palette::GRAYSCALE.color(3);
+        let white = Palette::GRAYSCALE.color(0);
+        assert_eq!(&line_pixels(&ppu, 0)[152..156], &[white; 4]);
+        assert_eq!(&line_pixels(&ppu, 0)[156..160], &[black; 4]);
+    }
+
+    #[test]
+    fn test_fifo_renderer_matches_scanline() {
+        let render = |renderer: RendererMode| {
+            let mut ppu = Ppu::new_headless();
+            ppu.set_renderer(renderer);
+            ppu.write(0xFF40, 0x93);
+            ppu.write(0xFF43, 3);
+            hide_sprites(&mut ppu);
+            // Tiles 1 and 2 with different patterns
+            for i in 0..8 {
+                ppu.load_vram(0x0010 + i * 2, &[0xF0, 0x3C]);
+                ppu.load_vram(0x0020 + i * 2, &[0x55 << (i % 2), 0xAA]);
+            }
+            // Checkered background map
+            for i in 0..0x400 {
+                ppu.load_vram(0x1800 + i, &[(i % 3) as u8]);
+            }
+            // One sprite hanging off the left edge, one in the middle
+            ppu.load_oam(0, &[16 + 20, 4, 1, 0]);
+            ppu.load_oam(4, &[16 + 60, 8 + 77, 2, 0]);
+            run_frame(&mut ppu);
+            ppu.viewport_buffer.clone()
+        };
+
+        let scanline = render(RendererMode::Scanline);
+        let fifo = render(RendererMode::Fifo);
+        assert!(scanline == fifo);
+    }
+
+    #[test]
+    fn test_export_import_vram() {
+        let mut ppu = Ppu::new_headless();
+        let pattern: Vec<u8> = (0..VRAM_LENGTH).map(|i| (i * 7) as u8).collect();
+        ppu.load_vram(0, &pattern);
+        let oam: Vec<u8> = (0..SPRITE_MEM_LENGTH).map(|i| i as u8).collect();
+        ppu.load_oam(0, &oam);
+
+        let path = std::env::temp_dir().join("rustboy_test_export_vram.bin");
+        ppu.export_vram(&path).unwrap();
+        let mut imported = Ppu::new_headless();
+        imported.import_vram(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(imported.vram(), ppu.vram());
+        assert_eq!(imported.oam(), ppu.oam());
+    }
+
+    #[test]
+    fn test_stat_interrupt_blocking() {
+        let mut ppu = Ppu::new_headless();
+        ppu.write(0xFF40, 0x93);
+        hide_sprites(&mut ppu);
+        // HBlank and LYC interrupts, coincidence on line 5
+        ppu.write(0xFF41, (1 << 3) | (1 << 6));
+        ppu.write(0xFF45, 5);
+        while ppu.ly != 4 {
+            ppu.update();
+        }
+        ppu.take_stat_interrupt();
+
+        // Line 4 HBlank raises the line. It stays high through the
+        // coincidence and HBlank of line 5, so nothing retriggers
+        let mut interrupts = 0;
+        while ppu.ly != 6 {
+            ppu.update();
+            if ppu.take_stat_interrupt() {
+                interrupts += 1;
+            }
+        }
+        assert_eq!(interrupts, 1);
+        assert_eq!(ppu.read(0xFF41).unwrap() & (1 << 2), 0);
+    }
+
+    #[test]
+    fn test_step_dot_mode_timing() {
+        let mut ppu = Ppu::new_headless();
+        ppu.write(0xFF40, 0x93);
+        hide_sprites(&mut ppu);
+        let mut events = vec![];
+        for dot in 1..=LINE_DOTS {
+            if let Some(mode) = ppu.step_dot() {
+                events.push((dot, mode));
+            }
+        }
+        let mode_3_length = PIXEL_TRANSFER_DOTS;
+        assert_eq!(
+            events,
+            vec![
+                (80, PpuMode::PixelTransfer),
+                (80 + mode_3_length, PpuMode::HBlank),
+                (456, PpuMode::OamSearch),
+            ]
+        );
+        assert_eq!(ppu.ly, 1);
+    }
+
+    #[test]
+    fn test_set_line_lyc_interrupt() {
+        let mut ppu = Ppu::new_headless();
+        // Only the LYC interrupt
+        ppu.write(0xFF41, 1 << 6);
+        ppu.write(0xFF45, 100);
+        ppu.set_line(99);
+        assert!(!ppu.take_stat_interrupt());
+        assert_eq!(ppu.read(0xFF41).unwrap() & (1 << 2), 0);
+
+        ppu.set_line(100);
+        assert_eq!(ppu.read(0xFF44), Some(100));
+        assert!(ppu.take_stat_interrupt());
+        assert_eq!(ppu.read(0xFF41).unwrap() & (1 << 2), 1 << 2);
+
+        // Staying on the line doesn't retrigger
+        ppu.set_line(100);
+        assert!(!ppu.take_stat_interrupt());
+    }
+
+    #[test]
+    fn test_mode_change_callback() {
+        use std::cell::RefCell;
+        use std::rc::Rc;
+
+        let mut ppu = Ppu::new_headless();
+        let changes = Rc::new(RefCell::new(vec![]));
+        let log = changes.clone();
+        ppu.set_mode_change_callback(Box::new(move |old, new, ly| {
+            log.borrow_mut().push((old, new, ly));
+        }));
+        while ppu.ly != 1 {
+            ppu.update();
+        }
+        assert_eq!(*changes.borrow(), vec![(2, 3, 0), (3, 0, 0), (0, 2, 1)]);
+    }
+
+    #[test]
+    fn test_frame_hash() {
+        let mut ppu = Ppu::new_headless();
+        // Stripes of four colors, 8 pixels wide
+        let colors = [0xffffff, 0x505151, 0x838484, 0];
+        for (i, pixel) in ppu.viewport_buffer.iter_mut().enumerate() {
+            *pixel = colors[i / 8 % 4];
+        }
+        assert_eq!(ppu.frame_hash(), 0x5dc4cf36bc1ada25);
+
+        ppu.viewport_buffer[1234] = colors[1];
+        assert_ne!(ppu.frame_hash(), 0x5dc4cf36bc1ada25);
+    }
+
+    #[test]
+    fn test_sprite_limit_uses_oam_order() {
+        for &renderer in [RendererMode::Scanline, RendererMode::Fifo].iter() {
+            let mut ppu = Ppu::new_headless();
+            ppu.set_renderer(renderer);
+            ppu.write(0xFF40, 0x93);
+            ppu.write(0xFF48, 0xE4);
+            hide_sprites(&mut ppu);
+            // Tile 1 black, tile 2 light gray
+            ppu.load_vram(0x0010, &[0xFF; 16]);
+            ppu.load_vram(0x0020, &[0xFF, 0x00].repeat(8));
+            // 12 sprites on line 0. The first 10 in OAM order are drawn
+            for i in 0..10 {
+                ppu.load_oam(i * 4, &[16, 8 + 16 + i as u8 * 12, 2, 0]);
+            }
+            // 11th has the lowest x, but is past the limit
+            ppu.load_oam(40, &[16, 8, 1, 0]);
+            ppu.load_oam(44, &[16, 8 + 150, 1, 0]);
+            run_line(&mut ppu, 0);
+
+            let white = Palette::GRAYSCALE.color(0);
+            let light = Palette::GRAYSCALE.color(1);
+            assert_eq!(&line_pixels(&ppu, 0)[0..8], &[white; 8]);
+            assert_eq!(&line_pixels(&ppu, 0)[16..24], &[light; 8]);
+            assert_eq!(&line_pixels(&ppu, 0)[150..158], &[white; 8]);
+        }
+    }
+
+    #[test]
+    fn test_sprite_priority_by_x() {
+        for &renderer in [RendererMode::Scanline, RendererMode::Fifo].iter() {
+            let mut ppu = Ppu::new_headless();
+            ppu.set_renderer(renderer);
+            ppu.write(0xFF40, 0x93);
+            ppu.write(0xFF48, 0xE4);
+            hide_sprites(&mut ppu);
+            ppu.load_vram(0x0010, &[0xFF; 16]);
+            ppu.load_vram(0x0020, &[0xFF, 0x00].repeat(8));
+            // Sprite 1 has the lower x, so it's on top of sprite 0 where they overlap
+            ppu.load_oam(0, &[16, 8 + 20, 2, 0]);
+            ppu.load_oam(4, &[16, 8 + 16, 1, 0]);
+            // Same x, lower OAM index is on top
+            ppu.load_oam(8, &[16, 8 + 40, 2, 0]);
+            ppu.load_oam(12, &[16, 8 + 40, 1, 0]);
+            run_line(&mut ppu, 0);
+
+            let black = Palette::GRAYSCALE.color(3);
+            let light = Palette::GRAYSCALE.color(1);
+            assert_eq!(&line_pixels(&ppu, 0)[16..24], &[black; 8]);
+            assert_eq!(&line_pixels(&ppu, 0)[24..28], &[light; 4]);
+            assert_eq!(&line_pixels(&ppu, 0)[40..48], &[light; 8]);
+        }
+    }
+
+    #[test]
+    fn test_sprite_y_edges() {
+        let mut ppu = Ppu::new_headless();
+        ppu.write(0xFF40, 0x93);
+        ppu.write(0xFF48, 0xE4);
+        hide_sprites(&mut ppu);
+        // Tile 1, only the last two rows black
+        let mut tile = [0; 16];
+        tile[12..16].copy_from_slice(&[0xFF; 4]);
+        ppu.load_vram(0x0010, &tile);
+        // Raw y 0 is hidden, raw y 10 shows its last two rows on lines 0 and 1
+        ppu.load_oam(0, &[0, 8 + 8, 1, 0]);
+        ppu.load_oam(4, &[10, 8 + 40, 1, 0]);
+        run_frame(&mut ppu);
+
+        let black = Palette::GRAYSCALE.color(3);
+        let white = Palette::GRAYSCALE.color(0);
+        for ly in 0..VIEWPORT_HEIGHT {
+            assert_eq!(&line_pixels(&ppu, ly)[8..16], &[white; 8]);
+        }
+        assert_eq!(&line_pixels(&ppu, 0)[40..48], &[black; 8]);
+        assert_eq!(&line_pixels(&ppu, 1)[40..48], &[black; 8]);
+        assert_eq!(&line_pixels(&ppu, 2)[40..48], &[white; 8]);
+    }
+
+    #[test]
+    fn test_ppu_mode_stat_bits() {
+        for &mode in [
+            PpuMode::HBlank,
+            PpuMode::VBlank,
+            PpuMode::OamSearch,
+            PpuMode::PixelTransfer,
+        ]
+        .iter()
+        {
+            assert_eq!(PpuMode::from_stat(mode.to_stat()), mode);
+            // Other STAT bits are ignored
+            assert_eq!(PpuMode::from_stat(0xFC | mode.to_stat()), mode);
+        }
+        assert_eq!(PpuMode::OamSearch.to_stat(), 2);
+        assert_eq!(PpuMode::PixelTransfer.to_stat(), 3);
+
+        let mut ppu = Ppu::new_headless();
+        for _ in 0..20 {
+            ppu.update();
+        }
+        assert_eq!(ppu.mode(), PpuMode::PixelTransfer);
+        assert_eq!(PpuMode::from_stat(ppu.read(0xFF41).unwrap()), ppu.mode());
+    }
+
+    #[test]
+    fn test_frame_dirty() {
+        let mut ppu = Ppu::new_headless();
+        ppu.write(0xFF40, 0x93);
+        ppu.write(0xFF48, 0xE4);
+        hide_sprites(&mut ppu);
+
+        // First frame paints the blank buffer white
+        run_frame(&mut ppu);
+        assert!(ppu.frame_dirty());
+        run_frame(&mut ppu);
+        assert!(!ppu.frame_dirty());
+
+        // Black tile 1 as sprite 0 at the top left corner
+        for i in 0..16 {
+            ppu.write_vram(0x8010 + i, 0xFF);
+        }
+        ppu.write_sprite_mem(0xFE00, 16);
+        ppu.write_sprite_mem(0xFE01, 8);
+        ppu.write_sprite_mem(0xFE02, 1);
+        run_frame(&mut ppu);
+        assert!(ppu.frame_dirty());
+        run_frame(&mut ppu);
+        assert!(!ppu.frame_dirty());
+    }
+
+    #[test]
+    fn test_8x16_sprite() {
+        for &renderer in [RendererMode::Scanline, RendererMode::Fifo].iter() {
+            let mut ppu = Ppu::new_headless();
+            ppu.set_renderer(renderer);
+            // 8x16 sprites on
+            ppu.write(0xFF40, 0x97);
+            ppu.write(0xFF48, 0xE4);
+            hide_sprites(&mut ppu);
+            // Tile 2 black on top, tile 3 light gray below
+            ppu.load_vram(0x0020, &[0xFF; 16]);
+            ppu.load_vram(0x0030, &[0xFF, 0x00].repeat(8));
+            // Odd tile number still starts from tile 2
+            ppu.load_oam(0, &[16, 8 + 16, 3, 0]);
+            run_line(&mut ppu, 15);
+
+            let black = Palette::GRAYSCALE.color(3);
+            let light = Palette::GRAYSCALE.color(1);
+            let white = Palette::GRAYSCALE.color(0);
+            for ly in 0..8 {
+                assert_eq!(&line_pixels(&ppu, ly)[16..24], &[black; 8]);
+            }
+            for ly in 8..16 {
+                assert_eq!(&line_pixels(&ppu, ly)[16..24], &[light; 8]);
+            }
+            run_line(&mut ppu, 16);
+            assert_eq!(&line_pixels(&ppu, 16)[16..24], &[white; 8]);
+        }
+    }
+
+    #[test]
+    fn test_sprite_flips() {
+        for &renderer in [RendererMode::Scanline, RendererMode::Fifo].iter() {
+            let mut ppu = Ppu::new_headless();
+            ppu.set_renderer(renderer);
+            ppu.write(0xFF40, 0x93);
+            ppu.write(0xFF48, 0xE4);
+            hide_sprites(&mut ppu);
+            // Tile 1: top row colors 3 2 1 0 0 0 0 1, other rows 3 0 0 0 0 0 0 0,
+            // bottom row 0 0 0 0 0 0 0 2
+            let mut tile = [0x80, 0x80].repeat(8);
+            tile[0..2].copy_from_slice(&[0xA1, 0xC0]);
+            tile[14..16].copy_from_slice(&[0x00, 0x01]);
+            ppu.load_vram(0x0010, &tile);
+            // Plain, x flipped, y flipped, both
+            ppu.load_oam(0, &[16, 8, 1, 0]);
+            ppu.load_oam(4, &[16, 8 + 16, 1, 0x20]);
+            ppu.load_oam(8, &[16, 8 + 32, 1, 0x40]);
+            ppu.load_oam(12, &[16, 8 + 48, 1, 0x60]);
+            run_line(&mut ppu, 0);
+
+            let c = |color| Palette::GRAYSCALE.color(color);
+            let line = line_pixels(&ppu, 0);
+            assert_eq!(
+                &line[0..8],
+                &[c(3), c(2), c(1), c(0), c(0), c(0), c(0), c(1)]
+            );
+            assert_eq!(
+                &line[16..24],
+                &[c(1), c(0), c(0), c(0), c(0), c(1), c(2), c(3)]
+            );
+            assert_eq!(
+                &line[32..40],
+                &[c(0), c(0), c(0), c(0), c(0), c(0), c(0), c(2)]
+            );
+            assert_eq!(
+                &line[48..56],
+                &[c(2), c(0), c(0), c(0), c(0), c(0), c(0), c(0)]
+            );
+        }
+    }
+
+    #[test]
+    fn test_sprite_palettes() {
+        for &renderer in [RendererMode::Scanline, RendererMode::Fifo].iter() {
+            let mut ppu = Ppu::new_headless();
+            ppu.set_renderer(renderer);
+            ppu.write(0xFF40, 0x93);
+            // Color 3 is light gray in OBP0 and dark gray in OBP1
+            ppu.write(0xFF48, 0x40);
+            ppu.write(0xFF49, 0x80);
+            hide_sprites(&mut ppu);
+            ppu.load_vram(0x0010, &[0xFF; 16]);
+            ppu.load_oam(0, &[16, 8, 1, 0]);
+            ppu.load_oam(4, &[16, 8 + 16, 1, 0x10]);
+            run_line(&mut ppu, 0);
+
+            assert_eq!(
+                &line_pixels(&ppu, 0)[0..8],
+                &[Palette::GRAYSCALE.color(1); 8]
+            );
+            assert_eq!(
+                &line_pixels(&ppu, 0)[16..24],
+                &[Palette::GRAYSCALE.color(2); 8]
+            );
+        }
+    }
+
+    #[test]
+    fn test_debug_sprite_overflow() {
+        let mut ppu = Ppu::new_headless();
+        ppu.write(0xFF40, 0x93);
+        ppu.set_debug_sprite_overflow(true);
+        hide_sprites(&mut ppu);
+        // 12 sprites on lines 8-15, 10 on lines 16-23
+        for i in 0..10 {
+            ppu.load_oam(i * 4, &[16 + 8, 8 + 16 + i as u8 * 12, 0, 0]);
+        }
+        ppu.load_oam(40, &[16 + 8, 8, 0, 0]);
+        ppu.load_oam(44, &[16 + 8, 8, 0, 0]);
+        for i in 0..10 {
+            ppu.load_oam(48 + i * 4, &[16 + 16, 8, 0, 0]);
+        }
+        run_line(&mut ppu, 16);
+
+        assert_ne!(line_pixels(&ppu, 0)[0], OVERFLOW_MARKER_COLOR);
+        assert_eq!(line_pixels(&ppu, 8)[0], OVERFLOW_MARKER_COLOR);
+        assert_eq!(line_pixels(&ppu, 15)[1], OVERFLOW_MARKER_COLOR);
+        assert_ne!(line_pixels(&ppu, 15)[2], OVERFLOW_MARKER_COLOR);
+        assert_ne!(line_pixels(&ppu, 16)[0], OVERFLOW_MARKER_COLOR);
+    }
+
+    #[test]
+    fn test_window_layer() {
+        for &renderer in [RendererMode::Scanline, RendererMode::Fifo].iter() {
+            let mut ppu = Ppu::new_headless();
+            ppu.set_renderer(renderer);
+            // Window on, using the map at 0x9C00
+            ppu.write(0xFF40, 0xF3);
+            ppu.write(0xFF47, 0xE4);
+            hide_sprites(&mut ppu);
+            // Tile 1 black, tile 2 light gray
+            ppu.load_vram(0x0010, &[0xFF; 16]);
+            ppu.load_vram(0x0020, &[0xFF, 0x00].repeat(8));
+            // Window tile rows 0 and 1 start with them
+            ppu.load_vram(0x1C00, &[1]);
+            ppu.load_vram(0x1C20, &[2]);
+            ppu.write(0xFF4A, 16);
+            ppu.write(0xFF4B, 7 + 40);
+            run_line(&mut ppu, 17);
+            // Moved off the screen for 4 lines, the window rows continue after
+            ppu.write(0xFF4B, 200);
+            run_line(&mut ppu, 21);
+            ppu.write(0xFF4B, 7 + 40);
+            run_line(&mut ppu, 28);
+
+            let black = Palette::GRAYSCALE.color(3);
+            let light = Palette::GRAYSCALE.color(1);
+            let white = Palette::GRAYSCALE.color(0);
+            assert_eq!(&line_pixels(&ppu, 15)[40..48], &[white; 8]);
+            assert_eq!(&line_pixels(&ppu, 16)[32..40], &[white; 8]);
+            assert_eq!(&line_pixels(&ppu, 16)[40..48], &[black; 8]);
+            assert_eq!(&line_pixels(&ppu, 16)[48..56], &[white; 8]);
+            assert_eq!(&line_pixels(&ppu, 20)[40..48], &[white; 8]);
+            assert_eq!(&line_pixels(&ppu, 27)[40..48], &[black; 8]);
+            assert_eq!(&line_pixels(&ppu, 28)[40..48], &[light; 8]);
+        }
+    }
+
+    #[test]
+    fn test_window_line_in_save_state() {
+        // Window tile row 0 black, row 1 light gray from line 16
+        let window_ppu = || {
+            let mut ppu = Ppu::new_headless();
+            ppu.write(0xFF40, 0xF3);
+            ppu.write(0xFF47, 0xE4);
+            hide_sprites(&mut ppu);
+            ppu.load_vram(0x0010, &[0xFF; 16]);
+            ppu.load_vram(0x0020, &[0xFF, 0x00].repeat(8));
+            ppu.load_vram(0x1C00, &[1]);
+            ppu.load_vram(0x1C20, &[2]);
+            ppu.write(0xFF4A, 16);
+            ppu.write(0xFF4B, 7 + 40);
+            ppu
+        };
+        let mut ppu = window_ppu();
+        run_line(&mut ppu, 20);
+        let mut w = StateWriter::new();
+        ppu.save_state(&mut w);
+        let state = w.into_vec();
+        run_line(&mut ppu, 143);
+
+        // Same lines up to the save, but further into the window when loading
+        let mut loaded = window_ppu();
+        run_line(&mut loaded, 40);
+        loaded.load_state(&mut StateReader::new(&state)).unwrap();
+        run_line(&mut loaded, 143);
+        assert_eq!(loaded.frame_hash(), ppu.frame_hash());
+    }
+
+    #[test]
+    fn test_lyc_coincidence_flag() {
+        let mut ppu = Ppu::new_headless();
+        ppu.write(0xFF40, 0x93);
+        hide_sprites(&mut ppu);
+        ppu.write(0xFF45, 3);
+        let coincidence = |ppu: &Ppu| ppu.read(0xFF41).unwrap() & (1 << 2) > 0;
+        for ly in 0..6 {
+            run_line(&mut ppu, ly);
+            assert_eq!(coincidence(&ppu), ly == 3, "ly {}", ly);
+        }
+
+        // Writing LYC updates it without waiting for the next line
+        ppu.write(0xFF45, 5);
+        assert!(coincidence(&ppu));
+        ppu.write(0xFF45, 6);
+        assert!(!coincidence(&ppu));
+    }
+
+    #[test]
+    fn test_last_bg_map_entry_drawn() {
+        let mut ppu = Ppu::new_headless();
+        ppu.write(0xFF40, 0x93);
+        ppu.write(0xFF47, 0xE4);
+        hide_sprites(&mut ppu);
+        ppu.write_vram(0x8010, 0xFF);
+        ppu.write_vram(0x9BFF, 1);
+        // Scrolled so the bottom right map entry is at the top left
+        ppu.write(0xFF42, 248);
+        ppu.write(0xFF43, 248);
+        run_line(&mut ppu, 0);
+        assert_eq!(
+            &line_pixels(&ppu, 0)[0..8],
+            &[Palette::GRAYSCALE.color(1); 8]
+        );
+        // Wraps around to the first column
+        assert_eq!(
+            &line_pixels(&ppu, 0)[8..16],
+            &[Palette::GRAYSCALE.color(0); 8]
+        );
+    }
+
+    #[test]
+    fn test_mid_frame_scroll() {
+        let mut ppu = Ppu::new_headless();
+        ppu.write(0xFF40, 0x93);
+        ppu.write(0xFF47, 0xE4);
+        hide_sprites(&mut ppu);
+        // Tile 1 black, on map row 0. Tile 2 light gray, on map row 4
+        ppu.load_vram(0x0010, &[0xFF; 16]);
+        ppu.load_vram(0x0020, &[0xFF, 0x00].repeat(8));
+        ppu.load_vram(0x1800, &[1]);
+        ppu.load_vram(0x1880, &[2]);
+        run_line(&mut ppu, 0);
+        // Line 1 shows map row 4 once scrolled down by 31
+        ppu.write(0xFF42, 31);
+        run_line(&mut ppu, 1);
+        // And palette changes take effect on the next line too
+        ppu.write(0xFF47, 0x1B);
+        run_line(&mut ppu, 2);
+
+        assert_eq!(line_pixels(&ppu, 0)[0], Palette::GRAYSCALE.color(3));
+        assert_eq!(line_pixels(&ppu, 1)[0], Palette::GRAYSCALE.color(1));
+        assert_eq!(line_pixels(&ppu, 2)[0], Palette::GRAYSCALE.color(2));
+        assert_eq!(line_pixels(&ppu, 2)[8], Palette::GRAYSCALE.color(3));
+    }
+
+    #[test]
+    fn test_strict_oam_reads() {
+        let mut ppu = Ppu::new_headless();
+        ppu.write(0xFF40, 0x93);
+        ppu.load_oam(0, &[0x42]);
+        ppu.set_strict_access(true);
+        // Starts in OAM search
+        assert_eq!(ppu.mode(), PpuMode::OamSearch);
+        assert_eq!(ppu.read_sprite_mem(0xFE00), 0xFF);
+        ppu.write_sprite_mem(0xFE00, 0x10);
+
+        run_line(&mut ppu, 0);
+        assert_eq!(ppu.read_sprite_mem(0xFE00), 0x42);
+
+        ppu.set_strict_access(false);
+        ppu.set_line(0);
+        while ppu.mode() != PpuMode::OamSearch {
+            ppu.update();
+        }
+        assert_eq!(ppu.read_sprite_mem(0xFE00), 0x42);
+    }
+
+    #[test]
+    fn test_bg_enable() {
+        for &renderer in [RendererMode::Scanline, RendererMode::Fifo].iter() {
+            let mut ppu = Ppu::new_headless();
+            ppu.set_renderer(renderer);
+            ppu.write(0xFF40, 0x93);
+            ppu.write(0xFF47, 0xE4);
+            ppu.write(0xFF48, 0xE4);
+            hide_sprites(&mut ppu);
+            // Black background everywhere, one black sprite
+            ppu.load_vram(0x0010, &[0xFF; 16]);
+            ppu.load_vram(0x1800, &[1; 32]);
+            ppu.load_oam(0, &[16, 8 + 40, 1, 0]);
+            run_line(&mut ppu, 0);
+            // Background off
+            ppu.write(0xFF40, 0x92);
+            run_line(&mut ppu, 1);
+
+            let black = Palette::GRAYSCALE.color(3);
+            let white = Palette::GRAYSCALE.color(0);
+            assert_eq!(&line_pixels(&ppu, 0)[0..8], &[black; 8]);
+            assert_eq!(&line_pixels(&ppu, 1)[0..40], &[white; 40][..]);
+            assert_eq!(&line_pixels(&ppu, 1)[40..48], &[black; 8]);
+            assert_eq!(&line_pixels(&ppu, 1)[48..], &[white; 112][..]);
+        }
+    }
+
+    #[test]
+    fn test_overlapping_sprites_over_limit() {
+        for &renderer in [RendererMode::Scanline, RendererMode::Fifo].iter() {
+            let mut ppu = Ppu::new_headless();
+            ppu.set_renderer(renderer);
+            ppu.write(0xFF40, 0x93);
+            ppu.write(0xFF48, 0xE4);
+            hide_sprites(&mut ppu);
+            // Tile 1 black, tile 2 light gray, tile 3 dark gray
+            ppu.load_vram(0x0010, &[0xFF; 16]);
+            ppu.load_vram(0x0020, &[0xFF, 0x00].repeat(8));
+            ppu.load_vram(0x0030, &[0x00, 0xFF].repeat(8));
+            // 9 light sprites stacked from x 40
+            for i in 0..9 {
+                ppu.load_oam(i * 4, &[16, 8 + 40 + i as u8, 2, 0]);
+            }
+            // 10th has the lowest x of the drawn ones, so it's on top
+            ppu.load_oam(36, &[16, 8 + 39, 3, 0]);
+            // 11th and 12th are further left, but past the limit
+            ppu.load_oam(40, &[16, 8 + 30, 1, 0]);
+            ppu.load_oam(44, &[16, 8 + 31, 1, 0]);
+            run_line(&mut ppu, 0);
+
+            let white = Palette::GRAYSCALE.color(0);
+            let light = Palette::GRAYSCALE.color(1);
+            let dark = Palette::GRAYSCALE.color(2);
+            let line = line_pixels(&ppu, 0);
+            assert_eq!(&line[30..39], &[white; 9]);
+            assert_eq!(&line[39..47], &[dark; 8]);
+            assert_eq!(&line[47..56], &[light; 9]);
+            assert_eq!(line[56], white);
+        }
+    }
+
+    #[test]
+    fn test_sprite_behind_background() {
+        for &renderer in [RendererMode::Scanline, RendererMode::Fifo].iter() {
+            let mut ppu = Ppu::new_headless();
+            ppu.set_renderer(renderer);
+            ppu.write(0xFF40, 0x93);
+            ppu.write(0xFF47, 0xE4);
+            ppu.write(0xFF48, 0xE4);
+            hide_sprites(&mut ppu);
+            // Tile 1 light gray, tile 2 black. Light background from x 8 to 16
+            ppu.load_vram(0x0010, &[0xFF, 0x00].repeat(8));
+            ppu.load_vram(0x0020, &[0xFF; 16]);
+            ppu.load_vram(0x1800, &[0, 1]);
+            // Black sprites behind the background at x 4 and x 20
+            ppu.load_oam(0, &[16, 8 + 4, 2, 0x80]);
+            ppu.load_oam(4, &[16, 8 + 20, 2, 0x80]);
+            run_line(&mut ppu, 0);
+
+            let white = Palette::GRAYSCALE.color(0);
+            let light = Palette::GRAYSCALE.color(1);
+            let black = Palette::GRAYSCALE.color(3);
+            let line = line_pixels(&ppu, 0);
+            assert_eq!(&line[0..4], &[white; 4]);
+            assert_eq!(&line[4..8], &[black; 4]);
+            assert_eq!(&line[8..16], &[light; 8]);
+            assert_eq!(&line[16..20], &[white; 4]);
+            assert_eq!(&line[20..28], &[black; 8]);
+        }
+    }
+
+    #[test]
+    fn test_lcd_off_resets_ly() {
+        let mut ppu = Ppu::new_headless();
+        run_line(&mut ppu, 50);
+        ppu.write(0xFF40, 0x11);
+        assert_eq!(ppu.read(0xFF44), Some(0));
+        assert_eq!(ppu.mode(), PpuMode::HBlank);
+        assert_eq!(ppu.read(0xFF41).unwrap() & 0b11, 0);
+        // Nothing advances while off, but blank frames are still shown
+        let frame = ppu.frame_count();
+        for _ in 0..FRAME_DOTS / 4 {
+            assert!(!ppu.update());
+        }
+        assert_eq!(ppu.read(0xFF44), Some(0));
+        assert_eq!(ppu.mode(), PpuMode::HBlank);
+        assert_eq!(ppu.frame_count(), frame + 1);
+        let blank = Palette::GRAYSCALE.color(0);
+        assert!(ppu.viewport().iter().all(|&pixel| pixel == blank));
+
+        ppu.write(0xFF40, 0x91);
+        assert_eq!(ppu.mode(), PpuMode::OamSearch);
+        for _ in 0..LINE_DOTS / 4 {
+            ppu.update();
+        }
+        assert_eq!(ppu.read(0xFF44), Some(1));
+    }
+
+    #[test]
+    fn test_framebuffer_rgba() {
+        let mut ppu = Ppu::new_headless();
+        ppu.write(0xFF40, 0x91);
+        ppu.write(0xFF47, 0xE4);
+        // Tile 1 has columns of colors 0, 1, 2, 3 twice
+        ppu.load_vram(0x0010, &[0x55, 0x33].repeat(8));
+        ppu.load_vram(0x1800, &[1]);
+        while !ppu.update() {
+            assert!(!ppu.frame_ready());
+        }
+        assert!(ppu.frame_ready());
+        ppu.update();
+        assert!(!ppu.frame_ready());
+
+        let rgba = ppu.framebuffer_rgba();
+        assert_eq!(rgba.len(), VIEWPORT_WIDTH * VIEWPORT_HEIGHT * 4);
+        assert_eq!(
+            &rgba[..16],
+            &[
+                0xff, 0xff, 0xff, 0xff, 0x83, 0x84, 0x84, 0xff, 0x50, 0x51, 0x51, 0xff, 0, 0, 0,
+                0xff
+            ]
+        );
+        // Second row of pixels
+        let row = VIEWPORT_WIDTH * 4;
+        assert_eq!(&rgba[row + 4..row + 8], &[0x83, 0x84, 0x84, 0xff]);
+        assert_eq!(&rgba[row + 32..row + 36], &[0xff, 0xff, 0xff, 0xff]);
+    }
+
+    #[test]
+    fn test_save_screenshot() {
+        let path = std::env::temp_dir().join("rustboy_test_screenshot.png");
+        // Nothing drawn yet
+        let ppu = Ppu::new_headless();
+        ppu.save_screenshot(&path).unwrap();
+
+        let decoder = png::Decoder::new(fs::File::open(&path).unwrap());
+        let (info, mut reader) = decoder.read_info().unwrap();
+        let mut data = vec![0; info.buffer_size()];
+        reader.next_frame(&mut data).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(info.width as usize, VIEWPORT_WIDTH);
+        assert_eq!(info.height as usize, VIEWPORT_HEIGHT);
+        assert_eq!(data, ppu.framebuffer_rgba());
+    }
+
+    #[test]
+    fn test_set_palette() {
+        let mut ppu = Ppu::new_headless();
+        ppu.write(0xFF40, 0x91);
+        ppu.write(0xFF47, 0xE4);
+        // Color 2 everywhere
+        ppu.load_vram(0x0000, &[0x00, 0xFF].repeat(8));
+        run_line(&mut ppu, 0);
+        ppu.set_palette(Palette::DMG_GREEN);
+        run_line(&mut ppu, 1);
+
+        assert_eq!(line_pixels(&ppu, 0)[0], 0x505151);
+        assert_eq!(line_pixels(&ppu, 1)[0], 0x306230);
+        assert_eq!(Palette::from_name("dmg-green"), Some(Palette::DMG_GREEN));
+        assert_eq!(Palette::from_name("purple"), None);
+    }
+
+    #[test]
+    fn test_fifo_mid_line_writes() {
+        // Left half of the map is tile 0, right half tile 1 in color 3
+        let render = |renderer| {
+            let mut ppu = Ppu::new_headless();
+            ppu.set_renderer(renderer);
+            ppu.write(0xFF47, 0xE4);
+            hide_sprites(&mut ppu);
+            ppu.load_vram(0x0010, &[0xFF; 16]);
+            for row in 0..32 {
+                ppu.load_vram(0x1800 + row * 32 + 16, &[1; 16]);
+            }
+            run_line(&mut ppu, 4);
+            while ppu.mode() != PpuMode::PixelTransfer {
+                ppu.step_dot();
+            }
+            for _ in 0..40 {
+                ppu.step_dot();
+            }
+            // Scroll to the right half and darken color 0
+            ppu.write(0xFF43, 128);
+            ppu.write(0xFF47, 0xE5);
+            while ppu.mode() != PpuMode::HBlank {
+                ppu.step_dot();
+            }
+            line_pixels(&ppu, 5).to_vec()
+        };
+        let white = Palette::GRAYSCALE.color(0);
+        let light = Palette::GRAYSCALE.color(1);
+        let black = Palette::GRAYSCALE.color(3);
+
+        // The start of the line was drawn before the writes
+        let fifo = render(RendererMode::Fifo);
+        assert_eq!(fifo[0], white);
+        assert_eq!(fifo[80], black);
+        assert!(fifo.contains(&light));
+
+        // The scanline renderer draws it all with the last values
+        let scanline = render(RendererMode::Scanline);
+        assert_eq!(scanline[0], black);
+        assert_eq!(scanline[159], light);
+    }
 }
 
 /*
@@ -0,0 +1,92 @@
+use crate::emulator::EmuError;
+
+// Save states start with this and a version number
+pub const STATE_MAGIC: &[u8; 4] = b"RBST";
+pub const STATE_VERSION: u8 = 8;
+
+// Serialized state is a flat list of values in the order they are written.
+// Multi byte values are little endian
+pub struct StateWriter {
+    buf: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn new() -> Self {
+        StateWriter { buf: vec![] }
+    }
+
+    pub fn into_vec(self) -> Vec<u8> {
+        self.buf
+    }
+
+    pub fn u8(&mut self, value: u8) {
+        self.buf.push(value);
+    }
+    pub fn bool(&mut self, value: bool) {
+        self.u8(value as u8);
+    }
+    pub fn u16(&mut self, value: u16) {
+        self.buf.extend_from_slice(&value.to_le_bytes());
+    }
+    pub fn u32(&mut self, value: u32) {
+        self.buf.extend_from_slice(&value.to_le_bytes());
+    }
+    pub fn u64(&mut self, value: u64) {
+        self.buf.extend_from_slice(&value.to_le_bytes());
+    }
+    pub fn bytes(&mut self, data: &[u8]) {
+        self.buf.extend_from_slice(data);
+    }
+}
+
+pub struct StateReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        StateReader { data, pos: 0 }
+    }
+
+    // Everything was read and nothing is left over
+    pub fn finished(&self) -> bool {
+        self.pos == self.data.len()
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], EmuError> {
+        if self.data.len() - self.pos < len {
+            return Err(EmuError::InvalidState("truncated".to_string()));
+        }
+        let ret = &self.data[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(ret)
+    }
+
+    pub fn u8(&mut self) -> Result<u8, EmuError> {
+        Ok(self.take(1)?[0])
+    }
+    pub fn bool(&mut self) -> Result<bool, EmuError> {
+        Ok(self.u8()? != 0)
+    }
+    pub fn u16(&mut self) -> Result<u16, EmuError> {
+        let mut bytes = [0; 2];
+        bytes.copy_from_slice(self.take(2)?);
+        Ok(u16::from_le_bytes(bytes))
+    }
+    pub fn u32(&mut self) -> Result<u32, EmuError> {
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(self.take(4)?);
+        Ok(u32::from_le_bytes(bytes))
+    }
+    pub fn u64(&mut self) -> Result<u64, EmuError> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.take(8)?);
+        Ok(u64::from_le_bytes(bytes))
+    }
+    // Fills the whole slice
+    pub fn bytes(&mut self, out: &mut [u8]) -> Result<(), EmuError> {
+        out.copy_from_slice(self.take(out.len())?);
+        Ok(())
+    }
+}
@@ -0,0 +1,114 @@
+// A tiny rom built in memory to check that a build runs at all.
+// It does some loads, arithmetic, stack and call work and writes the
+// results to the start of WRAM, then loops at DONE_ADDRESS
+use crate::cartridge::Cartridge;
+use crate::emulator::{BootMode, Emulator};
+
+const ROM_SIZE: usize = 0x8000;
+// Checked by the boot rom
+const NINTENDO_LOGO: [u8; 48] = [
+    0xCE, 0xED, 0x66, 0x66, 0xCC, 0x0D, 0x00, 0x0B, 0x03, 0x73, 0x00, 0x83, 0x00, 0x0C, 0x00, 0x0D,
+    0x00, 0x08, 0x11, 0x1F, 0x88, 0x89, 0x00, 0x0E, 0xDC, 0xCC, 0x6E, 0xE6, 0xDD, 0xDD, 0xD9, 0x99,
+    0xBB, 0xBB, 0x67, 0x63, 0x6E, 0x0E, 0xEC, 0xCC, 0xDD, 0xDC, 0x99, 0x9F, 0xBB, 0xB9, 0x33, 0x3E,
+];
+const TITLE: &[u8] = b"SELFTEST";
+
+const PROGRAM_START: usize = 0x150;
+const PROGRAM: [u8; 26] = [
+    0x31, 0xFE, 0xFF, // LD SP, $FFFE
+    0x3E, 0x12, // LD A, $12
+    0xC6, 0x30, // ADD A, $30
+    0x47, // LD B, A
+    0xAF, // XOR A
+    0x3C, // INC A
+    0x4F, // LD C, A
+    0x21, 0x00, 0xC0, // LD HL, $C000
+    0x78, 0x22, // LD A, B; LD (HL+), A
+    0x79, 0x22, // LD A, C; LD (HL+), A
+    0xC5, 0xD1, // PUSH BC; POP DE
+    0xCD, 0x70, 0x01, // CALL $0170
+    0x22, // LD (HL+), A
+    0x2F, 0x22, // CPL; LD (HL+), A
+];
+// JR -2 once everything is written
+pub const DONE_ADDRESS: u16 = 0x16A;
+const SUBROUTINE_START: usize = 0x170;
+const SUBROUTINE: [u8; 3] = [
+    0x7B, 0x82, // LD A, E; ADD A, D
+    0xC9, // RET
+];
+
+pub const SIGNATURE_ADDRESS: u16 = 0xC000;
+pub const SIGNATURE: [u8; 4] = [0x42, 0x01, 0x43, 0xBC];
+
+// Clock cycles the rom gets to reach DONE_ADDRESS
+const MAX_CYCLES: u64 = 100_000;
+
+pub fn rom() -> Vec<u8> {
+    let mut rom = vec![0; ROM_SIZE];
+    // NOP; JP $0150
+    rom[0x100..0x104].copy_from_slice(&[0x00, 0xC3, 0x50, 0x01]);
+    rom[0x104..0x134].copy_from_slice(&NINTENDO_LOGO);
+    rom[0x134..0x134 + TITLE.len()].copy_from_slice(TITLE);
+    // ROM only, 32KB, no RAM
+    rom[0x147] = 0x00;
+    rom[0x148] = 0x00;
+    rom[0x149] = 0x00;
+    rom[0x14D] = rom[0x134..0x14D]
+        .iter()
+        .fold(0u8, |x, &b| x.wrapping_sub(b).wrapping_sub(1));
+
+    let program_end = PROGRAM_START + PROGRAM.len();
+    rom[PROGRAM_START..program_end].copy_from_slice(&PROGRAM);
+    let done = DONE_ADDRESS as usize;
+    rom[done..done + 2].copy_from_slice(&[0x18, 0xFE]);
+    rom[SUBROUTINE_START..SUBROUTINE_START + SUBROUTINE.len()].copy_from_slice(&SUBROUTINE);
+
+    // Sum of every other byte, big endian
+    let checksum = rom.iter().fold(0u16, |sum, &b| sum.wrapping_add(b as u16));
+    rom[0x14E] = (checksum >> 8) as u8;
+    rom[0x14F] = checksum as u8;
+    rom
+}
+
+// Runs the rom without a window. Returns true if it got to the end
+// and left the right signature
+pub fn run() -> bool {
+    let mut emu = Emulator::headless(Cartridge::new(rom()), BootMode::SkipDmg);
+    while emu.cycles() < MAX_CYCLES && emu.cpu.registers()[5].1 != DONE_ADDRESS {
+        emu.step();
+    }
+    emu.cpu.registers()[5].1 == DONE_ADDRESS && signature(&emu) == SIGNATURE
+}
+
+fn signature(emu: &Emulator) -> [u8; 4] {
+    let mut signature = [0; 4];
+    for (i, byte) in signature.iter_mut().enumerate() {
+        *byte = emu.cpu.interconnect.read_pure(SIGNATURE_ADDRESS + i as u16);
+    }
+    signature
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_self_test_rom() {
+        let rom = rom();
+        assert_eq!(PROGRAM_START + PROGRAM.len(), DONE_ADDRESS as usize);
+        let cartridge = Cartridge::new(rom.clone());
+        assert!(cartridge.is_supported());
+        assert!(!cartridge.supports_cgb());
+
+        let mut emu = Emulator::headless(cartridge, BootMode::SkipDmg);
+        while emu.cpu.registers()[5].1 != DONE_ADDRESS {
+            assert!(emu.cycles() < MAX_CYCLES);
+            emu.step();
+        }
+        assert_eq!(signature(&emu), SIGNATURE);
+        // Stack is back where it started
+        assert_eq!(emu.cpu.registers()[4].1, 0xFFFE);
+        assert!(run());
+    }
+}
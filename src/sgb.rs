@@ -0,0 +1,133 @@
+// Super Game Boy packets are sent through P1 one bit at a time.
+// Both select lines low starts a packet, then P14 low is a 0 bit and
+// P15 low is a 1 bit, with both high in between. 16 bytes, low bit first,
+// and a 0 stop bit
+const PACKET_BITS: usize = 128;
+
+// Command names by id, from the top 5 bits of the first byte
+const COMMAND_NAMES: [&str; 0x19] = [
+    "PAL01", "PAL23", "PAL03", "PAL12", "ATTR_BLK", "ATTR_LIN", "ATTR_DIV", "ATTR_CHR", "SOUND",
+    "SOU_TRN", "PAL_SET", "PAL_TRN", "ATRC_EN", "TEST_EN", "ICON_EN", "DATA_SND", "DATA_TRN",
+    "MLT_REQ", "JUMP", "CHR_TRN", "PCT_TRN", "ATTR_TRN", "ATTR_SET", "MASK_EN", "OBJ_TRN",
+];
+
+// Collects packets from P1 writes. Nothing is done with them
+pub struct SgbDecoder {
+    packet: [u8; PACKET_BITS / 8],
+    // Bits received of the current packet, None when not receiving
+    bit: Option<usize>,
+    // Both lines have gone high since the last pulse
+    released: bool,
+    // Packets still coming for the last command
+    packets_left: u8,
+}
+
+impl SgbDecoder {
+    pub fn new() -> Self {
+        SgbDecoder {
+            packet: [0; PACKET_BITS / 8],
+            bit: None,
+            released: false,
+            packets_left: 0,
+        }
+    }
+
+    // Returns the command id when the first packet of a command is complete
+    pub fn write(&mut self, value: u8) -> Option<u8> {
+        let lines = (value >> 4) & 0b11;
+        if lines == 0b11 {
+            self.released = true;
+            return None;
+        }
+        if lines == 0 {
+            self.packet = [0; PACKET_BITS / 8];
+            self.bit = Some(0);
+            self.released = false;
+            return None;
+        }
+        let bit = self.bit?;
+        if !self.released {
+            return None;
+        }
+        self.released = false;
+        // P15 low is a 1
+        let one = lines == 0b01;
+        if bit == PACKET_BITS {
+            self.bit = None;
+            // Stop bit has to be 0
+            if one {
+                return None;
+            }
+            return self.finish_packet();
+        }
+        if one {
+            self.packet[bit / 8] |= 1 << (bit % 8);
+        }
+        self.bit = Some(bit + 1);
+        None
+    }
+
+    fn finish_packet(&mut self) -> Option<u8> {
+        if self.packets_left > 0 {
+            self.packets_left -= 1;
+            return None;
+        }
+        // The low 3 bits count the packets of the command, 0 is treated as 1
+        let length = (self.packet[0] & 0x07).max(1);
+        self.packets_left = length - 1;
+        Some(self.packet[0] >> 3)
+    }
+
+    pub fn packet(&self) -> &[u8] {
+        &self.packet
+    }
+}
+
+pub fn command_name(id: u8) -> &'static str {
+    COMMAND_NAMES.get(id as usize).copied().unwrap_or("Unknown")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // P1 writes sending the bytes as one packet
+    fn packet_writes(bytes: &[u8; 16]) -> Vec<u8> {
+        let mut writes = vec![0x00, 0x30];
+        for i in 0..PACKET_BITS {
+            let bit = bytes[i / 8] >> (i % 8) & 1;
+            writes.push(if bit == 1 { 0x10 } else { 0x20 });
+            writes.push(0x30);
+        }
+        writes.push(0x20);
+        writes.push(0x30);
+        writes
+    }
+
+    fn send(decoder: &mut SgbDecoder, writes: &[u8]) -> Vec<u8> {
+        writes.iter().filter_map(|&w| decoder.write(w)).collect()
+    }
+
+    #[test]
+    fn test_decode_packets() {
+        let mut decoder = SgbDecoder::new();
+        // Normal joypad reads aren't packets
+        assert!(send(&mut decoder, &[0x20, 0x10, 0x30, 0x20, 0x30]).is_empty());
+
+        // MLT_REQ, 1 packet, 2 players
+        let mut mlt_req = [0; 16];
+        mlt_req[0] = 0x11 << 3 | 1;
+        mlt_req[1] = 0x01;
+        assert_eq!(send(&mut decoder, &packet_writes(&mlt_req)), vec![0x11]);
+        assert_eq!(decoder.packet(), &mlt_req);
+        assert_eq!(command_name(0x11), "MLT_REQ");
+
+        // ATTR_BLK in 2 packets is reported once
+        let mut attr_blk = [0; 16];
+        attr_blk[0] = 0x04 << 3 | 2;
+        let mut writes = packet_writes(&attr_blk);
+        writes.extend(packet_writes(&[0xAA; 16]));
+        assert_eq!(send(&mut decoder, &writes), vec![0x04]);
+        assert_eq!(decoder.packet(), &[0xAA; 16]);
+    }
+}
@@ -1,5 +1,20 @@
+use crate::emulator::EmuError;
+use crate::save_state::{StateReader, StateWriter};
+use crate::utils::check_bit;
+use crate::CPU_SPEED;
+
 /*
 
+ FF10 (NR 10)
+ Name - NR 10
+ Contents - Sound Mode 1 register, Sweep register (R/W)
+ Bit 6-4 - Sweep Time, in 1/128 seconds
+ Bit 3 - Sweep Increase/Decrease
+ 0: Addition (frequency increases)
+ 1: Subtraction (frequency decreases)
+ Bit 2-0 - Number of sweep shift (n: 0-7)
+ X(t) = X(t-1) +/- X(t-1)/2^n
+
 Name - NR 52 (Value at reset: $F1-GB, $F0-SGB)
  Contents - Sound on/off (R/W)
 
@@ -101,46 +116,616 @@ Bit 7 - All sound on/off
 
 */
 
+pub const SAMPLE_RATE: u32 = 44100;
+// Stereo samples collected before they are pushed to the sink
+const SINK_CHUNK: usize = 512;
+
+// Where the generated audio goes, e.g. an audio device or a file.
+// Samples are interleaved left, right pairs from 0.0 to 1.0,
+// as the channels only output positive levels
+pub trait AudioSink {
+    fn push_samples(&mut self, samples: &[f32]);
+}
+// Clocks per frame sequencer step, it runs at 512 Hz
+const FRAME_SEQUENCER_CLOCKS: u32 = 8192;
+
+// Which of the 8 steps of a square wave are high, for each NR11 duty
+const DUTY_PATTERNS: [u8; 4] = [0b0000_0001, 0b1000_0001, 0b1000_0111, 0b0111_1110];
+
+// Square wave with a length counter, envelope and sweep.
+// The settings are read from the registers when needed
+#[derive(Debug, Default, Clone)]
+struct SquareChannel {
+    enabled: bool,
+    // Clocks until the next duty step
+    timer: u32,
+    duty_step: u8,
+    // Length counter, turns the channel off when it runs out
+    length: u8,
+    volume: u8,
+    envelope_timer: u8,
+    sweep_enabled: bool,
+    sweep_timer: u8,
+    shadow_frequency: u16,
+}
+
+impl SquareChannel {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bool(self.enabled);
+        w.u32(self.timer);
+        w.u8(self.duty_step);
+        w.u8(self.length);
+        w.u8(self.volume);
+        w.u8(self.envelope_timer);
+        w.bool(self.sweep_enabled);
+        w.u8(self.sweep_timer);
+        w.u16(self.shadow_frequency);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), EmuError> {
+        self.enabled = r.bool()?;
+        self.timer = r.u32()?;
+        self.duty_step = r.u8()?;
+        self.length = r.u8()?;
+        self.volume = r.u8()?;
+        self.envelope_timer = r.u8()?;
+        self.sweep_enabled = r.bool()?;
+        self.sweep_timer = r.u8()?;
+        self.shadow_frequency = r.u16()?;
+        Ok(())
+    }
+
+    fn trigger(&mut self, envelope: u8, frequency: u16) {
+        // Off right away if the DAC is
+        self.enabled = dac_enabled(envelope);
+        if self.length == 0 {
+            self.length = 64;
+        }
+        self.timer = square_period(frequency);
+        self.volume = envelope >> 4;
+        self.envelope_timer = envelope & 0x07;
+    }
+
+    fn step(&mut self, clocks: u32, frequency: u16) {
+        while self.timer <= clocks {
+            self.timer += square_period(frequency);
+            self.duty_step = (self.duty_step + 1) % 8;
+        }
+        self.timer -= clocks;
+    }
+
+    // 256 Hz
+    fn clock_length(&mut self, length_enabled: bool) {
+        if length_enabled && self.length > 0 {
+            self.length -= 1;
+            if self.length == 0 {
+                self.enabled = false;
+            }
+        }
+    }
+
+    // 64 Hz
+    fn clock_envelope(&mut self, envelope: u8) {
+        let period = envelope & 0x07;
+        if period == 0 {
+            return;
+        }
+        self.envelope_timer = self.envelope_timer.saturating_sub(1);
+        if self.envelope_timer > 0 {
+            return;
+        }
+        self.envelope_timer = period;
+        if check_bit(envelope, 3) {
+            if self.volume < 15 {
+                self.volume += 1;
+            }
+        } else if self.volume > 0 {
+            self.volume -= 1;
+        }
+    }
+
+    // Level 0 - 15 of the wave right now
+    fn output(&self, duty: u8) -> u8 {
+        if self.enabled && check_bit(DUTY_PATTERNS[duty as usize], 7 - self.duty_step) {
+            self.volume
+        } else {
+            0
+        }
+    }
+}
+
+// Plays the 32 4-bit samples of wave RAM, high nibble first
+#[derive(Debug, Default, Clone)]
+struct WaveChannel {
+    enabled: bool,
+    // Clocks until the next sample
+    timer: u32,
+    position: u8,
+    length: u16,
+}
+
+impl WaveChannel {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bool(self.enabled);
+        w.u32(self.timer);
+        w.u8(self.position);
+        w.u16(self.length);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), EmuError> {
+        self.enabled = r.bool()?;
+        self.timer = r.u32()?;
+        self.position = r.u8()?;
+        self.length = r.u16()?;
+        Ok(())
+    }
+
+    fn trigger(&mut self, dac_on: bool, frequency: u16) {
+        self.enabled = dac_on;
+        if self.length == 0 {
+            self.length = 256;
+        }
+        self.timer = wave_period(frequency);
+        self.position = 0;
+    }
+
+    fn step(&mut self, clocks: u32, frequency: u16) {
+        while self.timer <= clocks {
+            self.timer += wave_period(frequency);
+            self.position = (self.position + 1) % 32;
+        }
+        self.timer -= clocks;
+    }
+
+    fn clock_length(&mut self, length_enabled: bool) {
+        if length_enabled && self.length > 0 {
+            self.length -= 1;
+            if self.length == 0 {
+                self.enabled = false;
+            }
+        }
+    }
+
+    // NR32 bits 6-5: mute, 100%, 50% or 25%
+    fn output(&self, wave_ram: &[u8; 16], volume_code: u8) -> u8 {
+        if !self.enabled || volume_code == 0 {
+            return 0;
+        }
+        let byte = wave_ram[self.position as usize / 2];
+        let sample = if self.position % 2 == 0 {
+            byte >> 4
+        } else {
+            byte & 0x0F
+        };
+        sample >> (volume_code - 1)
+    }
+}
+
+// Clocks per wave RAM sample. The 32 samples make one wave at 65536/(2048-x) Hz
+fn wave_period(frequency: u16) -> u32 {
+    (2048 - frequency as u32) * 2
+}
+
+// Off when the top 5 bits of the envelope register are 0
+fn dac_enabled(envelope: u8) -> bool {
+    envelope & 0xF8 != 0
+}
+
+// Clocks per duty step. The 8 steps make one wave at 131072/(2048-x) Hz
+fn square_period(frequency: u16) -> u32 {
+    (2048 - frequency as u32) * 4
+}
+
+// What a channel is playing right now, for visualizers
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub struct ChannelState {
+    pub frequency: f32, // Hz
+    pub volume: u8,     // 0-15
+    pub on: bool,
+}
+
 #[allow(non_snake_case)]
-#[derive(Debug)]
 pub struct SoundSubsystem {
+    NR10: u8,
     NR11: u8,
     NR12: u8,
     NR13: u8,
     NR14: u8,
 
+    NR21: u8,
+    NR22: u8,
+    NR23: u8,
+    NR24: u8,
+
+    NR30: u8,
+    NR31: u8,
+    NR32: u8,
+    NR33: u8,
+    NR34: u8,
+
+    NR41: u8,
+    NR42: u8,
+    NR43: u8,
+    NR44: u8,
+
     NR50: u8,
     NR51: u8,
     NR52: u8,
+
+    // Output settings for the user, the emulated registers don't see these
+    master_mute: bool,
+    channels_enabled: [bool; 4],
+
+    channel1: SquareChannel,
+    channel3: WaveChannel,
+    // FF30 - FF3F
+    wave_ram: [u8; 16],
+    // Clocks until the next frame sequencer step, and the step 0 - 7
+    frame_sequencer_timer: u32,
+    frame_sequencer_step: u8,
+    // Counts up by sample_rate every clock, a sample is taken every CPU_SPEED
+    sample_timer: u64,
+    sample_rate: u32,
+    // Interleaved stereo samples waiting for the sink, or for take_samples without one
+    samples: Vec<f32>,
+    sink: Option<Box<dyn AudioSink>>,
 }
 
 impl SoundSubsystem {
     pub fn new() -> Self {
         SoundSubsystem {
+            NR10: 0,
             NR11: 0,
             NR12: 0,
             NR13: 0,
             NR14: 0,
 
+            NR21: 0,
+            NR22: 0,
+            NR23: 0,
+            NR24: 0,
+
+            NR30: 0,
+            NR31: 0,
+            NR32: 0,
+            NR33: 0,
+            NR34: 0,
+
+            NR41: 0,
+            NR42: 0,
+            NR43: 0,
+            NR44: 0,
+
             NR50: 0,
             NR51: 0,
             NR52: 0,
+
+            master_mute: false,
+            channels_enabled: [true; 4],
+
+            channel1: SquareChannel::default(),
+            channel3: WaveChannel::default(),
+            wave_ram: [0; 16],
+            frame_sequencer_timer: FRAME_SEQUENCER_CLOCKS,
+            frame_sequencer_step: 0,
+            sample_timer: 0,
+            sample_rate: SAMPLE_RATE,
+            samples: vec![],
+            sink: None,
+        }
+    }
+
+    pub fn with_sink(sink: Box<dyn AudioSink>, sample_rate: u32) -> Self {
+        let mut sound = SoundSubsystem::new();
+        sound.set_sink(sink, sample_rate);
+        sound
+    }
+
+    pub fn set_sink(&mut self, sink: Box<dyn AudioSink>, sample_rate: u32) {
+        self.sink = Some(sink);
+        self.sample_rate = sample_rate;
+        self.samples.clear();
+    }
+
+    // Register values left behind by the DMG boot rom
+    pub fn post_boot_init(&mut self) {
+        self.NR10 = 0x80;
+        self.NR11 = 0xBF;
+        self.NR12 = 0xF3;
+        self.NR13 = 0xFF;
+        self.NR14 = 0xBF;
+        self.NR21 = 0x3F;
+        self.NR22 = 0x00;
+        self.NR23 = 0xFF;
+        self.NR24 = 0xBF;
+        self.NR30 = 0x7F;
+        self.NR31 = 0xFF;
+        self.NR32 = 0x9F;
+        self.NR33 = 0xFF;
+        self.NR34 = 0xBF;
+        self.NR41 = 0xFF;
+        self.NR42 = 0x00;
+        self.NR43 = 0x00;
+        self.NR44 = 0xBF;
+        self.NR50 = 0x77;
+        self.NR51 = 0xF3;
+        self.NR52 = 0xF1;
+        // The boot sound was played on channel 1
+        self.channel1.trigger(self.NR12, self.channel1_frequency());
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        for &reg in self.registers().iter() {
+            w.u8(reg);
+        }
+        self.channel1.save_state(w);
+        self.channel3.save_state(w);
+        w.bytes(&self.wave_ram);
+        w.u32(self.frame_sequencer_timer);
+        w.u8(self.frame_sequencer_step);
+        w.u64(self.sample_timer);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), EmuError> {
+        self.NR10 = r.u8()?;
+        self.NR11 = r.u8()?;
+        self.NR12 = r.u8()?;
+        self.NR13 = r.u8()?;
+        self.NR14 = r.u8()?;
+        self.NR21 = r.u8()?;
+        self.NR22 = r.u8()?;
+        self.NR23 = r.u8()?;
+        self.NR24 = r.u8()?;
+        self.NR30 = r.u8()?;
+        self.NR31 = r.u8()?;
+        self.NR32 = r.u8()?;
+        self.NR33 = r.u8()?;
+        self.NR34 = r.u8()?;
+        self.NR41 = r.u8()?;
+        self.NR42 = r.u8()?;
+        self.NR43 = r.u8()?;
+        self.NR44 = r.u8()?;
+        self.NR50 = r.u8()?;
+        self.NR51 = r.u8()?;
+        self.NR52 = r.u8()?;
+        self.channel1.load_state(r)?;
+        self.channel3.load_state(r)?;
+        r.bytes(&mut self.wave_ram)?;
+        self.frame_sequencer_timer = r.u32()?;
+        self.frame_sequencer_step = r.u8()?;
+        self.sample_timer = r.u64()?;
+        Ok(())
+    }
+
+    // In save state order
+    fn registers(&self) -> [u8; 21] {
+        [
+            self.NR10, self.NR11, self.NR12, self.NR13, self.NR14, self.NR21, self.NR22, self.NR23,
+            self.NR24, self.NR30, self.NR31, self.NR32, self.NR33, self.NR34, self.NR41, self.NR42,
+            self.NR43, self.NR44, self.NR50, self.NR51, self.NR52,
+        ]
+    }
+
+    // Called once per machine cycle, 4 clock cycles
+    pub fn update(&mut self) {
+        // Powered off the channels stop, but silence is still output
+        // so the sink keeps getting samples at the sample rate
+        if self.powered() {
+            self.channel1.step(4, self.channel1_frequency());
+            self.channel3.step(4, self.channel3_frequency());
+
+            if self.frame_sequencer_timer <= 4 {
+                self.frame_sequencer_timer += FRAME_SEQUENCER_CLOCKS;
+                self.clock_frame_sequencer();
+            }
+            self.frame_sequencer_timer -= 4;
+        }
+
+        self.sample_timer += 4 * self.sample_rate as u64;
+        if self.sample_timer >= CPU_SPEED {
+            self.sample_timer -= CPU_SPEED;
+            let (left, right) = self.stereo_sample();
+            self.samples.push(left);
+            self.samples.push(right);
+            match &mut self.sink {
+                Some(sink) if self.samples.len() >= SINK_CHUNK * 2 => {
+                    sink.push_samples(&self.samples);
+                    self.samples.clear();
+                }
+                Some(_) => {}
+                // Nobody takes them, keep only the last second
+                None => {
+                    let max = self.sample_rate as usize * 2;
+                    if self.samples.len() > max {
+                        self.samples.drain(..max / 2);
+                    }
+                }
+            }
+        }
+    }
+
+    // NR51 picks the channels for each side, NR50 bits 6-4 and 2-0 set
+    // the left and right volume. All 4 channels at full volume is 1.0
+    fn stereo_sample(&self) -> (f32, f32) {
+        let outputs = self.channel_outputs();
+        let side = |enabled: u8, volume: u8| {
+            let sum: u32 = (0..4)
+                .filter(|&i| check_bit(enabled, i))
+                .map(|i| outputs[i as usize] as u32)
+                .sum();
+            sum as f32 / 60.0 * (volume + 1) as f32 / 8.0
+        };
+        (
+            side(self.NR51 >> 4, (self.NR50 >> 4) & 0x07),
+            side(self.NR51 & 0x0F, self.NR50 & 0x07),
+        )
+    }
+
+    // Samples generated since the last call, when there is no sink
+    pub fn take_samples(&mut self) -> Vec<f32> {
+        if self.sink.is_some() {
+            return vec![];
+        }
+        std::mem::replace(&mut self.samples, vec![])
+    }
+
+    // Length counters on even steps, sweep on 2 and 6, envelopes on 7
+    fn clock_frame_sequencer(&mut self) {
+        let step = self.frame_sequencer_step;
+        self.frame_sequencer_step = (step + 1) % 8;
+        if step % 2 == 0 {
+            self.channel1.clock_length(check_bit(self.NR14, 6));
+            self.channel3.clock_length(check_bit(self.NR34, 6));
+        }
+        if step == 2 || step == 6 {
+            self.clock_sweep();
+        }
+        if step == 7 {
+            self.channel1.clock_envelope(self.NR12);
         }
     }
 
+    // 128 Hz
+    fn clock_sweep(&mut self) {
+        self.channel1.sweep_timer = self.channel1.sweep_timer.saturating_sub(1);
+        if self.channel1.sweep_timer > 0 {
+            return;
+        }
+        self.channel1.sweep_timer = self.sweep_period();
+        if !self.channel1.sweep_enabled || (self.NR10 >> 4) & 0x07 == 0 {
+            return;
+        }
+        let frequency = self.next_sweep_frequency();
+        if frequency <= 2047 && self.NR10 & 0x07 != 0 {
+            self.channel1.shadow_frequency = frequency;
+            self.NR13 = frequency as u8;
+            self.NR14 = (self.NR14 & !0x07) | (frequency >> 8) as u8;
+            // Checked again with the new frequency, but not written back
+            self.next_sweep_frequency();
+        }
+    }
+
+    // Period 0 is treated as 8
+    fn sweep_period(&self) -> u8 {
+        match (self.NR10 >> 4) & 0x07 {
+            0 => 8,
+            period => period,
+        }
+    }
+
+    // Shadow frequency changed by one sweep step.
+    // Going over 2047 turns the channel off
+    fn next_sweep_frequency(&mut self) -> u16 {
+        let shadow = self.channel1.shadow_frequency;
+        let change = shadow >> (self.NR10 & 0x07);
+        let frequency = if check_bit(self.NR10, 3) {
+            shadow - change
+        } else {
+            shadow + change
+        };
+        if frequency > 2047 {
+            self.channel1.enabled = false;
+        }
+        frequency
+    }
+
+    fn trigger_channel1(&mut self) {
+        let frequency = self.channel1_frequency();
+        self.channel1.trigger(self.NR12, frequency);
+        self.channel1.shadow_frequency = frequency;
+        self.channel1.sweep_timer = self.sweep_period();
+        let shift = self.NR10 & 0x07;
+        self.channel1.sweep_enabled = (self.NR10 >> 4) & 0x07 != 0 || shift != 0;
+        if shift != 0 {
+            self.next_sweep_frequency();
+        }
+    }
+
+    fn channel1_frequency(&self) -> u16 {
+        self.NR13 as u16 | ((self.NR14 as u16 & 0x07) << 8)
+    }
+
+    fn channel3_frequency(&self) -> u16 {
+        self.NR33 as u16 | ((self.NR34 as u16 & 0x07) << 8)
+    }
+
+    fn powered(&self) -> bool {
+        check_bit(self.NR52, 7)
+    }
+
     pub fn write(&mut self, address: u16, value: u8) -> bool {
         match address {
+            0xFF10 => {
+                self.NR10 = value;
+            }
             0xFF11 => {
                 self.NR11 = value;
+                self.channel1.length = 64 - (value & 0x3F);
             }
             0xFF12 => {
                 self.NR12 = value;
+                if !dac_enabled(value) {
+                    self.channel1.enabled = false;
+                }
             }
             0xFF13 => {
                 self.NR13 = value;
             }
             0xFF14 => {
                 self.NR14 = value;
+                if check_bit(value, 7) {
+                    self.trigger_channel1();
+                }
+            }
+            // Unused registers, writes are ignored
+            0xFF15 | 0xFF1F => {}
+            0xFF16 => {
+                self.NR21 = value;
+            }
+            0xFF17 => {
+                self.NR22 = value;
+            }
+            0xFF18 => {
+                self.NR23 = value;
+            }
+            0xFF19 => {
+                self.NR24 = value;
+            }
+            0xFF1A => {
+                self.NR30 = value;
+                // DAC off
+                if !check_bit(value, 7) {
+                    self.channel3.enabled = false;
+                }
+            }
+            0xFF1B => {
+                self.NR31 = value;
+                self.channel3.length = 256 - value as u16;
+            }
+            0xFF1C => {
+                self.NR32 = value;
+            }
+            0xFF1D => {
+                self.NR33 = value;
+            }
+            0xFF1E => {
+                self.NR34 = value;
+                if check_bit(value, 7) {
+                    let frequency = self.channel3_frequency();
+                    self.channel3.trigger(check_bit(self.NR30, 7), frequency);
+                }
+            }
+            0xFF20 => {
+                self.NR41 = value;
+            }
+            0xFF21 => {
+                self.NR42 = value;
+            }
+            0xFF22 => {
+                self.NR43 = value;
+            }
+            0xFF23 => {
+                self.NR44 = value;
             }
             0xFF24 => {
                 self.NR50 = value;
@@ -149,24 +734,385 @@ impl SoundSubsystem {
                 self.NR51 = value;
             }
             0xFF26 => {
-                self.NR52 = value;
+                // Only the power bit can be written, the rest are the channel flags
+                self.NR52 = value & 0x80;
+                if !self.powered() {
+                    self.channel1.enabled = false;
+                    self.channel3.enabled = false;
+                }
+            }
+            0xFF30..=0xFF3F => {
+                self.wave_ram[(address - 0xFF30) as usize] = value;
             }
             _ => return false,
         }
         return true;
     }
 
+    // Sounds 2 and 4 aren't emulated and report as off
+    pub fn channel_state(&self) -> [ChannelState; 4] {
+        let mut channels = [ChannelState::default(); 4];
+        channels[0] = ChannelState {
+            frequency: 131072.0 / (2048 - self.channel1_frequency()) as f32,
+            volume: self.channel1.volume,
+            on: self.powered() && self.channel1.enabled,
+        };
+        // Volume is the loudest sample possible with the NR32 shift
+        let volume_code = (self.NR32 >> 5) & 0x03;
+        channels[2] = ChannelState {
+            frequency: 65536.0 / (2048 - self.channel3_frequency()) as f32,
+            volume: if volume_code == 0 {
+                0
+            } else {
+                15 >> (volume_code - 1)
+            },
+            on: self.powered() && self.channel3.enabled,
+        };
+        channels
+    }
+
+    pub fn set_master_mute(&mut self, mute: bool) {
+        self.master_mute = mute;
+    }
+
+    // Channel 0 is sound 1
+    pub fn set_channel_enabled(&mut self, channel: usize, enabled: bool) {
+        self.channels_enabled[channel] = enabled;
+    }
+
+    // Level each channel outputs right now, after muting
+    pub fn channel_outputs(&self) -> [u8; 4] {
+        let mut outputs = [0; 4];
+        if self.master_mute {
+            return outputs;
+        }
+        let levels = [
+            self.channel1.output(self.NR11 >> 6),
+            0,
+            self.channel3
+                .output(&self.wave_ram, (self.NR32 >> 5) & 0x03),
+            0,
+        ];
+        for (i, channel) in self.channel_state().iter().enumerate() {
+            if channel.on && self.channels_enabled[i] {
+                outputs[i] = levels[i];
+            }
+        }
+        outputs
+    }
+
+    // All the channels mixed together, 0 - 60
+    pub fn mix(&self) -> u8 {
+        self.channel_outputs().iter().sum()
+    }
+
     pub fn read(&self, address: u16) -> Option<u8> {
         match address {
-            0xFF11 => Some(self.NR11),
+            // Write only bits read as 1
+            0xFF10 => Some(self.NR10 | 0x80),
+            0xFF11 => Some(self.NR11 | 0x3F),
             0xFF12 => Some(self.NR12),
-            0xFF13 => Some(self.NR13),
-            0xFF14 => Some(self.NR14),
+            0xFF13 => Some(0xFF),
+            0xFF14 => Some(self.NR14 | 0xBF),
+
+            0xFF15 => Some(0xFF),
+            0xFF16 => Some(self.NR21 | 0x3F),
+            0xFF17 => Some(self.NR22),
+            0xFF18 => Some(0xFF),
+            0xFF19 => Some(self.NR24 | 0xBF),
+
+            0xFF1A => Some(self.NR30 | 0x7F),
+            0xFF1B => Some(0xFF),
+            0xFF1C => Some(self.NR32 | 0x9F),
+            0xFF1D => Some(0xFF),
+            0xFF1E => Some(self.NR34 | 0xBF),
+
+            0xFF1F => Some(0xFF),
+            0xFF20 => Some(0xFF),
+            0xFF21 => Some(self.NR42),
+            0xFF22 => Some(self.NR43),
+            0xFF23 => Some(self.NR44 | 0xBF),
 
             0xFF24 => Some(self.NR50),
             0xFF25 => Some(self.NR51),
-            0xFF26 => Some(self.NR52),
+            // Bits 4-6 are unused
+            0xFF26 => {
+                let channels = self.channel_state();
+                Some(self.NR52 | 0x70 | channels[0].on as u8 | (channels[2].on as u8) << 2)
+            }
+            0xFF30..=0xFF3F => Some(self.wave_ram[(address - 0xFF30) as usize]),
             _ => None,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::{Cell, RefCell};
+    use std::rc::Rc;
+
+    #[test]
+    fn test_channel_1_frequency() {
+        let mut sound = SoundSubsystem::new();
+        sound.post_boot_init();
+        // x = 0x783
+        sound.write(0xFF13, 0x83);
+        sound.write(0xFF14, 0x87);
+        let channel = sound.channel_state()[0];
+        assert_eq!(channel.frequency, 131072.0 / (2048 - 0x783) as f32);
+        assert_eq!(channel.volume, 0xF);
+        assert!(channel.on);
+        assert!(!sound.channel_state()[1].on);
+    }
+
+    #[test]
+    fn test_mute_channel() {
+        let mut sound = SoundSubsystem::new();
+        sound.post_boot_init();
+        assert_eq!(sound.mix(), 0xF);
+
+        sound.set_channel_enabled(0, false);
+        assert_eq!(sound.channel_outputs()[0], 0);
+        assert_eq!(sound.mix(), 0);
+        // The game still sees the channel playing
+        assert_eq!(sound.read(0xFF26).unwrap() & 0x01, 0x01);
+        assert!(sound.channel_state()[0].on);
+
+        sound.set_channel_enabled(0, true);
+        sound.set_master_mute(true);
+        assert_eq!(sound.mix(), 0);
+    }
+
+    #[test]
+    fn test_read_masks() {
+        let mut sound = SoundSubsystem::new();
+        sound.write(0xFF13, 0x12);
+        assert_eq!(sound.read(0xFF13), Some(0xFF));
+        sound.write(0xFF11, 0x80);
+        assert_eq!(sound.read(0xFF11), Some(0xBF));
+        sound.write(0xFF14, 0x47);
+        assert_eq!(sound.read(0xFF14), Some(0xFF));
+        sound.write(0xFF14, 0x07);
+        assert_eq!(sound.read(0xFF14), Some(0xBF));
+        sound.write(0xFF26, 0x80);
+        assert_eq!(sound.read(0xFF26), Some(0xF0));
+    }
+
+    #[test]
+    fn test_all_register_read_masks() {
+        // Bits that always read as 1, from 0xFF10 to 0xFF26
+        let masks = [
+            0x80, 0x3F, 0x00, 0xFF, 0xBF, // NR10 - NR14
+            0xFF, 0x3F, 0x00, 0xFF, 0xBF, // NR20 - NR24
+            0x7F, 0xFF, 0x9F, 0xFF, 0xBF, // NR30 - NR34
+            0xFF, 0xFF, 0x00, 0x00, 0xBF, // NR40 - NR44
+            0x00, 0x00, 0x70, // NR50 - NR52
+        ];
+        for &value in &[0x00, 0xFF, 0x5A] {
+            let mut sound = SoundSubsystem::new();
+            for (i, &mask) in masks.iter().enumerate() {
+                let address = 0xFF10 + i as u16;
+                // Don't trigger anything
+                let value = if mask == 0xBF { value & 0x7F } else { value };
+                assert!(sound.write(address, value));
+                // NR52 only keeps the power bit and reports the channels
+                let expected = if address == 0xFF26 {
+                    value & 0x80 | mask
+                } else {
+                    value | mask
+                };
+                assert_eq!(
+                    sound.read(address),
+                    Some(expected),
+                    "0x{:04x} = 0x{:02x}",
+                    address,
+                    value
+                );
+            }
+        }
+    }
+
+    fn run_seconds(sound: &mut SoundSubsystem, seconds: f64) {
+        for _ in 0..(CPU_SPEED as f64 * seconds / 4.0) as u64 {
+            sound.update();
+        }
+    }
+
+    #[test]
+    fn test_channel_1_square_wave() {
+        let mut sound = SoundSubsystem::new();
+        sound.write(0xFF26, 0x80);
+        // Sound 1 on the left at full volume
+        sound.write(0xFF24, 0x77);
+        sound.write(0xFF25, 0x10);
+        // 50% duty, full volume without envelope, x = 1750, about 440 Hz
+        sound.write(0xFF11, 0x80);
+        sound.write(0xFF12, 0xF0);
+        sound.write(0xFF13, (1750 & 0xFF) as u8);
+        sound.write(0xFF14, 0x80 | (1750 >> 8) as u8);
+        run_seconds(&mut sound, 1.0);
+
+        let stereo = sound.take_samples();
+        assert_eq!(stereo.len(), SAMPLE_RATE as usize * 2);
+        assert!(stereo.iter().skip(1).step_by(2).all(|&s| s == 0.0));
+        let samples: Vec<f32> = stereo.into_iter().step_by(2).collect();
+        assert!(samples.iter().all(|&s| s == 0.0 || s == 0.25));
+        let highs = samples.iter().filter(|&&s| s > 0.0).count();
+        assert!((highs as i32 - SAMPLE_RATE as i32 / 2).abs() < 500);
+        // One rising edge per wave
+        let rising = samples
+            .windows(2)
+            .filter(|w| w[0] == 0.0 && w[1] > 0.0)
+            .count();
+        let frequency = 131072.0 / (2048 - 1750) as f64;
+        assert!((rising as f64 - frequency).abs() <= 1.0);
+        assert!(sound.take_samples().is_empty());
+    }
+
+    #[test]
+    fn test_channel_1_length_and_envelope() {
+        let mut sound = SoundSubsystem::new();
+        sound.write(0xFF26, 0x80);
+        // Length 32, 1/8 second. Envelope going down a step every 1/64 seconds
+        sound.write(0xFF11, 0x80 | 32);
+        sound.write(0xFF12, 0xF1);
+        sound.write(0xFF14, 0xC0);
+        assert_eq!(sound.read(0xFF26).unwrap() & 0x01, 0x01);
+        run_seconds(&mut sound, 0.1);
+        let volume = sound.channel_state()[0].volume;
+        assert!(volume >= 8 && volume <= 10, "volume {}", volume);
+        run_seconds(&mut sound, 0.05);
+        assert!(!sound.channel_state()[0].on);
+        assert_eq!(sound.read(0xFF26).unwrap() & 0x01, 0);
+    }
+
+    #[test]
+    fn test_channel_1_sweep() {
+        let mut sound = SoundSubsystem::new();
+        sound.write(0xFF26, 0x80);
+        // Up by x/2 every 1/128 seconds
+        sound.write(0xFF10, 0x11);
+        sound.write(0xFF12, 0xF0);
+        sound.write(0xFF13, 0x00);
+        sound.write(0xFF14, 0x81);
+        run_seconds(&mut sound, 0.01);
+        assert_eq!(sound.channel1_frequency(), 0x180);
+        assert!(sound.channel_state()[0].on);
+        // Goes over 2047 after a few more
+        run_seconds(&mut sound, 0.05);
+        assert!(!sound.channel_state()[0].on);
+    }
+
+    #[test]
+    fn test_channel_3_wave() {
+        let mut sound = SoundSubsystem::new();
+        sound.write(0xFF26, 0x80);
+        // Samples 0 - 15 twice
+        for i in 0..16 {
+            sound.write(0xFF30 + i, 0x01 + 0x22 * (i % 8) as u8);
+        }
+        assert_eq!(sound.read(0xFF31), Some(0x23));
+        assert_eq!(sound.read(0xFF3F), Some(0xEF));
+
+        // DAC on, full volume, x = 2016: 64 clocks per sample
+        sound.write(0xFF1A, 0x80);
+        sound.write(0xFF1C, 0x20);
+        sound.write(0xFF1D, (2016 & 0xFF) as u8);
+        sound.write(0xFF1E, 0x80 | (2016 >> 8) as u8);
+        assert_eq!(sound.read(0xFF26).unwrap() & 0x04, 0x04);
+
+        let mut outputs = vec![];
+        for _ in 0..32 {
+            outputs.push(sound.channel_outputs()[2]);
+            for _ in 0..16 {
+                sound.update();
+            }
+        }
+        let expected: Vec<u8> = (0..32).map(|i| i % 16).collect();
+        assert_eq!(outputs, expected);
+        assert_eq!(sound.channel_state()[2].frequency, 65536.0 / 32.0);
+
+        // 25% shifts the samples right by 2
+        sound.write(0xFF1C, 0x60);
+        for _ in 0..16 * 7 {
+            sound.update();
+        }
+        assert_eq!(sound.channel_outputs()[2], 7 >> 2);
+
+        // DAC off stops the channel
+        sound.write(0xFF1A, 0x00);
+        assert_eq!(sound.read(0xFF26).unwrap() & 0x04, 0);
+        assert_eq!(sound.channel_outputs()[2], 0);
+    }
+
+    struct RecordingSink {
+        samples: Rc<RefCell<Vec<f32>>>,
+        pushes: Rc<Cell<u32>>,
+    }
+
+    impl AudioSink for RecordingSink {
+        fn push_samples(&mut self, samples: &[f32]) {
+            self.samples.borrow_mut().extend_from_slice(samples);
+            self.pushes.set(self.pushes.get() + 1);
+        }
+    }
+
+    #[test]
+    fn test_audio_sink() {
+        let samples = Rc::new(RefCell::new(vec![]));
+        let pushes = Rc::new(Cell::new(0));
+        let sink = RecordingSink {
+            samples: samples.clone(),
+            pushes: pushes.clone(),
+        };
+        let mut sound = SoundSubsystem::with_sink(Box::new(sink), 48000);
+        sound.write(0xFF26, 0x80);
+        // Sound 1 on the right only at half volume
+        sound.write(0xFF24, 0x73);
+        sound.write(0xFF25, 0x01);
+        sound.write(0xFF11, 0x80);
+        sound.write(0xFF12, 0xF0);
+        sound.write(0xFF13, (1750 & 0xFF) as u8);
+        sound.write(0xFF14, 0x80 | (1750 >> 8) as u8);
+
+        // 10 frames
+        for _ in 0..10 * 70224 / 4 {
+            sound.update();
+        }
+        assert!(sound.take_samples().is_empty());
+        assert!(pushes.get() > 1);
+
+        let samples = samples.borrow();
+        let frames = 10.0 * 70224.0 / CPU_SPEED as f64;
+        let expected = (frames * 48000.0) as usize * 2;
+        assert_eq!(samples.len() % 2, 0);
+        assert!(samples.len() <= expected && samples.len() + SINK_CHUNK * 2 > expected);
+        assert!(samples.iter().step_by(2).all(|&s| s == 0.0));
+        assert!(samples
+            .iter()
+            .skip(1)
+            .step_by(2)
+            .all(|&s| s == 0.0 || s == 0.125));
+        assert!(samples.iter().any(|&s| s > 0.0));
+    }
+
+    #[test]
+    fn test_silence_when_powered_off() {
+        let mut sound = SoundSubsystem::new();
+        sound.write(0xFF26, 0x80);
+        sound.write(0xFF24, 0x77);
+        sound.write(0xFF25, 0x11);
+        sound.write(0xFF11, 0x80);
+        sound.write(0xFF12, 0xF0);
+        sound.write(0xFF14, 0x87);
+        run_seconds(&mut sound, 0.5);
+        assert!(sound.take_samples().iter().any(|&s| s > 0.0));
+
+        sound.write(0xFF26, 0x00);
+        run_seconds(&mut sound, 1.0);
+        let samples = sound.take_samples();
+        assert_eq!(samples.len(), SAMPLE_RATE as usize * 2);
+        assert!(samples.iter().all(|&s| s == 0.0));
+    }
+}
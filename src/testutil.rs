@@ -0,0 +1,138 @@
+// Helpers for writing tests
+use std::collections::HashMap;
+
+const REGISTERS: [&str; 8] = ["B", "C", "D", "E", "H", "L", "(HL)", "A"];
+
+// Assembles a program loaded at the cartridge entry point 0x100
+pub fn assemble(src: &str) -> Vec<u8> {
+    assemble_at(src, 0x100)
+}
+
+// Tiny assembler for test programs.
+// Supports NOP, LD r, n / LD r, r, ADD A, n / ADD A, r, JP nn and labels.
+// Statements are separated by newlines or ';'. Numbers are decimal or $hex
+pub fn assemble_at(src: &str, origin: u16) -> Vec<u8> {
+    let mut statements = vec![];
+    let mut labels = HashMap::new();
+
+    // First pass finds the label addresses
+    let mut address = origin;
+    for statement in src.split(['\n', ';']) {
+        let mut statement = statement.trim();
+        if let Some(colon) = statement.find(':') {
+            labels.insert(statement[..colon].trim().to_string(), address);
+            statement = statement[colon + 1..].trim();
+        }
+        if statement.is_empty() {
+            continue;
+        }
+        address += instruction_size(statement);
+        statements.push(statement);
+    }
+
+    let mut out = vec![];
+    for statement in statements {
+        encode(statement, &labels, &mut out);
+    }
+    out
+}
+
+fn split_statement(statement: &str) -> (String, Vec<&str>) {
+    let mut parts = statement.splitn(2, char::is_whitespace);
+    let mnemonic = parts.next().unwrap().to_uppercase();
+    let operands = parts
+        .next()
+        .map(|ops| ops.split(',').map(|o| o.trim()).collect())
+        .unwrap_or_default();
+    (mnemonic, operands)
+}
+
+fn instruction_size(statement: &str) -> u16 {
+    let (mnemonic, operands) = split_statement(statement);
+    match mnemonic.as_str() {
+        "NOP" => 1,
+        "JP" => 3,
+        "LD" | "ADD" => {
+            if register_index(operands[1]).is_some() {
+                1
+            } else {
+                2
+            }
+        }
+        _ => panic!("Unsupported instruction: {}", statement),
+    }
+}
+
+fn encode(statement: &str, labels: &HashMap<String, u16>, out: &mut Vec<u8>) {
+    let (mnemonic, operands) = split_statement(statement);
+    match mnemonic.as_str() {
+        "NOP" => out.push(0x00),
+        "LD" => {
+            let dst = register(operands[0]);
+            match register_index(operands[1]) {
+                Some(src) => out.push(0x40 | dst << 3 | src),
+                None => {
+                    out.push(0x06 | dst << 3);
+                    out.push(number(operands[1]) as u8);
+                }
+            }
+        }
+        "ADD" => {
+            assert_eq!(operands[0].to_uppercase(), "A", "ADD only supports A");
+            match register_index(operands[1]) {
+                Some(src) => out.push(0x80 | src),
+                None => {
+                    out.push(0xC6);
+                    out.push(number(operands[1]) as u8);
+                }
+            }
+        }
+        "JP" => {
+            let address = match labels.get(operands[0]) {
+                Some(&address) => address,
+                None => number(operands[0]),
+            };
+            out.push(0xC3);
+            out.push(address as u8);
+            out.push((address >> 8) as u8);
+        }
+        _ => panic!("Unsupported instruction: {}", statement),
+    }
+}
+
+fn register_index(operand: &str) -> Option<u8> {
+    let operand = operand.to_uppercase();
+    REGISTERS
+        .iter()
+        .position(|&r| r == operand)
+        .map(|i| i as u8)
+}
+
+fn register(operand: &str) -> u8 {
+    register_index(operand).unwrap_or_else(|| panic!("Not a register: {}", operand))
+}
+
+fn number(operand: &str) -> u16 {
+    let result = match operand.strip_prefix('$') {
+        Some(hex) => u16::from_str_radix(hex, 16),
+        None => operand.parse(),
+    };
+    result.unwrap_or_else(|_| panic!("Not a number: {}", operand))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_assemble() {
+        assert_eq!(
+            assemble("start: LD A, $05; ADD A, $03; JP start"),
+            vec![0x3E, 0x05, 0xC6, 0x03, 0xC3, 0x00, 0x01]
+        );
+        assert_eq!(
+            assemble("NOP\nLD B, A\nADD A, B\nloop: JP loop"),
+            vec![0x00, 0x47, 0x80, 0xC3, 0x03, 0x01]
+        );
+    }
+}
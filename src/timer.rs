@@ -1,44 +1,63 @@
-use super::CPU_SPEED;
+use crate::emulator::EmuError;
+use crate::save_state::{StateReader, StateWriter};
 use crate::utils::check_bit;
 
-const DIV_PER_FRAME: u64 = 16384;
-const DIV_CLOCK_PER_CPU: u64 = CPU_SPEED / DIV_PER_FRAME;
-
 pub struct Timer {
-    main: u64,
-    sub: u64,
-    cl_div: u64,
-
-    div: u8,
     tima: u8,
     tma: u8,
     tac: u8,
 
-    div_counter: u64,
-    tima_counter: u64,
+    // Increases every clock cycle, div is the high byte.
+    // Tima increases when the counter bit selected by tac falls
+    counter: u16,
+    // Tima overflowed outside of update, the interrupt is requested on the next one
+    overflow_pending: bool,
 }
 
 impl Timer {
     pub fn new() -> Self {
         Timer {
-            main: 0,
-            sub: 0,
-            cl_div: 0,
-
-            div: 0,
             tima: 0,
             tma: 0,
             tac: 0,
 
-            div_counter: 0,
-            tima_counter: 0,
+            counter: 0,
+            overflow_pending: false,
         }
     }
 
+    // Register values left behind by the DMG boot rom
+    pub fn post_boot_init(&mut self) {
+        self.tima = 0;
+        self.tma = 0;
+        self.tac = 0;
+        // DIV is 0xAB when the boot rom hands over
+        self.counter = 0xABCC;
+        self.overflow_pending = false;
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.u8(self.tima);
+        w.u8(self.tma);
+        w.u8(self.tac);
+        w.u16(self.counter);
+        w.bool(self.overflow_pending);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), EmuError> {
+        self.tima = r.u8()?;
+        self.tma = r.u8()?;
+        self.tac = r.u8()?;
+        self.counter = r.u16()?;
+        self.overflow_pending = r.bool()?;
+        Ok(())
+    }
+
     pub fn write(&mut self, address: u16, value: u8) -> bool {
         match address {
             0xFF04 => {
-                self.div = 0;
+                // Resetting the counter can make the tima bit fall
+                self.set_counter(0);
             }
             0xFF05 => {
                 self.tima = value;
@@ -47,7 +66,13 @@ impl Timer {
                 self.tma = value;
             }
             0xFF07 => {
-                self.tac = value;
+                // Only the low 3 bits exist. Like a div write,
+                // switching the bit off while it's high increases tima
+                let was_high = self.timer_signal();
+                self.tac = value & 0x07;
+                if was_high && !self.timer_signal() {
+                    self.increase_tima();
+                }
             }
             _ => return false,
         }
@@ -56,56 +81,139 @@ impl Timer {
 
     pub fn read(&self, address: u16) -> Option<u8> {
         match address {
-            0xFF04 => Some(self.div),
+            0xFF04 => Some((self.counter >> 8) as u8),
             0xFF05 => Some(self.tima),
             0xFF06 => Some(self.tma),
-            0xFF07 => Some(self.tac),
+            // Unused bits read as 1
+            0xFF07 => Some(self.tac | 0xF8),
             _ => None,
         }
     }
 
+    // Called once per machine cycle, 4 clock cycles.
+    // Returns true when tima overflowed
     pub fn update(&mut self) -> bool {
-        self.sub += 1;
-
-        if self.sub >= 16 {
-            self.main += 1;
-            self.sub -= 16;
+        self.set_counter(self.counter.wrapping_add(4));
+        let overflow = self.overflow_pending;
+        self.overflow_pending = false;
+        overflow
+    }
 
-            // Handle div
-            self.cl_div += 1;
-            if self.cl_div == 16 {
-                self.cl_div = 0;
-                self.div = self.div.wrapping_add(1);
-            }
-        }
-        if !self.timer_enabled() {
-            return false;
+    fn set_counter(&mut self, counter: u16) {
+        let was_high = self.timer_signal();
+        self.counter = counter;
+        if was_high && !self.timer_signal() {
+            self.increase_tima();
         }
+    }
 
-        // Handle tima
-        if self.main >= self.timer_clock() {
-            self.main = 0;
-            if self.tima == 0xFF {
-                self.tima = self.tma;
-                return true;
-            }
+    fn increase_tima(&mut self) {
+        if self.tima == 0xFF {
+            self.tima = self.tma;
+            self.overflow_pending = true;
+        } else {
             self.tima += 1;
         }
+    }
 
-        false
+    // The counter bit tima follows, gated by the enable bit
+    fn timer_signal(&self) -> bool {
+        let bit = self.timer_clock() as u16 / 2;
+        self.timer_enabled() && self.counter & bit > 0
     }
 
     fn timer_enabled(&self) -> bool {
         check_bit(self.tac, 2)
     }
 
+    // Clock cycles between tima increases.
+    // 4096, 262144, 65536 and 16384 Hz
     fn timer_clock(&self) -> u64 {
         match self.tac & 0b11 {
-            0 => 64,
-            1 => 1,
-            2 => 4,
-            3 => 16,
+            0 => 1024,
+            1 => 16,
+            2 => 64,
+            3 => 256,
             _ => unreachable!(),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tac_unused_bits() {
+        let mut timer = Timer::new();
+        timer.write(0xFF07, 0xFF);
+        assert_eq!(timer.read(0xFF07), Some(0xFF));
+        assert_eq!(timer.tac, 0x07);
+        assert_eq!(timer.timer_clock(), 256);
+
+        timer.write(0xFF07, 0x00);
+        assert_eq!(timer.read(0xFF07), Some(0xF8));
+        assert!(!timer.timer_enabled());
+
+        // Bit 3 isn't the enable bit
+        timer.write(0xFF07, 0xF8);
+        assert!(!timer.timer_enabled());
+    }
+
+    #[test]
+    fn test_tac_frequencies() {
+        for &(tac, period) in [(0b100, 1024), (0b101, 16), (0b110, 64), (0b111, 256)].iter() {
+            let mut timer = Timer::new();
+            timer.write(0xFF07, tac);
+            // Clock cycles at each tima increase
+            let mut increases = vec![];
+            let mut clocks = 0;
+            while increases.len() < 3 {
+                timer.update();
+                clocks += 4;
+                if timer.read(0xFF05) != Some(increases.len() as u8) {
+                    increases.push(clocks);
+                }
+            }
+            assert_eq!(
+                increases,
+                vec![period, period * 2, period * 3],
+                "TAC {:b}",
+                tac
+            );
+        }
+    }
+
+    #[test]
+    fn test_div_write_increases_tima() {
+        // 16 clock period, follows bit 3
+        let mut timer = Timer::new();
+        timer.write(0xFF07, 0b101);
+        // Counter 8, bit 3 high
+        timer.update();
+        timer.update();
+        assert_eq!(timer.read(0xFF05), Some(0));
+        timer.write(0xFF04, 0x12);
+        assert_eq!(timer.read(0xFF04), Some(0));
+        assert_eq!(timer.read(0xFF05), Some(1));
+
+        // Counter 4, bit 3 low, no glitch
+        timer.update();
+        timer.write(0xFF04, 0);
+        assert_eq!(timer.read(0xFF05), Some(1));
+        // And counting goes on from 0
+        for _ in 0..4 {
+            timer.update();
+        }
+        assert_eq!(timer.read(0xFF05), Some(2));
+    }
+
+    #[test]
+    fn test_div_from_counter() {
+        let mut timer = Timer::new();
+        for _ in 0..64 * 3 {
+            timer.update();
+        }
+        assert_eq!(timer.read(0xFF04), Some(3));
+    }
+}
@@ -2,3 +2,13 @@
 pub fn check_bit(val: u8, b: u8) -> bool {
     val & (1 << b) > 0
 }
+
+// FNV-1a, stable across runs and platforms
+pub fn fnv1a(data: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for &byte in data {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
@@ -0,0 +1,124 @@
+use crate::sound_subsystem::AudioSink;
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::io::{BufWriter, SeekFrom};
+use std::path::Path;
+
+// Size of the RIFF and fmt chunks before the sample data
+const HEADER_SIZE: u32 = 44;
+
+// Writes the generated audio to a 16-bit stereo wav file.
+// There's no live audio device backend, cpal needs ALSA to build,
+// so this is the output main has. Embedders can supply their own sink.
+// The sizes in the header are filled in when the sink is dropped
+pub struct WavSink {
+    file: BufWriter<File>,
+    // Bytes of sample data written
+    data_len: u32,
+    failed: bool,
+}
+
+impl WavSink {
+    pub fn create<P: AsRef<Path>>(path: P, sample_rate: u32) -> io::Result<Self> {
+        let mut file = BufWriter::new(File::create(path)?);
+        write_header(&mut file, sample_rate)?;
+        Ok(WavSink {
+            file,
+            data_len: 0,
+            failed: false,
+        })
+    }
+
+    // Writes the final sizes to the header
+    pub fn finish(&mut self) -> io::Result<()> {
+        self.file.seek(SeekFrom::Start(4))?;
+        self.file
+            .write_all(&(HEADER_SIZE - 8 + self.data_len).to_le_bytes())?;
+        self.file.seek(SeekFrom::Start(40))?;
+        self.file.write_all(&self.data_len.to_le_bytes())?;
+        self.file.seek(SeekFrom::End(0))?;
+        self.file.flush()
+    }
+
+    fn write_samples(&mut self, samples: &[f32]) -> io::Result<()> {
+        for &sample in samples {
+            let value = (sample.max(-1.0).min(1.0) * i16::MAX as f32) as i16;
+            self.file.write_all(&value.to_le_bytes())?;
+        }
+        self.data_len += samples.len() as u32 * 2;
+        Ok(())
+    }
+}
+
+impl AudioSink for WavSink {
+    fn push_samples(&mut self, samples: &[f32]) {
+        if self.failed {
+            return;
+        }
+        if let Err(e) = self.write_samples(samples) {
+            println!("Couldn't write audio: {}", e);
+            self.failed = true;
+        }
+    }
+}
+
+impl Drop for WavSink {
+    fn drop(&mut self) {
+        if let Err(e) = self.finish() {
+            println!("Couldn't finish audio file: {}", e);
+        }
+    }
+}
+
+// Header for an empty file, the sizes are filled in by finish
+fn write_header<W: Write>(w: &mut W, sample_rate: u32) -> io::Result<()> {
+    let channels = 2u16;
+    let bits = 16u16;
+    let block_align = channels * bits / 8;
+    w.write_all(b"RIFF")?;
+    w.write_all(&(HEADER_SIZE - 8).to_le_bytes())?;
+    w.write_all(b"WAVEfmt ")?;
+    w.write_all(&16u32.to_le_bytes())?;
+    // PCM
+    w.write_all(&1u16.to_le_bytes())?;
+    w.write_all(&channels.to_le_bytes())?;
+    w.write_all(&sample_rate.to_le_bytes())?;
+    w.write_all(&(sample_rate * block_align as u32).to_le_bytes())?;
+    w.write_all(&block_align.to_le_bytes())?;
+    w.write_all(&bits.to_le_bytes())?;
+    w.write_all(b"data")?;
+    w.write_all(&0u32.to_le_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_wav_sink() {
+        let path = std::env::temp_dir().join("rustboy_test_wav_sink.wav");
+        {
+            let mut sink = WavSink::create(&path, 48000).unwrap();
+            sink.push_samples(&[0.0, 1.0, -1.0, 0.5]);
+            sink.push_samples(&[2.0, -2.0]);
+        }
+        let data = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(data.len(), HEADER_SIZE as usize + 12);
+        assert_eq!(&data[..4], b"RIFF");
+        assert_eq!(&data[4..8], &(HEADER_SIZE - 8 + 12).to_le_bytes());
+        assert_eq!(&data[8..16], b"WAVEfmt ");
+        assert_eq!(&data[22..24], &2u16.to_le_bytes());
+        assert_eq!(&data[24..28], &48000u32.to_le_bytes());
+        assert_eq!(&data[36..40], b"data");
+        assert_eq!(&data[40..44], &12u32.to_le_bytes());
+        let samples: Vec<i16> = data[44..]
+            .chunks(2)
+            .map(|b| i16::from_le_bytes([b[0], b[1]]))
+            .collect();
+        // Out of range samples are clamped
+        assert_eq!(samples, [0, 32767, -32767, 16383, 32767, -32767]);
+    }
+}